@@ -54,6 +54,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     }
 
     let driver = Driver::<Dma<u8>>::new(&pci_addr, queue_num)?;
+    let ns = driver.namespace(1).await.ok_or("namespace 1 not found")?;
 
     let time = duration.unwrap();
 
@@ -83,7 +84,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             }
 
             loop {
-                let mut ftrs = driver.read_batch(i, &data, lbas).await;
+                let mut ftrs = driver.read_batch(i, &ns, &data, lbas).await?;
                 if !ftrs.is_empty() {
                     op_count += ftrs.len();
                     pending.append(&mut ftrs);
@@ -21,8 +21,9 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     let driver = Driver::<Dma<u8>>::new(&pci_addr, 4)?;
+    let ns = driver.namespace(1).await.ok_or("namespace 1 not found")?;
 
-    let bytes = 8 * 512;
+    let bytes = 8 * ns.block_size as usize;
     let rand_block = &(0..HUGE_PAGE_SIZE)
         .map(|_| rand::random::<u8>())
         .collect::<Vec<_>>()[..];
@@ -31,19 +32,18 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
 
     buffer[0..12].copy_from_slice("Hello World!".as_bytes());
 
-    let f1 = driver.write(0, &buffer.slice(0..bytes), 0).await;
+    let f1 = driver.write(0, &ns, &buffer.slice(0..bytes), 0).await?;
     let _ = futures::future::join_all(f1).await;
-    let f2 = driver.read(0, &buffer.slice(0..bytes), 0).await;
+    let f2 = driver.read(0, &ns, &buffer.slice(0..bytes), 0).await?;
     let _ = futures::future::join_all(f2).await;
 
-    for b in buffer.chunks(2 * 4096) {
+    if let Some(b) = buffer.chunks(2 * 4096).next() {
         for byte in b.slice.iter().take(12) {
             if let Some(char) = std::char::from_u32(*byte as u32) {
                 print!("{}", char);
             }
         }
-        println!("");
-        break;
+        println!();
     }
 
     Ok(())
@@ -0,0 +1,33 @@
+use std::error::Error;
+
+use vroom::driver::Driver;
+use vroom::memory::{Dma, DmaSlice};
+use vroom::{EmulatedController, HUGE_PAGE_SIZE};
+
+#[tokio::main(flavor = "multi_thread")]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    // 64 MiB namespace with 512 byte blocks, no hardware or huge pages needed
+    let controller = EmulatedController::ram(64 << 20, 512)?;
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 4)?;
+    let ns = driver.namespace(1).await.ok_or("namespace 1 not found")?;
+
+    let bytes = 8 * ns.block_size as usize;
+    let mut buffer: Dma<u8> = Dma::allocate(HUGE_PAGE_SIZE)?;
+    buffer[0..12].copy_from_slice("Hello World!".as_bytes());
+
+    let f1 = driver.write(0, &ns, &buffer.slice(0..bytes), 0).await?;
+    futures::future::join_all(f1).await;
+    buffer[0..12].fill(0);
+    let f2 = driver.read(0, &ns, &buffer.slice(0..bytes), 0).await?;
+    futures::future::join_all(f2).await;
+
+    let mut image = [0u8; 12];
+    controller.read_image(&mut image, 0)?;
+    println!(
+        "read: {}, image: {}",
+        String::from_utf8_lossy(&buffer[0..12]),
+        String::from_utf8_lossy(&image)
+    );
+
+    driver.cleanup().await
+}
@@ -0,0 +1,79 @@
+use crate::{ErrorLogEntry, FirmwareSlotLog, SmartLog};
+
+/// Asynchronous Event Type, NVMe spec 5.2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncEventType {
+    /// General errors not associated with a command
+    Error,
+    SmartHealth,
+    Notice,
+    IoCommandSet,
+    VendorSpecific,
+    Reserved(u8),
+}
+
+/// Log page read to clear an asynchronous event
+#[derive(Debug, Clone)]
+pub enum AsyncEventLog {
+    Error(Vec<ErrorLogEntry>),
+    Smart(SmartLog),
+    FirmwareSlot(FirmwareSlotLog),
+    /// Log pages without a parser, e.g. the Changed Namespace List
+    Raw(Vec<u8>),
+}
+
+/// Asynchronous event reported by the controller, decoded from dword 0 of the completion of an
+/// Asynchronous Event Request (NVMe spec 5.2)
+#[derive(Debug, Clone)]
+pub struct AsyncEvent {
+    pub event_type: AsyncEventType,
+    /// Asynchronous Event Information, specific to the event type
+    pub info: u8,
+    /// Log Page Identifier of the log page with more information
+    pub log_page: u8,
+    /// Contents of the log page, `None` if it could not be read
+    pub log: Option<AsyncEventLog>,
+}
+
+impl AsyncEvent {
+    pub(crate) fn from_completion(dw0: u32) -> Self {
+        Self {
+            event_type: match dw0 & 0x7 {
+                0 => AsyncEventType::Error,
+                1 => AsyncEventType::SmartHealth,
+                2 => AsyncEventType::Notice,
+                6 => AsyncEventType::IoCommandSet,
+                7 => AsyncEventType::VendorSpecific,
+                t => AsyncEventType::Reserved(t as u8),
+            },
+            info: (dw0 >> 8) as u8,
+            log_page: (dw0 >> 16) as u8,
+            log: None,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match (self.event_type, self.info) {
+            (AsyncEventType::Error, 0x00) => "Write to Invalid Doorbell Register",
+            (AsyncEventType::Error, 0x01) => "Invalid Doorbell Write Value",
+            (AsyncEventType::Error, 0x02) => "Diagnostic Failure",
+            (AsyncEventType::Error, 0x03) => "Persistent Internal Error",
+            (AsyncEventType::Error, 0x04) => "Transient Internal Error",
+            (AsyncEventType::Error, 0x05) => "Firmware Image Load Error",
+            (AsyncEventType::SmartHealth, 0x00) => "NVM Subsystem Reliability",
+            (AsyncEventType::SmartHealth, 0x01) => "Temperature Threshold",
+            (AsyncEventType::SmartHealth, 0x02) => "Spare Below Threshold",
+            (AsyncEventType::Notice, 0x00) => "Namespace Attribute Changed",
+            (AsyncEventType::Notice, 0x01) => "Firmware Activation Starting",
+            (AsyncEventType::Notice, 0x02) => "Telemetry Log Changed",
+            (AsyncEventType::Notice, 0x03) => "Asymmetric Namespace Access Change",
+            (AsyncEventType::Notice, 0x04) => "Predictable Latency Event Aggregate Log Change",
+            (AsyncEventType::Notice, 0x05) => "LBA Status Information Alert",
+            (AsyncEventType::Notice, 0x06) => "Endurance Group Event Aggregate Log Page Change",
+            (AsyncEventType::IoCommandSet, 0x00) => "Reservation Log Page Available",
+            (AsyncEventType::IoCommandSet, 0x01) => "Sanitize Operation Completed",
+            (AsyncEventType::VendorSpecific, _) => "Vendor Specific",
+            _ => "Unknown Event",
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::{Arc, Mutex};
+
+use crate::memory::{Dma, DmaChunks, DmaSlice};
+use crate::prp::PAGE_SIZE;
+use crate::registers::Bar;
+
+/// What I/O queue pairs created after [`NvmeDevice::enable_cmb`] place in the Controller Memory
+/// Buffer
+///
+/// [`NvmeDevice::enable_cmb`]: crate::NvmeDevice::enable_cmb
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CmbUsage {
+    /// Submission queues, the controller fetches commands without a round trip over PCIe
+    pub submission_queues: bool,
+    /// PRP list pages of the queue slots
+    pub prp_lists: bool,
+}
+
+#[derive(Debug)]
+struct CmbState {
+    bar: Bar,
+    // offset of the buffer in the BAR
+    offset: usize,
+    // free ranges as offset -> length, relative to the start of the buffer
+    free: BTreeMap<usize, usize>,
+}
+
+impl CmbState {
+    // first fit
+    fn take(&mut self, size: usize) -> Option<usize> {
+        let (&offset, &len) = self.free.iter().find(|(_, &len)| len >= size)?;
+        self.free.remove(&offset);
+        if len > size {
+            self.free.insert(offset + size, len - size);
+        }
+        Some(offset)
+    }
+
+    fn release(&mut self, mut offset: usize, mut len: usize) {
+        // merge with the adjacent free ranges
+        if let Some((&next, &next_len)) = self.free.range(offset + len..).next() {
+            if next == offset + len {
+                self.free.remove(&next);
+                len += next_len;
+            }
+        }
+        if let Some((&prev, &prev_len)) = self.free.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+        self.free.insert(offset, len);
+    }
+}
+
+/// Controller Memory Buffer, memory on the controller mapped through one of its BARs (NVMe spec
+/// 8.2). Hands out page aligned [`CmbBuffer`]s that return to the buffer when dropped.
+#[derive(Debug, Clone)]
+pub struct ControllerMemoryBuffer {
+    state: Arc<Mutex<CmbState>>,
+    bus_addr: usize,
+    size: usize,
+    // CMBSZ, the supported uses
+    cmbsz: u32,
+}
+
+impl ControllerMemoryBuffer {
+    /// Buffer of `size` bytes at `offset` in `bar`, with the capabilities reported in `cmbsz`
+    pub(crate) fn new(bar: Bar, offset: usize, size: usize, cmbsz: u32) -> Self {
+        Self {
+            bus_addr: bar.bus_addr + offset,
+            size,
+            cmbsz,
+            state: Arc::new(Mutex::new(CmbState {
+                bar,
+                offset,
+                free: BTreeMap::from([(0, size)]),
+            })),
+        }
+    }
+
+    /// Allocates `size` bytes, rounded up to whole memory pages
+    pub fn allocate(&self, size: usize) -> Result<CmbBuffer, Box<dyn Error>> {
+        if size == 0 {
+            return Err("controller memory buffer allocations must not be empty".into());
+        }
+        let len = size.next_multiple_of(PAGE_SIZE);
+
+        let mut state = self.state.lock().unwrap();
+        let offset = state.take(len).ok_or(format!(
+            "controller memory buffer has no {len} contiguous bytes left"
+        ))?;
+        let virt = unsafe { state.bar.addr.add(state.offset + offset) };
+
+        Ok(CmbBuffer {
+            dma: Dma::borrowed(virt, self.bus_addr + offset, size),
+            state: Arc::clone(&self.state),
+            offset,
+            len,
+        })
+    }
+
+    /// Address of the buffer on the PCI bus, as used in commands
+    pub fn bus_addr(&self) -> usize {
+        self.bus_addr
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes not allocated, possibly fragmented
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().free.values().sum()
+    }
+
+    /// Submission queues may be placed in the buffer (CMBSZ.SQS)
+    pub fn supports_submission_queues(&self) -> bool {
+        self.cmbsz & 1 != 0
+    }
+
+    /// Completion queues may be placed in the buffer (CMBSZ.CQS)
+    pub fn supports_completion_queues(&self) -> bool {
+        self.cmbsz & (1 << 1) != 0
+    }
+
+    /// PRP lists and SGL segments may be placed in the buffer (CMBSZ.LISTS)
+    pub fn supports_prp_lists(&self) -> bool {
+        self.cmbsz & (1 << 2) != 0
+    }
+
+    /// Data of read and write commands may be placed in the buffer (CMBSZ.RDS and CMBSZ.WDS)
+    pub fn supports_data(&self) -> bool {
+        self.cmbsz & (0b11 << 3) == 0b11 << 3
+    }
+}
+
+/// Memory allocated from a [`ControllerMemoryBuffer`]
+///
+/// Usable wherever DMA memory is, either directly as a [`DmaSlice`] or as the [`Dma<u8>`] it
+/// dereferences to. Accesses by the CPU are uncached reads and writes over PCIe.
+#[derive(Debug)]
+pub struct CmbBuffer {
+    dma: Dma<u8>,
+    state: Arc<Mutex<CmbState>>,
+    offset: usize,
+    // allocated bytes, whole pages
+    len: usize,
+}
+
+unsafe impl Send for CmbBuffer {}
+unsafe impl Sync for CmbBuffer {}
+
+impl Drop for CmbBuffer {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release(self.offset, self.len);
+    }
+}
+
+impl Deref for CmbBuffer {
+    type Target = Dma<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.dma
+    }
+}
+
+impl DerefMut for CmbBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.dma
+    }
+}
+
+impl DmaSlice for CmbBuffer {
+    type Item = Dma<u8>;
+
+    fn chunks(&self, bytes: usize) -> DmaChunks<'_, u8> {
+        self.dma.chunks(bytes)
+    }
+
+    fn slice(&self, range: Range<usize>) -> Self::Item {
+        self.dma.slice(range)
+    }
+}
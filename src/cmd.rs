@@ -1,3 +1,6 @@
+use crate::queues::QueuePriority;
+use crate::sgl::SglDescriptor;
+
 /// NVMe Spec 4.2
 /// Submission queue entry
 #[derive(Clone, Copy, Debug, Default)]
@@ -32,7 +35,14 @@ pub struct NvmeCommand {
 }
 
 impl NvmeCommand {
-    pub fn create_io_completion_queue(c_id: u16, qid: u16, ptr: usize, size: u16) -> Self {
+    /// `ptr` points to a PRP list unless the queue is physically `contiguous`
+    pub fn create_io_completion_queue(
+        c_id: u16,
+        qid: u16,
+        ptr: usize,
+        size: u16,
+        contiguous: bool,
+    ) -> Self {
         Self {
             opcode: 5,
             flags: 0,
@@ -42,7 +52,7 @@ impl NvmeCommand {
             md_ptr: 0,
             d_ptr: [ptr as u64, 0],
             cdw10: ((size as u32) << 16) | (qid as u32),
-            cdw11: 1, // Physically Contiguous
+            cdw11: contiguous as u32, // Physically Contiguous
             cdw12: 0,
             cdw13: 0,
             cdw14: 0,
@@ -50,12 +60,16 @@ impl NvmeCommand {
         }
     }
 
+    /// `ptr` points to a PRP list unless the queue is physically `contiguous`, `priority` is only
+    /// used with weighted round robin arbitration
     pub fn create_io_submission_queue(
         c_id: u16,
         q_id: u16,
         ptr: usize,
         size: u16,
         cq_id: u16,
+        contiguous: bool,
+        priority: QueuePriority,
     ) -> Self {
         Self {
             opcode: 1,
@@ -66,8 +80,8 @@ impl NvmeCommand {
             md_ptr: 0,
             d_ptr: [ptr as u64, 0],
             cdw10: ((size as u32) << 16) | (q_id as u32),
-            cdw11: ((cq_id as u32) << 16) | 1, /* Physically Contiguous */
-            //TODO: QPRIO
+            // CQID | QPRIO | PC (Physically Contiguous)
+            cdw11: ((cq_id as u32) << 16) | (priority as u32) << 1 | contiguous as u32,
             cdw12: 0, //TODO: NVMSETID
             cdw13: 0,
             cdw14: 0,
@@ -129,6 +143,17 @@ impl NvmeCommand {
         }
     }
 
+    pub fn identify_io_command_set_controller(c_id: u16, ptr: usize, csi: u8) -> Self {
+        Self {
+            opcode: 6,
+            c_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: 6,
+            cdw11: (csi as u32) << 24,
+            ..Default::default()
+        }
+    }
+
     pub fn identify_namespace_list(c_id: u16, ptr: usize, base: u32) -> Self {
         Self {
             opcode: 6,
@@ -147,11 +172,68 @@ impl NvmeCommand {
         }
     }
 
-    pub fn get_features(c_id: u16, ptr: usize, fid: u8) -> Self {
+    /// `sel` selects the current (0), default (1), saved (2) value or the capabilities (3)
+    pub fn get_features(c_id: u16, ptr: usize, fid: u8, sel: u8, cdw11: u32) -> Self {
         Self {
             opcode: 0xA,
+            c_id,
+            d_ptr: [ptr as u64, 0],
+            cdw10: ((sel as u32 & 0x7) << 8) | u32::from(fid),
+            cdw11,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_features(c_id: u16, ptr: usize, fid: u8, value: u32) -> Self {
+        Self {
+            opcode: 0x9,
+            c_id,
             d_ptr: [ptr as u64, 0],
-            cdw10: u32::from(fid), // TODO: SEL
+            cdw10: u32::from(fid),
+            cdw11: value,
+            ..Default::default()
+        }
+    }
+
+    /// `size` is in memory pages, `list` points to `entries` Host Memory Buffer Descriptors
+    pub fn set_host_memory_buffer(
+        c_id: u16,
+        enable: bool,
+        memory_return: bool,
+        size: u32,
+        list: u64,
+        entries: u32,
+    ) -> Self {
+        Self {
+            opcode: 0x9,
+            c_id,
+            cdw10: 0x0D,
+            cdw11: (memory_return as u32) << 1 | enable as u32,
+            cdw12: size,
+            cdw13: list as u32,
+            cdw14: (list >> 32) as u32,
+            cdw15: entries,
+            ..Default::default()
+        }
+    }
+
+    /// Aborts command `cid` submitted to submission queue `sqid`
+    pub fn abort(c_id: u16, sqid: u16, cid: u16) -> Self {
+        Self {
+            opcode: 8,
+            c_id,
+            cdw10: (cid as u32) << 16 | sqid as u32,
+            ..Default::default()
+        }
+    }
+
+    /// Doorbell Buffer Config, `shadow` and `event_idx` are the addresses of the shadow
+    /// doorbell and EventIdx buffers
+    pub fn doorbell_buffer_config(c_id: u16, shadow: usize, event_idx: usize) -> Self {
+        Self {
+            opcode: 0x7C,
+            c_id,
+            d_ptr: [shadow as u64, event_idx as u64],
             ..Default::default()
         }
     }
@@ -174,7 +256,15 @@ impl NvmeCommand {
         }
     }
 
-    pub fn io_write(c_id: u16, ns_id: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64) -> Self {
+    pub fn io_write(
+        c_id: u16,
+        ns_id: u32,
+        lba: u64,
+        blocks_1: u16,
+        ptr0: u64,
+        ptr1: u64,
+        fua: bool,
+    ) -> Self {
         Self {
             opcode: 1,
             flags: 0,
@@ -185,13 +275,29 @@ impl NvmeCommand {
             d_ptr: [ptr0, ptr1],
             cdw10: lba as u32,
             cdw11: (lba >> 32) as u32,
-            cdw12: blocks_1 as u32,
+            cdw12: ((fua as u32) << 30) | blocks_1 as u32,
             cdw13: 0,
             cdw14: 0,
             cdw15: 0,
         }
     }
 
+    /// Replaces the PRP entries of the data pointer by SGL Entry 1 (PSDT 01b)
+    pub fn with_sgl(mut self, sgl: SglDescriptor) -> Self {
+        self.flags = (self.flags & 0x3F) | (0b01 << 6);
+        self.d_ptr = sgl.to_dptr();
+        self
+    }
+
+    pub fn flush(c_id: u16, ns_id: u32) -> Self {
+        Self {
+            opcode: 0,
+            c_id,
+            ns_id,
+            ..Default::default()
+        }
+    }
+
     pub(crate) fn format_nvm(c_id: u16, ns_id: u32) -> Self {
         Self {
             opcode: 0x80,
@@ -229,23 +335,51 @@ impl NvmeCommand {
         }
     }
 
-    pub(crate) fn get_log_page(
+    /// `numd` is the 0-based number of dwords to transfer, starting at byte `offset` of the log
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_log_page(
         c_id: u16,
+        ns_id: u32,
         numd: u32,
         ptr0: u64,
         ptr1: u64,
         lid: u8,
         lpid: u16,
+        offset: u64,
     ) -> Self {
         Self {
+            opcode: 2,
             c_id,
+            ns_id,
             d_ptr: [ptr0, ptr1],
             cdw10: (numd << 16) | lid as u32,
             cdw11: ((lpid as u32) << 16) | numd >> 16,
+            cdw12: offset as u32,
+            cdw13: (offset >> 32) as u32,
             ..Self::default()
         }
     }
 
+    /// `ranges` is the 0-based number of range descriptors at `ptr0`
+    pub fn dataset_management(
+        c_id: u16,
+        ns_id: u32,
+        ranges: u8,
+        ptr0: u64,
+        ptr1: u64,
+        deallocate: bool,
+    ) -> Self {
+        Self {
+            opcode: 9,
+            c_id,
+            ns_id,
+            d_ptr: [ptr0, ptr1],
+            cdw10: ranges as u32,
+            cdw11: (deallocate as u32) << 2,
+            ..Default::default()
+        }
+    }
+
     // not supported by samsung
     pub fn write_zeroes(c_id: u16, ns_id: u32, slba: u64, nlb: u16, deac: bool) -> Self {
         Self {
@@ -266,3 +400,16 @@ impl NvmeCommand {
         }
     }
 }
+
+/// NVMe Spec 3.2.3.1
+/// Dataset Management range descriptor
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct DsmRange {
+    /// Context attributes
+    pub attributes: u32,
+    /// Length in logical blocks
+    pub blocks: u32,
+    /// Starting LBA
+    pub slba: u64,
+}
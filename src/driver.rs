@@ -1,25 +1,94 @@
-use std::{cmp, error::Error, fmt::Debug, sync::Arc};
+use std::{
+    cmp,
+    error::Error,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-use futures::lock::Mutex;
+use futures::{lock::Mutex, Stream};
+use tokio::sync::broadcast;
 use tokio::sync::oneshot::{self};
 
+#[cfg(feature = "emulator")]
+use crate::emulator::EmulatedController;
 use crate::{
-    cmd::NvmeCommand,
-    memory::DmaSlice,
+    cmb::{CmbUsage, ControllerMemoryBuffer},
+    features::{Arbitration, Feature, FeatureSelect},
+    memory::{DmaPool, DmaSlice},
+    nvme::{invalid_input, write_dsm_ranges},
     pci::*,
-    request::{self, Request},
-    NvmeDevice, NvmeQueuePair, QUEUE_LENGTH,
+    pmr::PersistentMemoryRegion,
+    request::{Orphans, Request},
+    AsyncEvent, ControllerInfo, ErrorLogEntry, FirmwareSlotLog, LbaRange, NvmeDevice,
+    NvmeNamespace, NvmeQueuePair, QueuePriority, SglDescriptor, SmartLog, TimeoutError,
+    QUEUE_LENGTH,
 };
 
+/// Default time after which I/O commands are aborted
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the polling tasks look for expired commands
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+/// Asynchronous events buffered for subscribers that fall behind
+const EVENT_CAPACITY: usize = 64;
+
+/// Options of [`Driver::new_with_options`]
+#[derive(Debug, Clone)]
+pub struct DriverOptions {
+    /// Entries of every I/O queue, clamped to CAP.MQES. Besides the queues themselves, every
+    /// entry takes a 4 KiB page for its PRP list.
+    pub queue_length: usize,
+    /// Use shadow doorbells for the I/O queues if the controller supports Doorbell Buffer
+    /// Config, see [`NvmeDevice::enable_shadow_doorbells`]
+    pub shadow_doorbells: bool,
+    /// Map the Controller Memory Buffer and place what the usage asks for in it, see
+    /// [`NvmeDevice::enable_cmb`]. Fails if the controller has none.
+    pub cmb: Option<CmbUsage>,
+    /// Priority class of each queue pair by index, pairs without an entry are
+    /// [`QueuePriority::Medium`]. Only used if the controller supports weighted round robin.
+    pub priorities: Vec<QueuePriority>,
+    /// Weights of the priority classes, see [`NvmeDevice::set_arbitration`]
+    pub arbitration: Option<Arbitration>,
+}
+
+impl Default for DriverOptions {
+    fn default() -> Self {
+        Self {
+            queue_length: QUEUE_LENGTH,
+            shadow_doorbells: true,
+            cmb: None,
+            priorities: Vec::new(),
+            arbitration: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Driver<T: DmaSlice + Debug> {
     queue_pairs: Vec<Mutex<NvmeQueuePair<T>>>,
     nvme: Arc<Mutex<NvmeDevice<T>>>,
+    // abort commands whose request was dropped before completion
+    abort_dropped: AtomicBool,
+    io_timeout_us: AtomicU64,
+    events: broadcast::Sender<AsyncEvent>,
+    // range lists of Dataset Management commands
+    range_pool: DmaPool,
 }
 
 #[allow(unreachable_code)]
 impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driver<T> {
     pub fn new(pci_addr: &str, num_q_pairs: usize) -> Result<Arc<Self>, Box<dyn Error>> {
+        Self::new_with_options(pci_addr, num_q_pairs, DriverOptions::default())
+    }
+
+    pub fn new_with_options(
+        pci_addr: &str,
+        num_q_pairs: usize,
+        options: DriverOptions,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
         let mut vendor_file = pci_open_resource_ro(pci_addr, "vendor").expect("wrong pci address");
         let mut device_file = pci_open_resource_ro(pci_addr, "device").expect("wrong pci address");
         let mut config_file = pci_open_resource_ro(pci_addr, "config").expect("wrong pci address");
@@ -34,22 +103,80 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
             return Err(format!("device {} is not a block device", pci_addr).into());
         }
 
-        let mut nvme = NvmeDevice::<T>::init(pci_addr)?;
+        Self::with_device(NvmeDevice::<T>::init(pci_addr)?, num_q_pairs, options)
+    }
+
+    /// Creates a driver for a controller emulated in software, see [`EmulatedController`].
+    #[cfg(feature = "emulator")]
+    pub fn new_emulated(
+        controller: Arc<EmulatedController>,
+        num_q_pairs: usize,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
+        Self::new_emulated_with_options(controller, num_q_pairs, DriverOptions::default())
+    }
+
+    #[cfg(feature = "emulator")]
+    pub fn new_emulated_with_options(
+        controller: Arc<EmulatedController>,
+        num_q_pairs: usize,
+        options: DriverOptions,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
+        Self::with_device(
+            NvmeDevice::<T>::init_emulated(controller)?,
+            num_q_pairs,
+            options,
+        )
+    }
+
+    fn with_device(
+        mut nvme: NvmeDevice<T>,
+        num_q_pairs: usize,
+        options: DriverOptions,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
         nvme.identify_controller()?;
         let ns = nvme.identify_namespace_list(0);
         for n in ns {
             println!("ns_id: {n}");
             nvme.identify_namespace(n);
         }
+        if let Err(e) = nvme.enable_async_events() {
+            eprintln!("asynchronous events are not available: {e}");
+        }
+        if options.shadow_doorbells && nvme.controller_info().supports_doorbell_buffer_config() {
+            if let Err(e) = nvme.enable_shadow_doorbells() {
+                eprintln!("shadow doorbells are not available: {e}");
+            }
+        }
+        if let Some(usage) = options.cmb {
+            nvme.enable_cmb(usage)?;
+        }
+        if let Some(arbitration) = options.arbitration {
+            nvme.set_arbitration(arbitration)?;
+        }
 
+        // queue pair 1 is used by the synchronous functions of the device
+        let available = nvme.max_io_queue_pairs().saturating_sub(1);
+        if !(1..=available).contains(&num_q_pairs) {
+            return Err(format!(
+                "invalid number of queue pairs {num_q_pairs}, the controller supports 1 to {available}"
+            )
+            .into());
+        }
+
+        let len = options.queue_length.min(nvme.max_queue_length());
         let mut queue_pairs = Vec::new();
-        for _ in 0..num_q_pairs {
-            queue_pairs.push(Mutex::new(nvme.create_io_queue_pair(QUEUE_LENGTH)?));
+        for i in 0..num_q_pairs {
+            let priority = options.priorities.get(i).copied().unwrap_or_default();
+            queue_pairs.push(Mutex::new(nvme.create_io_queue_pair(len, priority)?));
         }
 
         let driver = Arc::new(Driver {
             queue_pairs,
             nvme: Arc::new(Mutex::new(nvme)),
+            abort_dropped: AtomicBool::new(false),
+            io_timeout_us: AtomicU64::new(DEFAULT_IO_TIMEOUT.as_micros() as u64),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            range_pool: DmaPool::new(0)?,
         });
 
         driver.start_polling();
@@ -63,43 +190,78 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
     async fn submit(
         &self,
         q_id: usize,
+        ns: &NvmeNamespace,
         data: &T,
         lba: u64,
         write: bool,
-    ) -> Option<(Option<usize>, Vec<u16>)> {
+        fua: bool,
+    ) -> std::io::Result<Option<(Option<usize>, Vec<u16>)>> {
         if let Some(mut q_pair) = self.queue_pairs[q_id].try_lock() {
-            let (tail, ids) = q_pair.submit_async(data, lba, write);
+            let (tail, ids) = q_pair.submit_async(ns, data, lba, write, fua)?;
             if !ids.is_empty() {
-                return Some((tail, ids));
+                return Ok(Some((tail, ids)));
             }
         }
-        None
+        Ok(None)
     }
 
+    // spawns a task per queue pair delivering completions, the tasks stop once the driver is
+    // dropped
     #[allow(unused_assignments)]
     fn start_polling(self: &Arc<Self>) {
         for q_id in 0..self.queue_pairs.len() {
-            let driver = Arc::clone(self);
+            let weak: Weak<Self> = Arc::downgrade(self);
 
             tokio::spawn(async move {
                 let mut empty_poll_count = 0;
-                loop {
-                    let completed_ids = {
-                        let mut q_pair = driver.queue_pairs[q_id].lock().await;
-                        q_pair.poll_multi(16)
+                let mut last_timeout_check = Instant::now();
+                while let Some(driver) = weak.upgrade() {
+                    if last_timeout_check.elapsed() >= TIMEOUT_CHECK_INTERVAL {
+                        last_timeout_check = Instant::now();
+                        driver.expire(q_id).await;
+                        // one task is enough to watch the controller status
+                        if q_id == 0 {
+                            driver.check_fatal_status().await;
+                            driver.deliver_async_events().await;
+                        }
+                    }
+
+                    let (sqid, orphans, abortable) = {
+                        let q_pair = driver.queue_pairs[q_id].lock().await;
+                        let dropped = driver.abort_dropped.load(Ordering::Relaxed);
+                        let abortable = q_pair.orphans.take_abortable(dropped);
+                        (q_pair.id, Arc::clone(&q_pair.orphans), abortable)
                     };
 
-                    if !completed_ids.is_empty() {
-                        empty_poll_count = 0;
+                    // before polling, a reset recreates the queue and fails all pending requests
+                    if !abortable.is_empty() {
+                        driver.abort(sqid, &orphans, abortable).await;
+                    }
 
-                        let q_pair = driver.queue_pairs[q_id].lock().await;
-                        for id in completed_ids {
-                            if let Some(sender) = q_pair.pending.lock().await.remove(&id) {
-                                let _ = sender.send(Ok(()));
-                            }
+                    // completions are delivered under the same lock, so no reset can recreate
+                    // the queue and hand their command ids to new requests in between
+                    let completed = {
+                        let mut q_pair = driver.queue_pairs[q_id].lock().await;
+                        let completions = q_pair.poll_multi(16);
+                        let completed = !completions.is_empty();
+                        for (id, result) in completions {
+                            let sender = q_pair.pending.lock().await.remove(&id);
+                            // discarded if the request was dropped or timed out
+                            q_pair.orphans.complete(id, || {
+                                sender.is_some_and(|(sender, _)| {
+                                    sender.send(result.map_err(std::io::Error::from)).is_ok()
+                                })
+                            });
                         }
+                        completed
+                    };
+
+                    if completed {
+                        empty_poll_count = 0;
                     } else {
                         empty_poll_count = cmp::min(empty_poll_count + 1, 20);
+                        // don't keep the driver alive while idle
+                        drop(driver);
 
                         if empty_poll_count > 10 {
                             let sleep_duration =
@@ -114,47 +276,236 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
         }
     }
 
-    pub async fn read(&self, q_id: usize, data: &T, lba: u64) -> Vec<Request> {
+    // fails the requests of commands on queue pair `q_id` pending for longer than the timeout
+    async fn expire(&self, q_id: usize) {
+        let timeout = self.io_timeout();
+        let q_pair = self.queue_pairs[q_id].lock().await;
+        let mut pending = q_pair.pending.lock().await;
+        let expired: Vec<u16> = pending
+            .iter()
+            .filter(|(_, (_, submitted))| submitted.elapsed() > timeout)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in expired {
+            let Some((sender, _)) = pending.remove(&id) else {
+                continue;
+            };
+            let error = TimeoutError {
+                sqid: q_pair.id,
+                cid: id,
+                timeout,
+            };
+            q_pair.orphans.expire(id, || {
+                let _ = sender.send(Err(error.into()));
+            });
+        }
+    }
+
+    // aborts the orphaned I/O commands `ids` of submission queue `sqid`, the controller is reset
+    // if an Abort command times out as well
+    async fn abort(&self, sqid: u16, orphans: &Orphans, ids: Vec<u16>) {
+        let mut nvme = self.nvme.lock().await;
+        let resets = nvme.reset_count();
+        let mut reset = false;
+        for c_id in ids {
+            // completed in the meantime or discarded by a reset
+            if !orphans.contains(c_id) {
+                continue;
+            }
+            match nvme.abort(sqid, c_id) {
+                Err(e) if e.is::<TimeoutError>() => {
+                    reset = true;
+                    break;
+                }
+                Err(e) => eprintln!("abort of command {c_id} failed: {e}"),
+                Ok(_) => {}
+            }
+        }
+        // the controller may also have been reset by a timed out admin command
+        if reset || nvme.reset_count() != resets {
+            if let Err(e) = self.recover(&mut nvme, reset).await {
+                eprintln!("recovering from controller reset failed: {e}");
+            }
+        }
+    }
+
+    // resets the controller if it reported a fatal status
+    async fn check_fatal_status(&self) {
+        // don't stall polling while admin commands are running
+        let Some(mut nvme) = self.nvme.try_lock() else {
+            return;
+        };
+        if nvme.fatal_status() {
+            eprintln!("controller reported a fatal status, resetting");
+            if let Err(e) = self.recover(&mut nvme, true).await {
+                eprintln!("recovering from controller reset failed: {e}");
+            }
+        }
+    }
+
+    // hands asynchronous events reported by the controller to the subscribers
+    async fn deliver_async_events(&self) {
+        let Some(mut nvme) = self.nvme.try_lock() else {
+            return;
+        };
+        // reading a log page may time out and reset the controller
+        let events = self
+            .with_recovery(&mut nvme, |nvme| nvme.poll_async_events())
+            .await;
+        drop(nvme);
+
+        for event in events {
+            // fails if nobody is subscribed
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Subscribes to the asynchronous events reported by the controller from now on, e.g. SMART
+    /// / health critical warnings. The log page of an event is read before it is delivered,
+    /// which happens in the polling task of queue pair 0. A subscriber that falls behind by more
+    /// than 64 events misses the oldest ones.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<AsyncEvent> {
+        self.events.subscribe()
+    }
+
+    /// Stream of the asynchronous events, see [`Driver::subscribe_events`]. Missed events are
+    /// skipped, the stream ends with the driver.
+    pub fn events(&self) -> impl Stream<Item = AsyncEvent> {
+        futures::stream::unfold(self.subscribe_events(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Resets the controller and recreates all queue pairs with their ids, e.g. to recover from
+    /// a controller that stopped responding. This happens automatically if the controller
+    /// reports a fatal status or an Abort command times out.
+    ///
+    /// Requests of commands in flight fail with [`std::io::ErrorKind::ConnectionReset`] and can
+    /// be resubmitted once this returns.
+    pub async fn reset(&self) -> Result<(), Box<dyn Error>> {
+        let mut nvme = self.nvme.lock().await;
+        self.recover(&mut nvme, true).await
+    }
+
+    // runs the admin commands of `f`, see `with_recovery`
+    async fn admin<R>(&self, f: impl FnOnce(&mut NvmeDevice<T>) -> R) -> R {
+        let mut nvme = self.nvme.lock().await;
+        self.with_recovery(&mut nvme, f).await
+    }
+
+    // runs `f` on `nvme` and recreates the queue pairs if an admin command timed out and reset
+    // the controller meanwhile, which deleted all I/O queues
+    async fn with_recovery<R>(
+        &self,
+        nvme: &mut NvmeDevice<T>,
+        f: impl FnOnce(&mut NvmeDevice<T>) -> R,
+    ) -> R {
+        let resets = nvme.reset_count();
+        let result = f(nvme);
+        if nvme.reset_count() != resets {
+            if let Err(e) = self.recover(nvme, false).await {
+                eprintln!("recovering from controller reset failed: {e}");
+            }
+        }
+        result
+    }
+
+    // resets the controller if `reset` is set, recreates all queue pairs and fails the commands
+    // in flight. Holds all queue pairs so nothing is submitted to a deleted queue meanwhile.
+    async fn recover(&self, nvme: &mut NvmeDevice<T>, reset: bool) -> Result<(), Box<dyn Error>> {
+        let mut q_pairs = Vec::with_capacity(self.queue_pairs.len());
+        for q_pair in self.queue_pairs.iter() {
+            q_pairs.push(q_pair.lock().await);
+        }
+
+        if reset {
+            nvme.reset()?;
+        }
+        for q_pair in q_pairs.iter_mut() {
+            nvme.recreate_io_queue_pair(q_pair)?;
+            for (_, (sender, _)) in q_pair.pending.lock().await.drain() {
+                let _ = sender.send(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "command discarded by a controller reset",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets how long I/O commands may take before their request fails with a [`TimeoutError`].
+    /// Expired commands are aborted, and the controller is reset if the Abort command times out
+    /// as well, see [`NvmeDevice::set_admin_timeout`]. Defaults to [`DEFAULT_IO_TIMEOUT`].
+    pub fn set_io_timeout(&self, timeout: Duration) {
+        self.io_timeout_us
+            .store(timeout.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn io_timeout(&self) -> Duration {
+        Duration::from_micros(self.io_timeout_us.load(Ordering::Relaxed))
+    }
+
+    /// Sets how long admin commands may take before they are aborted, defaults to CAP.TO
+    pub async fn set_admin_timeout(&self, timeout: Duration) {
+        self.nvme.lock().await.set_admin_timeout(timeout);
+    }
+
+    pub async fn read(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        data: &T,
+        lba: u64,
+    ) -> std::io::Result<Vec<Request>> {
         let mut actual_qid = q_id;
         loop {
-            match self.submit(actual_qid, data, lba, false).await {
+            match self.submit(actual_qid, ns, data, lba, false, false).await? {
                 Some((tail, ids)) => {
                     if ids.is_empty() {
                         println!("Empty command id list");
-                        return Vec::new();
+                        return Ok(Vec::new());
                     }
 
                     if ids.len() == 1 {
                         let c_id = ids[0];
                         let (sender, receiver) = oneshot::channel();
-                        {
+                        let orphans = {
                             let q_pair = self.queue_pairs[actual_qid].lock().await;
-                            q_pair.pending.lock().await.insert(c_id, sender);
-                        }
+                            q_pair
+                                .pending
+                                .lock()
+                                .await
+                                .insert(c_id, (sender, Instant::now()));
+                            Arc::clone(&q_pair.orphans)
+                        };
                         if let Some(tail) = tail {
                             self.queue_pairs[actual_qid]
                                 .lock()
                                 .await
                                 .set_tail(tail as u32);
                         }
-                        return vec![Request {
-                            id: c_id,
-                            receiver,
-                            state: request::State::Submitted,
-                        }];
+                        return Ok(vec![Request::new(c_id, receiver, orphans)]);
                     } else {
                         let mut requests = Vec::with_capacity(ids.len());
                         for &c_id in ids.iter() {
                             let (sender, receiver) = oneshot::channel();
-                            {
+                            let orphans = {
                                 let q_pair = self.queue_pairs[actual_qid].lock().await;
-                                q_pair.pending.lock().await.insert(c_id, sender);
-                            }
-                            requests.push(Request {
-                                id: c_id,
-                                receiver,
-                                state: crate::request::State::Submitted,
-                            });
+                                q_pair
+                                    .pending
+                                    .lock()
+                                    .await
+                                    .insert(c_id, (sender, Instant::now()));
+                                Arc::clone(&q_pair.orphans)
+                            };
+                            requests.push(Request::new(c_id, receiver, orphans));
                         }
                         if let Some(tail) = tail {
                             self.queue_pairs[actual_qid]
@@ -162,15 +513,28 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
                                 .await
                                 .set_tail(tail as u32);
                         }
-                        return requests;
+                        return Ok(requests);
+                    }
+                }
+                None => {
+                    actual_qid = (actual_qid + 1) % self.queue_pairs.len();
+                    if actual_qid == q_id {
+                        // every queue is full or blocked by orphans, let the polling tasks
+                        // complete and expire commands before the next pass
+                        tokio::task::yield_now().await;
                     }
                 }
-                None => actual_qid = (actual_qid + 1) % self.queue_pairs.len(),
             }
         }
     }
 
-    pub async fn read_batch(&self, q_id: usize, datas: &[T], lbas: &[u64]) -> Vec<Request> {
+    pub async fn read_batch(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        datas: &[T],
+        lbas: &[u64],
+    ) -> std::io::Result<Vec<Request>> {
         assert_eq!(
             datas.len(),
             lbas.len(),
@@ -181,10 +545,14 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
         let mut all_ids = Vec::with_capacity(datas.len());
 
         let mut q_pair = self.queue_pairs[q_id].lock().await;
+        // reject the whole batch before anything is submitted
+        for (data, &lba) in datas.iter().zip(lbas.iter()) {
+            q_pair.check_transfer(ns, data, lba)?;
+        }
 
         let mut last_tail = None;
         for (data, &lba) in datas.iter().zip(lbas.iter()) {
-            let (tail, ids) = q_pair.submit_async(data, lba, false);
+            let (tail, ids) = q_pair.submit_async(ns, data, lba, false, false)?;
             all_ids.extend(ids);
             if let Some(tail) = tail {
                 last_tail = Some(tail);
@@ -193,42 +561,73 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
         drop(q_pair);
         for c_id in all_ids {
             let (sender, receiver) = oneshot::channel();
-            {
+            let orphans = {
                 let q_pair = self.queue_pairs[q_id].lock().await;
-                q_pair.pending.lock().await.insert(c_id, sender);
-            }
-            requests.push(Request {
-                id: c_id,
-                receiver,
-                state: crate::request::State::Submitted,
-            });
+                q_pair
+                    .pending
+                    .lock()
+                    .await
+                    .insert(c_id, (sender, Instant::now()));
+                Arc::clone(&q_pair.orphans)
+            };
+            requests.push(Request::new(c_id, receiver, orphans));
         }
         if let Some(tail) = last_tail {
             self.queue_pairs[q_id].lock().await.set_tail(tail as u32);
         }
-        requests
+        Ok(requests)
+    }
+
+    pub async fn write(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        data: &T,
+        lba: u64,
+    ) -> std::io::Result<Vec<Request>> {
+        self.submit_write(q_id, ns, data, lba, false).await
+    }
+
+    /// Like [`Driver::write`], but the requests only complete once the data is on non-volatile
+    /// media (Force Unit Access)
+    pub async fn write_fua(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        data: &T,
+        lba: u64,
+    ) -> std::io::Result<Vec<Request>> {
+        self.submit_write(q_id, ns, data, lba, true).await
     }
 
-    pub async fn write(&self, q_id: usize, data: &T, lba: u64) -> Vec<Request> {
+    async fn submit_write(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        data: &T,
+        lba: u64,
+        fua: bool,
+    ) -> std::io::Result<Vec<Request>> {
         let mut requests = Vec::new();
         let mut actual_qid = q_id;
         loop {
-            match self.submit(actual_qid, data, lba, true).await {
+            match self.submit(actual_qid, ns, data, lba, true, fua).await? {
                 Some((tail, ids)) => {
                     if ids.is_empty() {
                         println!("Empty command id list");
                     }
                     for &c_id in ids.iter() {
                         let (sender, receiver) = oneshot::channel();
-                        {
+                        let orphans = {
                             let q_pair = self.queue_pairs[actual_qid].lock().await;
-                            q_pair.pending.lock().await.insert(c_id, sender);
-                        }
-                        requests.push(Request {
-                            id: c_id,
-                            receiver,
-                            state: crate::request::State::Submitted,
-                        });
+                            q_pair
+                                .pending
+                                .lock()
+                                .await
+                                .insert(c_id, (sender, Instant::now()));
+                            Arc::clone(&q_pair.orphans)
+                        };
+                        requests.push(Request::new(c_id, receiver, orphans));
                     }
                     if let Some(tail) = tail {
                         self.queue_pairs[actual_qid]
@@ -238,13 +637,26 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
                     }
                     break;
                 }
-                None => actual_qid = (actual_qid + 1) % self.queue_pairs.len(),
+                None => {
+                    actual_qid = (actual_qid + 1) % self.queue_pairs.len();
+                    if actual_qid == q_id {
+                        // every queue is full or blocked by orphans, let the polling tasks
+                        // complete and expire commands before the next pass
+                        tokio::task::yield_now().await;
+                    }
+                }
             }
         }
-        requests
+        Ok(requests)
     }
 
-    pub async fn write_batch(&self, q_id: usize, datas: &[T], lbas: &[u64]) -> Vec<Request> {
+    pub async fn write_batch(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        datas: &[T],
+        lbas: &[u64],
+    ) -> std::io::Result<Vec<Request>> {
         assert_eq!(
             datas.len(),
             lbas.len(),
@@ -255,10 +667,14 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
         let mut all_ids = Vec::with_capacity(datas.len());
 
         let mut q_pair = self.queue_pairs[q_id].lock().await;
+        // reject the whole batch before anything is submitted
+        for (data, &lba) in datas.iter().zip(lbas.iter()) {
+            q_pair.check_transfer(ns, data, lba)?;
+        }
 
         let mut last_tail = None;
         for (data, &lba) in datas.iter().zip(lbas.iter()) {
-            let (tail, ids) = q_pair.submit_async(data, lba, true);
+            let (tail, ids) = q_pair.submit_async(ns, data, lba, true, false)?;
             all_ids.extend(ids);
             if let Some(tail) = tail {
                 last_tail = Some(tail);
@@ -267,43 +683,313 @@ impl<T: DmaSlice + std::marker::Sync + std::marker::Send + 'static + Debug> Driv
         drop(q_pair);
         for c_id in all_ids {
             let (sender, receiver) = oneshot::channel();
-            {
+            let orphans = {
                 let q_pair = self.queue_pairs[q_id].lock().await;
-                q_pair.pending.lock().await.insert(c_id, sender);
-            }
-            requests.push(Request {
-                id: c_id,
-                receiver,
-                state: crate::request::State::Submitted,
-            });
+                q_pair
+                    .pending
+                    .lock()
+                    .await
+                    .insert(c_id, (sender, Instant::now()));
+                Arc::clone(&q_pair.orphans)
+            };
+            requests.push(Request::new(c_id, receiver, orphans));
         }
         if let Some(tail) = last_tail {
             self.queue_pairs[q_id].lock().await.set_tail(tail as u32);
         }
-        requests
+        Ok(requests)
     }
 
-    // for manual cleanup at end of program
-    pub async fn cleanup(&self) -> Result<(), Box<dyn std::error::Error>> {
-        for q_id in 0..self.queue_pairs.len() {
-            let q_pair = self.queue_pairs[q_id].lock().await;
-            let pending = q_pair.pending.lock().await;
-            if !pending.is_empty() {
-                eprintln!("Outstanding requests in queue: {}", (q_id + 1));
+    /// Uses SGLs instead of PRPs on all queue pairs if the controller supports them.
+    /// Returns whether SGLs are used.
+    pub async fn set_sgl(&self, enable: bool) -> Result<bool, Box<dyn Error>> {
+        let mut enabled = false;
+        for q_pair in self.queue_pairs.iter() {
+            enabled = q_pair.lock().await.set_sgl(enable)?;
+        }
+        Ok(enabled)
+    }
+
+    /// Reads consecutive blocks starting at `lba` into the buffers described by `descriptors`
+    /// with a single command. Requires SGLs, see [`Driver::set_sgl`].
+    pub async fn read_vectored(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        descriptors: &[SglDescriptor],
+        lba: u64,
+    ) -> std::io::Result<Request> {
+        self.submit_vectored(q_id, ns, descriptors, lba, false)
+            .await
+    }
+
+    /// Writes the buffers described by `descriptors` to consecutive blocks starting at `lba`
+    /// with a single command. Requires SGLs, see [`Driver::set_sgl`].
+    pub async fn write_vectored(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        descriptors: &[SglDescriptor],
+        lba: u64,
+    ) -> std::io::Result<Request> {
+        self.submit_vectored(q_id, ns, descriptors, lba, true).await
+    }
+
+    async fn submit_vectored(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        descriptors: &[SglDescriptor],
+        lba: u64,
+        write: bool,
+    ) -> std::io::Result<Request> {
+        loop {
+            let mut q_pair = self.queue_pairs[q_id].lock().await;
+            if let Some((tail, c_id)) =
+                q_pair.submit_vectored(ns, descriptors, lba, write, false)?
+            {
+                let (sender, receiver) = oneshot::channel();
+                q_pair
+                    .pending
+                    .lock()
+                    .await
+                    .insert(c_id, (sender, Instant::now()));
+                q_pair.set_tail(tail as u32);
+                return Ok(Request::new(c_id, receiver, Arc::clone(&q_pair.orphans)));
             }
+            drop(q_pair);
+            tokio::task::yield_now().await;
         }
+    }
 
+    /// Whether to send an Abort command for requests dropped before their completion.
+    /// Completions of dropped requests are discarded either way.
+    pub fn set_abort_dropped(&self, enable: bool) {
+        self.abort_dropped.store(enable, Ordering::Relaxed);
+    }
+
+    /// Number of commands on queue pair `q_id` whose request was dropped before completion
+    pub async fn dropped_outstanding(&self, q_id: usize) -> usize {
+        self.queue_pairs[q_id].lock().await.orphans.len()
+    }
+
+    /// Namespaces identified during initialization, ordered by namespace id
+    pub async fn namespaces(&self) -> Vec<NvmeNamespace> {
+        let mut namespaces: Vec<_> = self
+            .nvme
+            .lock()
+            .await
+            .namespaces
+            .values()
+            .copied()
+            .collect();
+        namespaces.sort_by_key(|ns| ns.id);
+        namespaces
+    }
+
+    /// Handle of namespace `id` for use with the I/O functions
+    pub async fn namespace(&self, id: u32) -> Option<NvmeNamespace> {
+        self.nvme.lock().await.namespaces.get(&id).copied()
+    }
+
+    /// Controller data read by identify controller during initialization
+    pub async fn controller_info(&self) -> ControllerInfo {
+        self.admin(|nvme| nvme.controller_info().clone()).await
+    }
+
+    /// Enables the Persistent Memory Region, see [`NvmeDevice::enable_pmr`]
+    pub async fn enable_pmr(&self) -> Result<PersistentMemoryRegion, Box<dyn Error>> {
+        self.admin(|nvme| nvme.enable_pmr()).await
+    }
+
+    /// Controller Memory Buffer enabled with [`DriverOptions::cmb`], to allocate data buffers
+    pub async fn cmb(&self) -> Option<ControllerMemoryBuffer> {
+        self.nvme.lock().await.cmb().cloned()
+    }
+
+    /// Reads the SMART / Health Information log page, see [`NvmeDevice::smart_log`]
+    pub async fn smart_log(&self, ns_id: u32) -> Result<SmartLog, Box<dyn Error>> {
+        self.admin(|nvme| nvme.smart_log(ns_id)).await
+    }
+
+    /// Reads the Error Information log page, see [`NvmeDevice::error_log`]
+    pub async fn error_log(&self) -> Result<Vec<ErrorLogEntry>, Box<dyn Error>> {
+        self.admin(|nvme| nvme.error_log()).await
+    }
+
+    /// Reads the Firmware Slot Information log page
+    pub async fn firmware_slot_log(&self) -> Result<FirmwareSlotLog, Box<dyn Error>> {
+        self.admin(|nvme| nvme.firmware_slot_log()).await
+    }
+
+    /// Reads `len` bytes of log page `lid` starting at byte `offset`, see
+    /// [`NvmeDevice::get_log_page`]
+    pub async fn get_log_page(
+        &self,
+        lid: u8,
+        ns_id: u32,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.admin(|nvme| nvme.get_log_page(lid, ns_id, offset, len))
+            .await
+    }
+
+    /// Reads the value of feature `F`, see [`NvmeDevice::get_feature`]
+    pub async fn get_feature<F: Feature>(
+        &self,
+        select: FeatureSelect,
+    ) -> Result<F, Box<dyn Error>> {
+        self.admin(|nvme| nvme.get_feature(select)).await
+    }
+
+    /// Changes feature `F`, see [`NvmeDevice::set_feature`]
+    pub async fn set_feature<F: Feature>(&self, value: &F) -> Result<u32, Box<dyn Error>> {
+        self.admin(|nvme| nvme.set_feature(value)).await
+    }
+
+    /// Commits all completed writes to namespace `ns` to non-volatile media.
+    /// Completes immediately if the controller has no volatile write cache.
+    ///
+    /// A flush covers writes completed on any queue, so it goes to the first queue pair with a
+    /// free slot.
+    pub async fn flush(&self, ns: &NvmeNamespace) -> std::io::Result<()> {
+        if !self.nvme.lock().await.has_volatile_write_cache() {
+            return Ok(());
+        }
+
+        let mut q_id = 0;
+        let request = loop {
+            let mut q_pair = self.queue_pairs[q_id].lock().await;
+            if let Some((tail, c_id)) = q_pair.submit_flush(ns) {
+                let (sender, receiver) = oneshot::channel();
+                q_pair
+                    .pending
+                    .lock()
+                    .await
+                    .insert(c_id, (sender, Instant::now()));
+                q_pair.set_tail(tail as u32);
+                break Request::new(c_id, receiver, Arc::clone(&q_pair.orphans));
+            }
+            drop(q_pair);
+            q_id = (q_id + 1) % self.queue_pairs.len();
+            if q_id == 0 {
+                // all queues are full, let the polling tasks complete commands
+                tokio::task::yield_now().await;
+            }
+        };
+        request.await
+    }
+
+    /// Deallocates (trims) `ranges` of namespace `ns` and waits for the commands to complete.
+    /// Range lists longer than the controller's limit are split into multiple commands.
+    pub async fn deallocate(
+        &self,
+        q_id: usize,
+        ns: &NvmeNamespace,
+        ranges: &[LbaRange],
+    ) -> std::io::Result<()> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        if ranges.iter().any(|range| range.blocks == 0) {
+            return Err(invalid_input("LBA ranges must not be empty"));
+        }
+        let limit = self
+            .nvme
+            .lock()
+            .await
+            .dsm_range_limit()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Unsupported, e.to_string()))?;
+
+        let mut requests = Vec::with_capacity(ranges.len().div_ceil(limit));
+        for chunk in ranges.chunks(limit) {
+            // one page of range descriptors per command, kept alive until the command completed
+            let mut page = self
+                .range_pool
+                .allocate(4096)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            write_dsm_ranges(&mut page[..], chunk);
+
+            loop {
+                let mut q_pair = self.queue_pairs[q_id].lock().await;
+                if let Some((tail, c_id)) = q_pair.submit_deallocate(ns, page.phys, chunk.len()) {
+                    let (sender, receiver) = oneshot::channel();
+                    q_pair
+                        .pending
+                        .lock()
+                        .await
+                        .insert(c_id, (sender, Instant::now()));
+                    q_pair.set_tail(tail as u32);
+                    requests.push(
+                        Request::new(c_id, receiver, Arc::clone(&q_pair.orphans)).keep_alive(page),
+                    );
+                    break;
+                }
+                drop(q_pair);
+                tokio::task::yield_now().await;
+            }
+        }
+
+        futures::future::join_all(requests)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Deletes all queue pairs and shuts the controller down, see [`NvmeDevice::shutdown`].
+    /// Done on drop unless called before, but errors are only reported here.
+    pub async fn cleanup(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut nvme = self.nvme.lock().await;
-        for q_pair in &self.queue_pairs {
-            let id = q_pair.lock().await.id;
+        let mut q_pairs = Vec::with_capacity(self.queue_pairs.len());
+        for q_pair in self.queue_pairs.iter() {
+            q_pairs.push(q_pair.lock().await);
+        }
+        shutdown(&mut nvme, q_pairs.iter_mut().map(|q_pair| &mut **q_pair))
+    }
+
+    /// Like [`Driver::cleanup`], but also gives the device back to its kernel driver, see
+    /// [`NvmeDevice::release`]
+    pub async fn release(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.cleanup().await?;
+        self.nvme.lock().await.release()
+    }
+
+    /// Whether dropping the driver gives the device back to its kernel driver, see
+    /// [`NvmeDevice::set_release_on_drop`]
+    pub async fn set_release_on_drop(&self, enable: bool) {
+        self.nvme.lock().await.set_release_on_drop(enable);
+    }
+}
 
-            nvme.submit_and_complete_admin(|c_id, _| {
-                NvmeCommand::delete_io_submission_queue(c_id, id)
-            })?;
-            nvme.submit_and_complete_admin(|c_id, _| {
-                NvmeCommand::delete_io_completion_queue(c_id, id)
-            })?;
+// deletes `q_pairs` unless the controller was already shut down, then shuts it down
+fn shutdown<'a, T: DmaSlice + Debug + 'a>(
+    nvme: &mut NvmeDevice<T>,
+    q_pairs: impl Iterator<Item = &'a mut NvmeQueuePair<T>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if nvme.is_shut_down() {
+        return Ok(());
+    }
+    let mut q_ids = Vec::new();
+    for q_pair in q_pairs {
+        if !q_pair.pending.get_mut().is_empty() {
+            eprintln!("Outstanding requests in queue: {}", q_pair.id);
+        }
+        q_ids.push(q_pair.id);
+    }
+    // shut down regardless, the volatile write cache matters more
+    nvme.shutdown_deleting(q_ids)
+}
+
+impl<T: DmaSlice + Debug> Drop for Driver<T> {
+    fn drop(&mut self) {
+        // the polling tasks only hold weak references, nothing else can access the queues
+        let Some(nvme) = Arc::get_mut(&mut self.nvme) else {
+            return;
+        };
+        let q_pairs = self.queue_pairs.iter_mut().map(|q_pair| q_pair.get_mut());
+        if let Err(e) = shutdown(nvme.get_mut(), q_pairs) {
+            eprintln!("shutdown failed: {e}");
         }
-        Ok(())
     }
 }
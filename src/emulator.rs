@@ -0,0 +1,1405 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::cmd::NvmeCommand;
+use crate::memory::{self, Dma};
+use crate::nvme::{NvmeRegs32, NvmeRegs64};
+use crate::prp::PAGE_SIZE;
+use crate::queues::QueuePriority;
+use crate::registers::{Bar, RegisterAccess};
+
+/// Size of the emulated BAR0, leaves room for 1536 queue pairs with a doorbell stride of 0
+const BAR_SIZE: usize = 0x4000;
+/// Maximum Queue Entries Supported, I/O queues of this size span multiple huge pages
+const MAX_QUEUE_ENTRIES: usize = 1 << 16;
+/// BAR holding the Controller Memory Buffer, which takes up all of it
+const CMB_BAR: u8 = 2;
+/// Controller Memory Buffer size in units of 1 MiB (CMBSZ.SZU = 2)
+const CMB_SIZE_MIB: usize = 16;
+/// BAR holding the Persistent Memory Region
+const PMR_BAR: u8 = 4;
+const PMR_SIZE_MIB: usize = 4;
+/// Number of I/O queues granted by Get Features Number of Queues
+const MAX_IO_QUEUES: u32 = 64;
+/// Asynchronous Event Request Limit
+const AERL: usize = 4;
+/// Warning and critical composite temperature thresholds in Kelvin
+const WCTEMP: u16 = 343;
+const CCTEMP: u16 = 353;
+/// Number of entries of the Error Information log page
+const ERROR_LOG_ENTRIES: usize = 64;
+/// Dataset Management Ranges Limit
+const DMRL: u8 = 128;
+/// Maximum Data Transfer Size as a power of two of the minimum memory page size (4 KiB)
+const MDTS: u8 = 5;
+
+// status codes, NVMe spec 4.6.1.2
+const SUCCESS: u16 = 0x00;
+const INVALID_OPCODE: u16 = 0x01;
+const INVALID_FIELD: u16 = 0x02;
+const COMMAND_SEQUENCE_ERROR: u16 = 0x0C;
+const ABORT_REQUESTED: u16 = 0x07;
+const DATA_TRANSFER_ERROR: u16 = 0x04;
+const INVALID_NAMESPACE: u16 = 0x0B;
+const INVALID_SGL_SEGMENT_DESCRIPTOR: u16 = 0x0D;
+const DATA_SGL_LENGTH_INVALID: u16 = 0x0F;
+const SGL_DESCRIPTOR_TYPE_INVALID: u16 = 0x11;
+const LBA_OUT_OF_RANGE: u16 = 0x80;
+// command specific status codes (SCT 1)
+const COMPLETION_QUEUE_INVALID: u16 = 0x100;
+const INVALID_QUEUE_IDENTIFIER: u16 = 0x101;
+const INVALID_QUEUE_SIZE: u16 = 0x102;
+const ASYNC_EVENT_REQUEST_LIMIT_EXCEEDED: u16 = 0x105;
+const INVALID_QUEUE_DELETION: u16 = 0x10C;
+
+/// Backing store of the emulated namespace
+#[derive(Debug)]
+pub enum Storage {
+    Ram(Vec<u8>),
+    File(File),
+}
+
+impl Storage {
+    fn len(&self) -> Result<u64, Box<dyn Error>> {
+        match self {
+            Storage::Ram(data) => Ok(data.len() as u64),
+            Storage::File(file) => Ok(file.metadata()?.len()),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Box<dyn Error>> {
+        match self {
+            Storage::Ram(data) => {
+                buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()])
+            }
+            Storage::File(file) => file.read_exact_at(buf, offset)?,
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Box<dyn Error>> {
+        match self {
+            Storage::Ram(data) => {
+                data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf)
+            }
+            Storage::File(file) => file.write_all_at(buf, offset)?,
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Box<dyn Error>> {
+        if let Storage::File(file) = self {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SubQueue {
+    addr: usize,
+    size: usize,
+    cq_id: u16,
+    head: usize,
+    // QPRIO of the Create I/O Submission Queue command
+    priority: QueuePriority,
+}
+
+#[derive(Debug)]
+struct CompQueue {
+    addr: usize,
+    size: usize,
+    head: usize,
+    tail: usize,
+    phase: bool,
+    // completions that did not fit into the queue yet
+    backlog: VecDeque<(u32, u16, u16, u16, u16)>,
+}
+
+impl CompQueue {
+    fn new(addr: usize, size: usize) -> Self {
+        Self {
+            addr,
+            size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+
+    fn post(&mut self, dw0: u32, sq_head: u16, sq_id: u16, c_id: u16, status: u16) {
+        if self.is_full() || !self.backlog.is_empty() {
+            self.backlog.push_back((dw0, sq_head, sq_id, c_id, status));
+        } else {
+            self.write_entry(dw0, sq_head, sq_id, c_id, status);
+        }
+    }
+
+    fn write_entry(&mut self, dw0: u32, sq_head: u16, sq_id: u16, c_id: u16, status: u16) {
+        let entry = (self.addr + self.tail * 16) as *mut u32;
+        unsafe {
+            std::ptr::write_volatile(entry, dw0);
+            std::ptr::write_volatile(entry.add(1), 0);
+            std::ptr::write_volatile(entry.add(2), (sq_id as u32) << 16 | sq_head as u32);
+            // the phase bit must only become visible after the rest of the entry
+            fence(Ordering::Release);
+            let status = status << 1 | self.phase as u16;
+            std::ptr::write_volatile(entry.add(3), (status as u32) << 16 | c_id as u32);
+        }
+
+        self.tail = (self.tail + 1) % self.size;
+        if self.tail == 0 {
+            self.phase = !self.phase;
+        }
+    }
+
+    fn set_head(&mut self, head: usize) {
+        self.head = head % self.size;
+        while !self.is_full() {
+            match self.backlog.pop_front() {
+                Some((dw0, sq_head, sq_id, c_id, status)) => {
+                    self.write_entry(dw0, sq_head, sq_id, c_id, status)
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    sub_queues: HashMap<u16, SubQueue>,
+    comp_queues: HashMap<u16, CompQueue>,
+    // current values of the features transferred in dword 11
+    features: HashMap<u8, u32>,
+    // composite over and under temperature thresholds in Kelvin
+    temperature_thresholds: (u16, u16),
+    // milliseconds set by the host and when
+    timestamp: Option<(u64, Instant)>,
+    // Host Memory Buffer size, descriptor list address and entry count
+    host_memory: [u32; 4],
+    // Controller Memory Buffer Memory Space Control
+    cmbmsc: u64,
+    // Persistent Memory Region Control and Memory Space Control
+    pmrctl: u32,
+    pmrmsc: u64,
+    // shadow doorbell and EventIdx buffer set with Doorbell Buffer Config
+    doorbell_buffers: Option<(usize, usize)>,
+    doorbell_writes: u64,
+    // outstanding asynchronous event requests
+    aers: Vec<u16>,
+    // completion dword 0 of events waiting for a request
+    events: VecDeque<u32>,
+    // I/O commands are held instead of executed, see `EmulatedController::stall_io`
+    stall_io: bool,
+    ignore_aborts: bool,
+    // opcode of the next admin command to leave uncompleted
+    ignored_admin: Option<u8>,
+    // held I/O commands as (sq id, command id, sq head after the command)
+    held: Vec<(u16, u16, u16)>,
+    health: Health,
+    storage: Storage,
+    block_size: u64,
+    blocks: u64,
+}
+
+/// Counters reported in the SMART / Health Information log page
+#[derive(Debug, Default)]
+struct Health {
+    bytes_read: u64,
+    bytes_written: u64,
+    read_commands: u64,
+    write_commands: u64,
+    power_cycles: u64,
+    // composite temperature in Kelvin, 0 until set
+    temperature: u16,
+    error_count: u64,
+    // most recent error first
+    errors: VecDeque<ErrorEntry>,
+}
+
+/// Entry of the Error Information log page
+#[derive(Debug)]
+struct ErrorEntry {
+    error_count: u64,
+    sqid: u16,
+    cid: u16,
+    status: u16,
+    lba: u64,
+    ns_id: u32,
+}
+
+/// NVMe controller emulated in software.
+///
+/// Exposes the register layout of a real controller through [`RegisterAccess`] and executes
+/// commands synchronously when a submission queue doorbell is written. The controller has a
+/// single namespace (id 1) backed by RAM or a file image, a 16 MiB Controller Memory Buffer in
+/// BAR 2 and a 4 MiB Persistent Memory Region in BAR 4.
+///
+/// Device addresses in commands are interpreted as virtual addresses of this process, so creating
+/// an emulated controller switches all further [`memory::Dma`] allocations to regular memory.
+/// Real devices can't be opened in the same process afterwards, and creating a controller fails
+/// once one was opened. Only available with the `emulator` feature.
+#[derive(Debug)]
+pub struct EmulatedController {
+    state: Mutex<State>,
+    // Controller Memory Buffer, supports submission queues, PRP lists and data
+    cmb: Arc<Dma<u8>>,
+    // Persistent Memory Region, only as persistent as the process
+    pmr: Arc<Dma<u8>>,
+}
+
+impl EmulatedController {
+    /// Creates a controller whose namespace is `size` bytes of zeroed RAM.
+    pub fn ram(size: usize, block_size: u64) -> Result<Arc<Self>, Box<dyn Error>> {
+        Self::new(Storage::Ram(vec![0; size]), block_size)
+    }
+
+    /// Creates a controller whose namespace is the (existing) image file at `path`.
+    pub fn file(path: &str, block_size: u64) -> Result<Arc<Self>, Box<dyn Error>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::new(Storage::File(file), block_size)
+    }
+
+    pub fn new(storage: Storage, block_size: u64) -> Result<Arc<Self>, Box<dyn Error>> {
+        if !block_size.is_power_of_two() || !(512..=1 << 16).contains(&block_size) {
+            return Err(format!("unsupported block size {block_size}").into());
+        }
+        let blocks = storage.len()? / block_size;
+
+        memory::enable_identity_mapping()?;
+
+        Ok(Arc::new(Self {
+            cmb: Arc::new(Dma::allocate(CMB_SIZE_MIB << 20)?),
+            pmr: Arc::new(Dma::allocate(PMR_SIZE_MIB << 20)?),
+            state: Mutex::new(State {
+                cc: 0,
+                csts: 0,
+                aqa: 0,
+                asq: 0,
+                acq: 0,
+                sub_queues: HashMap::new(),
+                comp_queues: HashMap::new(),
+                features: HashMap::new(),
+                temperature_thresholds: (WCTEMP, 0),
+                timestamp: None,
+                host_memory: [0; 4],
+                cmbmsc: 0,
+                pmrctl: 0,
+                pmrmsc: 0,
+                doorbell_buffers: None,
+                doorbell_writes: 0,
+                aers: Vec::new(),
+                events: VecDeque::new(),
+                stall_io: false,
+                ignore_aborts: false,
+                ignored_admin: None,
+                held: Vec::new(),
+                health: Health::default(),
+                storage,
+                block_size,
+                blocks,
+            }),
+        }))
+    }
+
+    /// Reads `buf.len()` bytes of the namespace starting at byte `offset`.
+    pub fn read_image(&self, buf: &mut [u8], offset: u64) -> Result<(), Box<dyn Error>> {
+        self.state.lock().unwrap().storage.read_at(buf, offset)
+    }
+
+    /// Holds all further I/O commands instead of executing them, to test timeouts. Held commands
+    /// only complete once they are aborted, or are discarded by a controller reset.
+    pub fn stall_io(&self, stall: bool) {
+        self.state.lock().unwrap().stall_io = stall;
+    }
+
+    /// Never completes Abort commands, to test escalation to a controller reset.
+    pub fn ignore_aborts(&self, ignore: bool) {
+        self.state.lock().unwrap().ignore_aborts = ignore;
+    }
+
+    /// Never completes the next admin command with `opcode`, to test admin command timeouts.
+    pub fn ignore_admin_command(&self, opcode: u8) {
+        self.state.lock().unwrap().ignored_admin = Some(opcode);
+    }
+
+    /// Sets the Controller Fatal Status (CSTS.CFS), the controller stops processing commands
+    /// until it is reset.
+    pub fn inject_fatal_status(&self) {
+        self.state.lock().unwrap().csts |= 1 << 1;
+    }
+
+    /// Completes an outstanding Asynchronous Event Request with the event described by
+    /// `event_type`, `info` and `log_page`, or queues the event until a request is submitted.
+    pub fn raise_async_event(&self, event_type: u8, info: u8, log_page: u8) {
+        let dw0 = (log_page as u32) << 16 | (info as u32) << 8 | (event_type & 0x7) as u32;
+        self.state.lock().unwrap().raise_async_event(dw0);
+    }
+
+    /// Sets the composite temperature in Kelvin. Crossing a temperature threshold (over 70 °C by
+    /// default) sets the temperature critical warning and raises a SMART / health event if
+    /// enabled.
+    pub fn set_temperature(&self, kelvin: u16) {
+        let mut state = self.state.lock().unwrap();
+        let was_exceeded = state.temperature_exceeded();
+        state.health.temperature = kelvin;
+        let enabled = state
+            .features
+            .get(&0x0B)
+            .is_some_and(|config| config & (1 << 1) != 0);
+        if state.temperature_exceeded() && !was_exceeded && enabled {
+            // Temperature Threshold, SMART / Health Information log page
+            state.raise_async_event(2 << 16 | 1 << 8 | 1);
+        }
+    }
+
+    /// Number of doorbell register writes so far, shadow doorbells avoid most of them.
+    pub fn doorbell_writes(&self) -> u64 {
+        self.state.lock().unwrap().doorbell_writes
+    }
+
+    /// Priority class I/O submission queue `q_id` was created with, `None` if it does not exist.
+    pub fn submission_queue_priority(&self, q_id: u16) -> Option<QueuePriority> {
+        let state = self.state.lock().unwrap();
+        state.sub_queues.get(&q_id).map(|sq| sq.priority)
+    }
+
+    /// Writes `buf` to the namespace starting at byte `offset`, bypassing the queues.
+    pub fn write_image(&self, buf: &[u8], offset: u64) -> Result<(), Box<dyn Error>> {
+        self.state.lock().unwrap().storage.write_at(buf, offset)
+    }
+}
+
+impl RegisterAccess for EmulatedController {
+    fn size(&self) -> usize {
+        BAR_SIZE
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        let state = self.state.lock().unwrap();
+        match offset {
+            o if o == NvmeRegs32::VS as usize => 0x0001_0400,
+            o if o == NvmeRegs32::CC as usize => state.cc,
+            o if o == NvmeRegs32::CSTS as usize => state.csts,
+            o if o == NvmeRegs32::AQA as usize => state.aqa,
+            // CMBLOC and CMBSZ are only reported with CMBMSC.CRE set
+            o if o == NvmeRegs32::CMBLOC as usize && state.cmbmsc & 1 != 0 => CMB_BAR as u32,
+            o if o == NvmeRegs32::CMBSZ as usize && state.cmbmsc & 1 != 0 => {
+                // SZ | SZU = 1 MiB | WDS | RDS | LISTS | SQS
+                (CMB_SIZE_MIB as u32) << 12 | 2 << 8 | 0b11101
+            }
+            o if o == NvmeRegs32::CMBLOC as usize || o == NvmeRegs32::CMBSZ as usize => 0,
+            // CBAI: enabled at an address other than the one of the buffer
+            o if o == NvmeRegs32::CMBSTS as usize => {
+                let cba = (state.cmbmsc & !0xFFF) as usize;
+                (state.cmbmsc & 0b10 != 0 && cba != self.cmb.phys) as u32
+            }
+            o if o == NvmeRegs32::PMRCAP as usize => {
+                // RDS | WDS | BIR | PMRWBM: reading PMRSTS persists writes | PMRTO = 500 ms | CMSS
+                1 << 3 | 1 << 4 | (PMR_BAR as u32) << 5 | 0b10 << 10 | 1 << 16 | 1 << 24
+            }
+            o if o == NvmeRegs32::PMRCTL as usize => state.pmrctl,
+            o if o == NvmeRegs32::PMRSTS as usize => {
+                let nrdy = state.pmrctl & 1 == 0;
+                let cba = (state.pmrmsc & !0xFFF) as usize;
+                let cbai = state.pmrmsc & 0b10 != 0 && cba != self.pmr.phys;
+                (nrdy as u32) << 8 | (cbai as u32) << 12
+            }
+            // 64 KiB elasticity buffer
+            o if o == NvmeRegs32::PMREBS as usize => 64 << 8 | 1,
+            // 1 GiB/s
+            o if o == NvmeRegs32::PMRSWTP as usize => 1 << 8 | 3,
+            // PMRMSC is not 8 byte aligned
+            o if o == NvmeRegs64::PMRMSC as usize => state.pmrmsc as u32,
+            o if o == NvmeRegs64::PMRMSC as usize + 4 => (state.pmrmsc >> 32) as u32,
+            o if o < 0x1000 && o % 8 == 4 => (state.read64(o - 4) >> 32) as u32,
+            o if o < 0x1000 => state.read64(o) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            o if o == NvmeRegs32::CC as usize => state.set_cc(value),
+            o if o == NvmeRegs32::AQA as usize => state.aqa = value,
+            o if o == NvmeRegs32::PMRCTL as usize => state.pmrctl = value & 1,
+            o if o == NvmeRegs64::PMRMSC as usize => {
+                state.pmrmsc = (state.pmrmsc & !0xFFFF_FFFF) | value as u64
+            }
+            o if o == NvmeRegs64::PMRMSC as usize + 4 => {
+                state.pmrmsc = (state.pmrmsc & 0xFFFF_FFFF) | (value as u64) << 32
+            }
+            o if o >= 0x1000 => {
+                state.doorbell_writes += 1;
+                state.ring_doorbell(o, value)
+            }
+            _ => {}
+        }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        self.state.lock().unwrap().read64(offset)
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            o if o == NvmeRegs64::ASQ as usize => state.asq = value,
+            o if o == NvmeRegs64::ACQ as usize => state.acq = value,
+            o if o == NvmeRegs64::CMBMSC as usize => state.cmbmsc = value,
+            _ => {}
+        }
+    }
+
+    fn map_bar(&self, bar: u8) -> Result<Bar, Box<dyn Error>> {
+        match bar {
+            CMB_BAR => Ok(Bar::memory(Arc::clone(&self.cmb))),
+            PMR_BAR => Ok(Bar::memory(Arc::clone(&self.pmr))),
+            _ => Err(format!("emulated controller has no BAR {bar}").into()),
+        }
+    }
+}
+
+impl State {
+    fn read64(&self, offset: usize) -> u64 {
+        match offset {
+            o if o == NvmeRegs64::CAP as usize => {
+                // MQES | CQR | AMS = weighted round robin | TO = 10s | CSS = NVM command set | PMRS
+                // | CMBS, commands are executed in doorbell order regardless of the arbitration
+                (MAX_QUEUE_ENTRIES as u64 - 1)
+                    | 1 << 16
+                    | 1 << 17
+                    | 20 << 24
+                    | 1 << 37
+                    | 1 << 56
+                    | 1 << 57
+            }
+            o if o == NvmeRegs64::CMBMSC as usize => self.cmbmsc,
+            o if o == NvmeRegs64::ASQ as usize => self.asq,
+            o if o == NvmeRegs64::ACQ as usize => self.acq,
+            _ => 0,
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        4096 << ((self.cc >> 7) & 0xF)
+    }
+
+    fn set_cc(&mut self, value: u32) {
+        let enable = value & 1 == 1;
+        let enabled = self.cc & 1 == 1;
+        self.cc = value;
+
+        if enable && !enabled {
+            let sq_size = (self.aqa & 0xFFF) as usize + 1;
+            let cq_size = ((self.aqa >> 16) & 0xFFF) as usize + 1;
+            self.sub_queues.insert(
+                0,
+                SubQueue {
+                    addr: self.asq as usize,
+                    size: sq_size,
+                    cq_id: 0,
+                    head: 0,
+                    // the admin queue is served before all I/O queues
+                    priority: QueuePriority::Urgent,
+                },
+            );
+            self.comp_queues
+                .insert(0, CompQueue::new(self.acq as usize, cq_size));
+            self.csts |= 1;
+            self.health.power_cycles += 1;
+        } else if !enable && enabled {
+            self.sub_queues.clear();
+            self.comp_queues.clear();
+            self.aers.clear();
+            self.held.clear();
+            // a controller level reset restores the default features
+            self.features.clear();
+            self.temperature_thresholds = (WCTEMP, 0);
+            self.timestamp = None;
+            self.host_memory = [0; 4];
+            self.doorbell_buffers = None;
+            // a reset clears the fatal status
+            self.csts &= !0b11;
+        }
+
+        // shutdown notification, report shutdown processing complete right away
+        if (value >> 14) & 0b11 != 0 {
+            let _ = self.storage.sync();
+            self.csts = (self.csts & !0b1100) | 0b10 << 2;
+        } else {
+            self.csts &= !0b1100;
+        }
+    }
+
+    fn ring_doorbell(&mut self, offset: usize, value: u32) {
+        // no commands are processed after a fatal error
+        if self.csts & (1 << 1) != 0 {
+            return;
+        }
+        let idx = (offset - 0x1000) / self.doorbell_stride();
+        let qid = (idx / 2) as u16;
+
+        if idx % 2 == 1 {
+            if let Some(cq) = self.comp_queues.get_mut(&qid) {
+                cq.set_head(value as usize);
+            }
+            self.update_cq_event_idx(qid);
+            return;
+        }
+
+        let Some(sq) = self.sub_queues.get(&qid) else {
+            return;
+        };
+        let (addr, size, cq_id) = (sq.addr, sq.size, sq.cq_id);
+        // the shadow doorbell may already be further than the value written to the register
+        let tail = self.shadow_doorbell(idx).unwrap_or(value) as usize % size;
+        let mut head = sq.head;
+
+        while head != tail {
+            let cmd = unsafe { std::ptr::read_volatile((addr + head * 64) as *const NvmeCommand) };
+            head = (head + 1) % size;
+            if let Some(sq) = self.sub_queues.get_mut(&qid) {
+                sq.head = head;
+            }
+
+            let result = if qid == 0 {
+                self.execute_admin(&cmd)
+            } else if self.stall_io {
+                self.held.push((qid, cmd.c_id, head as u16));
+                None
+            } else {
+                self.execute_io(&cmd)
+            };
+
+            if let Some((dw0, status)) = result {
+                if status != SUCCESS {
+                    self.log_error(qid, &cmd, status);
+                }
+                self.sync_cq_head(cq_id);
+                if let Some(cq) = self.comp_queues.get_mut(&cq_id) {
+                    cq.post(dw0, head as u16, qid, cmd.c_id, status);
+                }
+            }
+
+            // the queue may have been deleted by the command
+            if !self.sub_queues.contains_key(&qid) {
+                break;
+            }
+        }
+
+        // commands are only processed on doorbell writes, so every new one has to be rung
+        self.set_event_idx(idx, head as u32);
+        self.update_cq_event_idx(cq_id);
+    }
+
+    fn doorbell_stride(&self) -> usize {
+        4 << ((self.read64(NvmeRegs64::CAP as usize) >> 32) & 0xF)
+    }
+
+    // value of doorbell `idx` in the shadow doorbell buffer, the admin queue always uses the
+    // registers
+    fn shadow_doorbell(&self, idx: usize) -> Option<u32> {
+        let (shadow, _) = self.doorbell_buffer_entries(idx)?;
+        Some(unsafe { std::ptr::read_volatile(shadow as *const u32) })
+    }
+
+    fn set_event_idx(&self, idx: usize, value: u32) {
+        if let Some((_, event_idx)) = self.doorbell_buffer_entries(idx) {
+            unsafe { std::ptr::write_volatile(event_idx as *mut u32, value) };
+        }
+    }
+
+    // addresses of the shadow doorbell and EventIdx entries of doorbell `idx`, both buffers are
+    // a single page
+    fn doorbell_buffer_entries(&self, idx: usize) -> Option<(usize, usize)> {
+        let offset = idx * self.doorbell_stride();
+        let (shadow, event_idx) = self
+            .doorbell_buffers
+            .filter(|_| idx >= 2 && offset + 4 <= PAGE_SIZE)?;
+        Some((shadow + offset, event_idx + offset))
+    }
+
+    // picks up completion queue head updates only written to the shadow doorbell
+    fn sync_cq_head(&mut self, qid: u16) {
+        if let Some(head) = self.shadow_doorbell(2 * qid as usize + 1) {
+            if let Some(cq) = self.comp_queues.get_mut(&qid) {
+                cq.set_head(head as usize);
+            }
+        }
+    }
+
+    // the head is read from the shadow doorbell before posting, so only a full queue with
+    // waiting completions needs the register write of the next head update
+    fn update_cq_event_idx(&mut self, qid: u16) {
+        let idx = 2 * qid as usize + 1;
+        loop {
+            let Some(cq) = self.comp_queues.get(&qid) else {
+                return;
+            };
+            let head = cq.head;
+            if cq.backlog.is_empty() {
+                self.set_event_idx(idx, (head as u32).wrapping_sub(1));
+                return;
+            }
+            self.set_event_idx(idx, head as u32);
+            fence(Ordering::SeqCst);
+            // the host may have moved the head before it could see the EventIdx
+            self.sync_cq_head(qid);
+            if self.comp_queues.get(&qid).is_none_or(|cq| cq.head == head) {
+                return;
+            }
+        }
+    }
+
+    fn temperature(&self) -> u16 {
+        match self.health.temperature {
+            // 35 °C
+            0 => 308,
+            kelvin => kelvin,
+        }
+    }
+
+    // I/O submission and completion queues allocated with Number of Queues
+    fn allocated_queues(&self) -> (u16, u16) {
+        let dw0 = match self.features.get(&0x07) {
+            Some(&dw0) => dw0,
+            None => State::default_feature(0x07).unwrap(),
+        };
+        ((dw0 & 0xFFFF) as u16 + 1, (dw0 >> 16) as u16 + 1)
+    }
+
+    fn temperature_exceeded(&self) -> bool {
+        let (over, under) = self.temperature_thresholds;
+        self.temperature() >= over || self.temperature() < under
+    }
+
+    // values after a reset, `None` for unsupported features
+    fn default_feature(fid: u8) -> Option<u32> {
+        match fid {
+            // Arbitration, Power Management, Interrupt Coalescing, Async Event Configuration,
+            // Host Memory Buffer
+            0x01 | 0x02 | 0x08 | 0x0B | 0x0D => Some(0),
+            // Volatile Write Cache: enabled
+            0x06 => Some(1),
+            0x07 => Some((MAX_IO_QUEUES - 1) << 16 | (MAX_IO_QUEUES - 1)),
+            _ => None,
+        }
+    }
+
+    fn set_features(&mut self, cmd: &NvmeCommand) -> Option<(u32, u16)> {
+        let fid = cmd.cdw10 as u8;
+        let status = match fid {
+            // composite temperature only
+            0x04 if (cmd.cdw11 >> 16) & 0xF != 0 => INVALID_FIELD,
+            0x04 => {
+                let threshold = cmd.cdw11 as u16;
+                match (cmd.cdw11 >> 20) & 0x3 {
+                    0 => self.temperature_thresholds.0 = threshold,
+                    1 => self.temperature_thresholds.1 = threshold,
+                    _ => return Some((0, INVALID_FIELD)),
+                }
+                SUCCESS
+            }
+            // a single power state
+            0x02 if cmd.cdw11 & 0x1F != 0 => INVALID_FIELD,
+            // only before any i/o queues were created
+            0x07 if !self
+                .sub_queues
+                .keys()
+                .chain(self.comp_queues.keys())
+                .all(|&qid| qid == 0) =>
+            {
+                COMMAND_SEQUENCE_ERROR
+            }
+            // grants up to MAX_IO_QUEUES of each
+            0x07 => {
+                let granted = |requested: u32| requested.min(MAX_IO_QUEUES - 1);
+                let dw0 = granted(cmd.cdw11 >> 16) << 16 | granted(cmd.cdw11 & 0xFFFF);
+                self.features.insert(fid, dw0);
+                return Some((dw0, SUCCESS));
+            }
+            0x0D => {
+                let enable = cmd.cdw11 & 1 == 1;
+                self.host_memory = match enable {
+                    true => [cmd.cdw12, cmd.cdw13, cmd.cdw14, cmd.cdw15],
+                    false => [0; 4],
+                };
+                self.features.insert(fid, enable as u32);
+                SUCCESS
+            }
+            0x0E => {
+                let mut data = [0; 8];
+                match self.read_data(cmd, &mut data) {
+                    SUCCESS => {
+                        let millis = u64::from_le_bytes(data) & 0xFFFF_FFFF_FFFF;
+                        self.timestamp = Some((millis, Instant::now()));
+                        SUCCESS
+                    }
+                    status => status,
+                }
+            }
+            fid if State::default_feature(fid).is_some() => {
+                self.features.insert(fid, cmd.cdw11);
+                SUCCESS
+            }
+            _ => INVALID_FIELD,
+        };
+        Some((0, status))
+    }
+
+    fn get_features(&mut self, cmd: &NvmeCommand) -> Option<(u32, u16)> {
+        let fid = cmd.cdw10 as u8;
+        let select = (cmd.cdw10 >> 8) & 0x7;
+        // nothing is saveable, the saved values are the defaults
+        let default = select == 1 || select == 2;
+        let dw0 = match (select, fid) {
+            // capabilities: changeable
+            (3, 0x04 | 0x0E) => 1 << 2,
+            (3, fid) if State::default_feature(fid).is_some() => 1 << 2,
+            (0..=2, 0x04) => {
+                if (cmd.cdw11 >> 16) & 0xF != 0 {
+                    return Some((0, INVALID_FIELD));
+                }
+                let (over, under) = match default {
+                    true => (WCTEMP, 0),
+                    false => self.temperature_thresholds,
+                };
+                match (cmd.cdw11 >> 20) & 0x3 {
+                    0 => over as u32,
+                    1 => under as u32,
+                    _ => return Some((0, INVALID_FIELD)),
+                }
+            }
+            (0..=2, 0x0D) => {
+                let mut data = vec![0; 4096];
+                if !default {
+                    for (i, dword) in self.host_memory.iter().enumerate() {
+                        data[i * 4..i * 4 + 4].copy_from_slice(&dword.to_le_bytes());
+                    }
+                }
+                let status = self.write_data(cmd, &data);
+                let enabled = match default {
+                    true => 0,
+                    false => self.features.get(&fid).copied().unwrap_or(0),
+                };
+                return Some((enabled, status));
+            }
+            (0..=2, 0x0E) => {
+                let mut data = [0; 8];
+                if let Some((millis, set)) = self.timestamp.filter(|_| !default) {
+                    let millis = millis + set.elapsed().as_millis() as u64;
+                    data[..6].copy_from_slice(&millis.to_le_bytes()[..6]);
+                    // Timestamp Origin: set by the host
+                    data[6] = 1 << 1;
+                }
+                return Some((0, self.write_data(cmd, &data)));
+            }
+            (0..=2, fid) => match State::default_feature(fid) {
+                Some(value) if default => value,
+                Some(value) => self.features.get(&fid).copied().unwrap_or(value),
+                None => return Some((0, INVALID_FIELD)),
+            },
+            _ => return Some((0, INVALID_FIELD)),
+        };
+        Some((dw0, SUCCESS))
+    }
+
+    fn raise_async_event(&mut self, dw0: u32) {
+        if self.aers.is_empty() {
+            self.events.push_back(dw0);
+            return;
+        }
+        let c_id = self.aers.remove(0);
+        let sq_head = self.sub_queues.get(&0).map_or(0, |sq| sq.head as u16);
+        if let Some(cq) = self.comp_queues.get_mut(&0) {
+            cq.post(dw0, sq_head, 0, c_id, SUCCESS);
+        }
+    }
+
+    fn log_error(&mut self, sqid: u16, cmd: &NvmeCommand, status: u16) {
+        let health = &mut self.health;
+        health.error_count += 1;
+        health.errors.push_front(ErrorEntry {
+            error_count: health.error_count,
+            sqid,
+            cid: cmd.c_id,
+            status,
+            lba: if sqid == 0 {
+                0
+            } else {
+                (cmd.cdw11 as u64) << 32 | cmd.cdw10 as u64
+            },
+            ns_id: cmd.ns_id,
+        });
+        health.errors.truncate(ERROR_LOG_ENTRIES);
+    }
+
+    /// Returns `None` if the command does not complete immediately
+    fn execute_admin(&mut self, cmd: &NvmeCommand) -> Option<(u32, u16)> {
+        let qid = cmd.cdw10 as u16;
+        let size = (cmd.cdw10 >> 16) as usize + 1;
+        let mqes = MAX_QUEUE_ENTRIES;
+
+        if self.ignored_admin == Some(cmd.opcode) {
+            self.ignored_admin = None;
+            return None;
+        }
+        let status = match cmd.opcode {
+            // Delete I/O Submission Queue
+            0x00 => {
+                if qid == 0 || self.sub_queues.remove(&qid).is_none() {
+                    INVALID_QUEUE_IDENTIFIER
+                } else {
+                    SUCCESS
+                }
+            }
+            // Create I/O Submission Queue
+            0x01 => {
+                let cq_id = (cmd.cdw11 >> 16) as u16;
+                if qid == 0 || qid > self.allocated_queues().0 || self.sub_queues.contains_key(&qid)
+                {
+                    INVALID_QUEUE_IDENTIFIER
+                } else if cq_id == 0 || !self.comp_queues.contains_key(&cq_id) {
+                    COMPLETION_QUEUE_INVALID
+                } else if !(2..=mqes).contains(&size) {
+                    INVALID_QUEUE_SIZE
+                } else if cmd.cdw11 & 1 == 0 {
+                    INVALID_FIELD
+                } else {
+                    self.sub_queues.insert(
+                        qid,
+                        SubQueue {
+                            addr: cmd.d_ptr[0] as usize,
+                            size,
+                            cq_id,
+                            head: 0,
+                            priority: match (cmd.cdw11 >> 1) & 0b11 {
+                                0 => QueuePriority::Urgent,
+                                1 => QueuePriority::High,
+                                2 => QueuePriority::Medium,
+                                _ => QueuePriority::Low,
+                            },
+                        },
+                    );
+                    SUCCESS
+                }
+            }
+            // Get Log Page
+            0x02 => {
+                let numd = ((cmd.cdw11 & 0xFFFF) << 16 | cmd.cdw10 >> 16) as usize + 1;
+                let offset = (cmd.cdw13 as u64) << 32 | cmd.cdw12 as u64;
+                let log = match cmd.cdw10 as u8 {
+                    0x01 => self.error_log(),
+                    0x02 if ![0, 1, 0xFFFF_FFFF].contains(&{ cmd.ns_id }) => {
+                        return Some((0, INVALID_NAMESPACE))
+                    }
+                    0x02 => self.smart_log(),
+                    0x03 => self.firmware_slot_log(),
+                    _ => Vec::new(),
+                };
+                if offset as usize > log.len() || !offset.is_multiple_of(4) {
+                    INVALID_FIELD
+                } else {
+                    let mut data = log[offset as usize..].to_vec();
+                    data.resize(numd * 4, 0);
+                    self.write_data(cmd, &data)
+                }
+            }
+            // Delete I/O Completion Queue
+            0x04 => {
+                if qid == 0 || !self.comp_queues.contains_key(&qid) {
+                    INVALID_QUEUE_IDENTIFIER
+                } else if self.sub_queues.values().any(|sq| sq.cq_id == qid) {
+                    INVALID_QUEUE_DELETION
+                } else {
+                    self.comp_queues.remove(&qid);
+                    SUCCESS
+                }
+            }
+            // Create I/O Completion Queue
+            0x05 => {
+                if qid == 0
+                    || qid > self.allocated_queues().1
+                    || self.comp_queues.contains_key(&qid)
+                {
+                    INVALID_QUEUE_IDENTIFIER
+                } else if !(2..=mqes).contains(&size) {
+                    INVALID_QUEUE_SIZE
+                } else if cmd.cdw11 & 1 == 0 {
+                    INVALID_FIELD
+                } else {
+                    self.comp_queues
+                        .insert(qid, CompQueue::new(cmd.d_ptr[0] as usize, size));
+                    SUCCESS
+                }
+            }
+            // Identify
+            0x06 => match cmd.cdw10 & 0xFF {
+                0 if cmd.ns_id != 1 => INVALID_NAMESPACE,
+                0 => self.write_data(cmd, &self.identify_namespace()),
+                1 => self.write_data(cmd, &self.identify_controller()),
+                2 => {
+                    let mut data = vec![0; 4096];
+                    if cmd.ns_id < 1 {
+                        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+                    }
+                    self.write_data(cmd, &data)
+                }
+                // NVM command set specific controller data
+                6 if cmd.cdw11 >> 24 == 0 => {
+                    let mut data = vec![0; 4096];
+                    data[3] = DMRL;
+                    self.write_data(cmd, &data)
+                }
+                _ => INVALID_FIELD,
+            },
+            // Abort, only held commands can be found
+            0x08 if self.ignore_aborts => return None,
+            0x08 => {
+                let (sqid, cid) = (cmd.cdw10 as u16, (cmd.cdw10 >> 16) as u16);
+                let Some(idx) = self
+                    .held
+                    .iter()
+                    .position(|&(q, c, _)| (q, c) == (sqid, cid))
+                else {
+                    return Some((1, SUCCESS));
+                };
+                let (_, _, sq_head) = self.held.remove(idx);
+                let cq_id = self.sub_queues.get(&sqid).map(|sq| sq.cq_id);
+                if let Some(cq_id) = cq_id {
+                    self.sync_cq_head(cq_id);
+                }
+                if let Some(cq) = cq_id.and_then(|id| self.comp_queues.get_mut(&id)) {
+                    cq.post(0, sq_head, sqid, cid, ABORT_REQUESTED);
+                }
+                if let Some(cq_id) = cq_id {
+                    self.update_cq_event_idx(cq_id);
+                }
+                SUCCESS
+            }
+            // Set Features
+            0x09 => return self.set_features(cmd),
+            // Get Features
+            0x0A => return self.get_features(cmd),
+            // Doorbell Buffer Config
+            0x7C => {
+                let (shadow, event_idx) = (cmd.d_ptr[0] as usize, cmd.d_ptr[1] as usize);
+                if shadow == 0 || event_idx == 0 || (shadow | event_idx) % 4096 != 0 {
+                    INVALID_FIELD
+                } else {
+                    self.doorbell_buffers = Some((shadow, event_idx));
+                    SUCCESS
+                }
+            }
+            // Asynchronous Event Request
+            0x0C => {
+                if self.aers.len() >= AERL {
+                    ASYNC_EVENT_REQUEST_LIMIT_EXCEEDED
+                } else if let Some(dw0) = self.events.pop_front() {
+                    return Some((dw0, SUCCESS));
+                } else {
+                    self.aers.push(cmd.c_id);
+                    return None;
+                }
+            }
+            // Format NVM
+            0x80 => {
+                if cmd.ns_id != 1 && cmd.ns_id != 0xFFFF_FFFF {
+                    INVALID_NAMESPACE
+                } else {
+                    let zeroes = vec![0; self.block_size as usize];
+                    let failed = (0..self.blocks).any(|lba| {
+                        self.storage
+                            .write_at(&zeroes, lba * self.block_size)
+                            .is_err()
+                    });
+                    if failed {
+                        DATA_TRANSFER_ERROR
+                    } else {
+                        SUCCESS
+                    }
+                }
+            }
+            _ => INVALID_OPCODE,
+        };
+        Some((0, status))
+    }
+
+    fn execute_io(&mut self, cmd: &NvmeCommand) -> Option<(u32, u16)> {
+        if cmd.ns_id != 1 {
+            return Some((0, INVALID_NAMESPACE));
+        }
+
+        let slba = (cmd.cdw11 as u64) << 32 | cmd.cdw10 as u64;
+        let nlb = (cmd.cdw12 & 0xFFFF) as u64 + 1;
+
+        let status = match cmd.opcode {
+            // Flush
+            0x00 => self.sync(),
+            // Write
+            0x01 => match self.check_transfer(slba, nlb) {
+                Ok(()) => {
+                    let mut data = vec![0; (nlb * self.block_size) as usize];
+                    match self.read_data(cmd, &mut data) {
+                        // Force Unit Access
+                        SUCCESS => match self.store(&data, slba) {
+                            SUCCESS if cmd.cdw12 & (1 << 30) != 0 => self.sync(),
+                            status => status,
+                        },
+                        status => status,
+                    }
+                }
+                Err(status) => status,
+            },
+            // Read
+            0x02 => match self.check_transfer(slba, nlb) {
+                Ok(()) => {
+                    let mut data = vec![0; (nlb * self.block_size) as usize];
+                    match self.storage.read_at(&mut data, slba * self.block_size) {
+                        Ok(()) => self.write_data(cmd, &data),
+                        Err(_) => DATA_TRANSFER_ERROR,
+                    }
+                }
+                Err(status) => status,
+            },
+            // Write Zeroes
+            0x08 => match self.check_range(slba, nlb) {
+                Ok(()) => self.store(&vec![0; (nlb * self.block_size) as usize], slba),
+                Err(status) => status,
+            },
+            // Dataset Management
+            0x09 => {
+                let nr = (cmd.cdw10 & 0xFF) as usize + 1;
+                if nr > DMRL as usize {
+                    return Some((0, INVALID_FIELD));
+                }
+                let mut ranges = vec![0; nr * 16];
+                match self.read_data(cmd, &mut ranges) {
+                    // reads of deallocated blocks return zeroes
+                    SUCCESS if cmd.cdw11 & (1 << 2) != 0 => ranges
+                        .chunks(16)
+                        .map(|range| {
+                            let nlb = u32::from_le_bytes(range[4..8].try_into().unwrap()) as u64;
+                            let slba = u64::from_le_bytes(range[8..16].try_into().unwrap());
+                            match self.check_range(slba, nlb) {
+                                Ok(()) => {
+                                    self.store(&vec![0; (nlb * self.block_size) as usize], slba)
+                                }
+                                Err(status) => status,
+                            }
+                        })
+                        .find(|&status| status != SUCCESS)
+                        .unwrap_or(SUCCESS),
+                    status => status,
+                }
+            }
+            _ => INVALID_OPCODE,
+        };
+
+        if status == SUCCESS {
+            let bytes = nlb * self.block_size;
+            match cmd.opcode {
+                0x01 => {
+                    self.health.write_commands += 1;
+                    self.health.bytes_written += bytes;
+                }
+                0x02 => {
+                    self.health.read_commands += 1;
+                    self.health.bytes_read += bytes;
+                }
+                _ => {}
+            }
+        }
+        Some((0, status))
+    }
+
+    fn check_transfer(&self, slba: u64, nlb: u64) -> Result<(), u16> {
+        if nlb * self.block_size > 4096 << MDTS {
+            return Err(INVALID_FIELD);
+        }
+        self.check_range(slba, nlb)
+    }
+
+    fn check_range(&self, slba: u64, nlb: u64) -> Result<(), u16> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.blocks => Ok(()),
+            _ => Err(LBA_OUT_OF_RANGE),
+        }
+    }
+
+    fn store(&mut self, data: &[u8], slba: u64) -> u16 {
+        match self.storage.write_at(data, slba * self.block_size) {
+            Ok(()) => SUCCESS,
+            Err(_) => DATA_TRANSFER_ERROR,
+        }
+    }
+
+    fn sync(&self) -> u16 {
+        match self.storage.sync() {
+            Ok(()) => SUCCESS,
+            Err(_) => DATA_TRANSFER_ERROR,
+        }
+    }
+
+    /// Resolves the PRP entries of `cmd` into (address, length) segments covering `len` bytes
+    fn prp_segments(&self, cmd: &NvmeCommand, len: usize) -> Vec<(usize, usize)> {
+        let page = self.page_size();
+        let prp1 = cmd.d_ptr[0] as usize;
+        let prp2 = cmd.d_ptr[1] as usize;
+
+        let first = len.min(page - prp1 % page);
+        let mut segments = vec![(prp1, first)];
+        let mut remaining = len - first;
+
+        if remaining == 0 {
+            return segments;
+        }
+        if remaining <= page {
+            segments.push((prp2, remaining));
+            return segments;
+        }
+
+        // prp2 points to a PRP list, the last entry of each list page chains to the next one
+        let mut entry = prp2;
+        while remaining > 0 {
+            let ptr = unsafe { std::ptr::read_volatile(entry as *const u64) } as usize;
+            if (entry + 8).is_multiple_of(page) && remaining > page {
+                entry = ptr;
+                continue;
+            }
+            let n = remaining.min(page);
+            segments.push((ptr, n));
+            remaining -= n;
+            entry += 8;
+        }
+        segments
+    }
+
+    /// Host memory described by the data pointer of `cmd` as PRPs or SGL, NVMe spec 4.3 & 4.4.
+    /// Bit bucket segments have no address.
+    fn data_segments(
+        &self,
+        cmd: &NvmeCommand,
+        len: usize,
+    ) -> Result<Vec<(Option<usize>, usize)>, u16> {
+        if cmd.flags >> 6 == 0 {
+            let segments = self.prp_segments(cmd, len);
+            if segments.iter().any(|&(addr, _)| addr == 0) {
+                return Err(DATA_TRANSFER_ERROR);
+            }
+            return Ok(segments
+                .into_iter()
+                .map(|(addr, n)| (Some(addr), n))
+                .collect());
+        }
+
+        let mut segments = Vec::new();
+        let mut remaining = len;
+        let mut descriptors = vec![cmd.d_ptr];
+        let mut i = 0;
+        while remaining > 0 {
+            let Some(&[addr, dw]) = descriptors.get(i) else {
+                return Err(DATA_SGL_LENGTH_INVALID);
+            };
+            let n = (dw & 0xFFFF_FFFF) as usize;
+            match dw >> 60 {
+                // Data Block
+                0x0 => segments.push((Some(addr as usize), n.min(remaining))),
+                // Bit Bucket
+                0x1 => segments.push((None, n.min(remaining))),
+                // Segment, Last Segment: only allowed as last descriptor of a list
+                0x2 | 0x3 if i + 1 == descriptors.len() => {
+                    if n == 0 || !n.is_multiple_of(16) {
+                        return Err(INVALID_SGL_SEGMENT_DESCRIPTOR);
+                    }
+                    descriptors = (0..n / 16)
+                        .map(|j| {
+                            let entry = (addr as usize + j * 16) as *const u64;
+                            unsafe {
+                                [
+                                    std::ptr::read_volatile(entry),
+                                    std::ptr::read_volatile(entry.add(1)),
+                                ]
+                            }
+                        })
+                        .collect();
+                    i = 0;
+                    continue;
+                }
+                0x2 | 0x3 => return Err(INVALID_SGL_SEGMENT_DESCRIPTOR),
+                _ => return Err(SGL_DESCRIPTOR_TYPE_INVALID),
+            }
+            remaining -= n.min(remaining);
+            i += 1;
+        }
+        Ok(segments)
+    }
+
+    /// Copies `data` to the host memory described by the data pointer of `cmd`
+    fn write_data(&self, cmd: &NvmeCommand, data: &[u8]) -> u16 {
+        let segments = match self.data_segments(cmd, data.len()) {
+            Ok(segments) => segments,
+            Err(status) => return status,
+        };
+        let mut offset = 0;
+        for (addr, len) in segments {
+            if let Some(addr) = addr {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(data[offset..].as_ptr(), addr as *mut u8, len);
+                }
+            }
+            offset += len;
+        }
+        SUCCESS
+    }
+
+    /// Fills `data` from the host memory described by the data pointer of `cmd`
+    fn read_data(&self, cmd: &NvmeCommand, data: &mut [u8]) -> u16 {
+        let segments = match self.data_segments(cmd, data.len()) {
+            Ok(segments) => segments,
+            Err(status) => return status,
+        };
+        let mut offset = 0;
+        for (addr, len) in segments {
+            // there is no data to write in a bit bucket
+            let Some(addr) = addr else {
+                return SGL_DESCRIPTOR_TYPE_INVALID;
+            };
+            unsafe {
+                std::ptr::copy_nonoverlapping(addr as *const u8, data[offset..].as_mut_ptr(), len);
+            }
+            offset += len;
+        }
+        SUCCESS
+    }
+
+    fn identify_controller(&self) -> Vec<u8> {
+        let mut data = vec![0; 4096];
+        let pad = |field: &mut [u8], value: &str| {
+            field.fill(b' ');
+            field[..value.len()].copy_from_slice(value.as_bytes());
+        };
+
+        pad(&mut data[4..24], "VROOM0001");
+        pad(&mut data[24..64], "vroom emulated controller");
+        pad(&mut data[64..72], env!("CARGO_PKG_VERSION"));
+        data[77] = MDTS;
+        // CNTLID
+        data[78..80].copy_from_slice(&1u16.to_le_bytes());
+        // VER
+        data[80..84].copy_from_slice(&0x0001_0400u32.to_le_bytes());
+        // OAES: namespace attribute and firmware activation notices
+        data[92..96].copy_from_slice(&(1u32 << 8 | 1 << 9).to_le_bytes());
+        // OACS: Doorbell Buffer Config
+        data[256..258].copy_from_slice(&(1u16 << 8).to_le_bytes());
+        // AERL
+        data[259] = (AERL - 1) as u8;
+        // FRMW: a single read only firmware slot
+        data[260] = 1 << 1 | 1;
+        // LPA: extended data for Get Log Page
+        data[261] = 1 << 2;
+        // ELPE
+        data[262] = (ERROR_LOG_ENTRIES - 1) as u8;
+        data[266..268].copy_from_slice(&WCTEMP.to_le_bytes());
+        data[268..270].copy_from_slice(&CCTEMP.to_le_bytes());
+        // HMPRE / HMMIN in 4 KiB units: 4 MiB / 2 MiB
+        data[272..276].copy_from_slice(&1024u32.to_le_bytes());
+        data[276..280].copy_from_slice(&512u32.to_le_bytes());
+        // TNVMCAP
+        let capacity = (self.blocks * self.block_size) as u128;
+        data[280..296].copy_from_slice(&capacity.to_le_bytes());
+        // SQES / CQES
+        data[512] = 0x66;
+        data[513] = 0x44;
+        // NN
+        data[516..520].copy_from_slice(&1u32.to_le_bytes());
+        // ONCS: Dataset Management | Write Zeroes
+        data[520..522].copy_from_slice(&(1u16 << 2 | 1 << 3).to_le_bytes());
+        // VWC: volatile write cache present
+        data[525] = 1;
+        // SGLS: SGLs without alignment requirements | bit bucket descriptors
+        data[536..540].copy_from_slice(&(1u32 | 1 << 16).to_le_bytes());
+        // PSD0: 25 W
+        data[2048..2050].copy_from_slice(&2500u16.to_le_bytes());
+        data
+    }
+
+    fn smart_log(&self) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        // data units are thousands of 512 byte units, rounded up
+        let units = |bytes: u64| bytes.div_ceil(512_000) as u128;
+
+        if self.temperature_exceeded() {
+            // Critical Warning: temperature
+            data[0] = 1 << 1;
+        }
+        data[1..3].copy_from_slice(&self.temperature().to_le_bytes());
+        // Available Spare / Available Spare Threshold
+        data[3] = 100;
+        data[4] = 10;
+        data[32..48].copy_from_slice(&units(self.health.bytes_read).to_le_bytes());
+        data[48..64].copy_from_slice(&units(self.health.bytes_written).to_le_bytes());
+        data[64..80].copy_from_slice(&(self.health.read_commands as u128).to_le_bytes());
+        data[80..96].copy_from_slice(&(self.health.write_commands as u128).to_le_bytes());
+        data[112..128].copy_from_slice(&(self.health.power_cycles as u128).to_le_bytes());
+        data[176..192].copy_from_slice(&(self.health.error_count as u128).to_le_bytes());
+        data
+    }
+
+    fn error_log(&self) -> Vec<u8> {
+        let mut data = vec![0; ERROR_LOG_ENTRIES * 64];
+        for (entry, error) in data.chunks_mut(64).zip(&self.health.errors) {
+            entry[0..8].copy_from_slice(&error.error_count.to_le_bytes());
+            entry[8..10].copy_from_slice(&error.sqid.to_le_bytes());
+            entry[10..12].copy_from_slice(&error.cid.to_le_bytes());
+            entry[12..14].copy_from_slice(&(error.status << 1).to_le_bytes());
+            // Parameter Error Location: not reported
+            entry[14..16].copy_from_slice(&0xFFFFu16.to_le_bytes());
+            entry[16..24].copy_from_slice(&error.lba.to_le_bytes());
+            entry[24..28].copy_from_slice(&error.ns_id.to_le_bytes());
+        }
+        data
+    }
+
+    fn firmware_slot_log(&self) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        // AFI: running from slot 1
+        data[0] = 1;
+        data[8..16].fill(b' ');
+        let revision = env!("CARGO_PKG_VERSION");
+        data[8..8 + revision.len()].copy_from_slice(revision.as_bytes());
+        data
+    }
+
+    fn identify_namespace(&self) -> Vec<u8> {
+        let mut data = vec![0; 4096];
+        // NSZE / NCAP / NUSE
+        data[0..8].copy_from_slice(&self.blocks.to_le_bytes());
+        data[8..16].copy_from_slice(&self.blocks.to_le_bytes());
+        data[16..24].copy_from_slice(&self.blocks.to_le_bytes());
+        // DLFEAT: deallocated blocks read as zeroes
+        data[33] = 1;
+        // LBAF0 with LBADS = log2(block size)
+        let lbads = self.block_size.trailing_zeros();
+        data[128..132].copy_from_slice(&(lbads << 16).to_le_bytes());
+        data
+    }
+}
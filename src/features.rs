@@ -0,0 +1,258 @@
+/// Which value Get Features returns (SEL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeatureSelect {
+    #[default]
+    Current = 0,
+    Default = 1,
+    Saved = 2,
+}
+
+/// Feature whose value is transferred in dword 11 of Set Features and dword 0 of the
+/// completion of Get Features
+pub trait Feature: Sized {
+    /// Feature Identifier
+    const ID: u8;
+
+    fn encode(&self) -> u32;
+
+    fn decode(dw0: u32) -> Self;
+}
+
+/// Capabilities of a feature, returned by Get Features with SEL 011b
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeatureCapabilities {
+    /// The value can be saved across power cycles
+    pub saveable: bool,
+    /// The value is specific to a namespace
+    pub namespace_specific: bool,
+    /// The value can be changed with Set Features
+    pub changeable: bool,
+}
+
+impl FeatureCapabilities {
+    pub(crate) fn decode(dw0: u32) -> Self {
+        Self {
+            saveable: dw0 & 1 != 0,
+            namespace_specific: dw0 & (1 << 1) != 0,
+            changeable: dw0 & (1 << 2) != 0,
+        }
+    }
+}
+
+/// Command arbitration (0x01), the weights only apply with weighted round robin, see
+/// [`crate::NvmeDevice::weighted_round_robin`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Arbitration {
+    /// Arbitration Burst, commands fetched at once from a queue as power of two, 7 for no limit
+    pub burst: u8,
+    /// Low Priority Weight (0-based)
+    pub low_weight: u8,
+    /// Medium Priority Weight (0-based)
+    pub medium_weight: u8,
+    /// High Priority Weight (0-based)
+    pub high_weight: u8,
+}
+
+impl Feature for Arbitration {
+    const ID: u8 = 0x01;
+
+    fn encode(&self) -> u32 {
+        (self.high_weight as u32) << 24
+            | (self.medium_weight as u32) << 16
+            | (self.low_weight as u32) << 8
+            | (self.burst & 0x7) as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            burst: (dw0 & 0x7) as u8,
+            low_weight: (dw0 >> 8) as u8,
+            medium_weight: (dw0 >> 16) as u8,
+            high_weight: (dw0 >> 24) as u8,
+        }
+    }
+}
+
+/// Power Management (0x02)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PowerManagement {
+    /// Power State, index into [`crate::ControllerInfo::power_states`]
+    pub power_state: u8,
+    /// Workload Hint
+    pub workload_hint: u8,
+}
+
+impl Feature for PowerManagement {
+    const ID: u8 = 0x02;
+
+    fn encode(&self) -> u32 {
+        ((self.workload_hint & 0x7) as u32) << 5 | (self.power_state & 0x1F) as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            power_state: (dw0 & 0x1F) as u8,
+            workload_hint: ((dw0 >> 5) & 0x7) as u8,
+        }
+    }
+}
+
+/// Temperature Threshold (0x04) of a single sensor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TemperatureThreshold {
+    /// Threshold in Kelvin
+    pub threshold: u16,
+    /// Threshold Temperature Select, 0 for the composite temperature, 1-8 for the sensors
+    pub sensor: u8,
+    /// Under instead of over temperature threshold
+    pub under: bool,
+}
+
+impl Feature for TemperatureThreshold {
+    const ID: u8 = 0x04;
+
+    fn encode(&self) -> u32 {
+        (self.under as u32) << 20 | ((self.sensor & 0xF) as u32) << 16 | self.threshold as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            threshold: dw0 as u16,
+            sensor: ((dw0 >> 16) & 0xF) as u8,
+            under: (dw0 >> 20) & 0x3 == 1,
+        }
+    }
+}
+
+/// Volatile Write Cache (0x06)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolatileWriteCache {
+    pub enabled: bool,
+}
+
+impl Feature for VolatileWriteCache {
+    const ID: u8 = 0x06;
+
+    fn encode(&self) -> u32 {
+        self.enabled as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            enabled: dw0 & 1 == 1,
+        }
+    }
+}
+
+/// Number of Queues (0x07), requested with Set Features and allocated by the controller
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NumberOfQueues {
+    /// Number of I/O submission queues, excluding the admin queue
+    pub submission: u32,
+    /// Number of I/O completion queues, excluding the admin queue
+    pub completion: u32,
+}
+
+impl Feature for NumberOfQueues {
+    const ID: u8 = 0x07;
+
+    fn encode(&self) -> u32 {
+        // both are 0-based
+        (self.completion.clamp(1, 0xFFFF) - 1) << 16 | (self.submission.clamp(1, 0xFFFF) - 1)
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            submission: (dw0 & 0xFFFF) + 1,
+            completion: (dw0 >> 16) + 1,
+        }
+    }
+}
+
+/// Interrupt Coalescing (0x08)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterruptCoalescing {
+    /// Aggregation Threshold, completions per interrupt (0-based)
+    pub threshold: u8,
+    /// Aggregation Time in 100 microsecond units
+    pub time: u8,
+}
+
+impl Feature for InterruptCoalescing {
+    const ID: u8 = 0x08;
+
+    fn encode(&self) -> u32 {
+        (self.time as u32) << 8 | self.threshold as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            threshold: dw0 as u8,
+            time: (dw0 >> 8) as u8,
+        }
+    }
+}
+
+/// Asynchronous Event Configuration (0x0B)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AsyncEventConfig {
+    /// SMART / health critical warnings reported as events, bits as in
+    /// [`crate::SmartLog::critical_warning`]
+    pub critical_warnings: u8,
+    /// Notices reported as events, bits 31:8 as in OAES, see [`crate::ControllerInfo::oaes`]
+    pub notices: u32,
+}
+
+impl Feature for AsyncEventConfig {
+    const ID: u8 = 0x0B;
+
+    fn encode(&self) -> u32 {
+        (self.notices & !0xFF) | self.critical_warnings as u32
+    }
+
+    fn decode(dw0: u32) -> Self {
+        Self {
+            critical_warnings: dw0 as u8,
+            notices: dw0 & !0xFF,
+        }
+    }
+}
+
+/// Host Memory Buffer (0x0D) attributes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostMemoryBuffer {
+    pub enabled: bool,
+    /// Host Memory Buffer Size in memory pages
+    pub size: u32,
+    /// Host Memory Descriptor List Address
+    pub list_addr: u64,
+    /// Host Memory Descriptor List Entry Count
+    pub list_entries: u32,
+}
+
+pub(crate) const HOST_MEMORY_BUFFER: u8 = 0x0D;
+
+/// Timestamp (0x0E)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    /// Milliseconds since midnight, 01-Jan-1970, UTC
+    pub millis: u64,
+    /// The controller may have stopped counting, e.g. in non-operational power states
+    pub stopped: bool,
+    /// Timestamp Origin, 0 if it was reset, 1 if it was set by the host
+    pub origin: u8,
+}
+
+pub(crate) const TIMESTAMP: u8 = 0x0E;
+
+impl Timestamp {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        let mut millis = [0; 8];
+        millis[..6].copy_from_slice(&data[..6]);
+        Self {
+            millis: u64::from_le_bytes(millis),
+            stopped: data[6] & 1 != 0,
+            origin: (data[6] >> 1) & 0x7,
+        }
+    }
+}
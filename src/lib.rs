@@ -1,22 +1,44 @@
 #![cfg_attr(target_arch = "aarch64", feature(stdarch_arm_hints))]
+#[allow(dead_code)]
+mod async_event;
+pub mod cmb;
 #[allow(unused)]
 mod cmd;
 #[allow(dead_code)]
 pub mod driver;
+#[cfg(feature = "emulator")]
+pub mod emulator;
+pub mod features;
+mod log_page;
 #[allow(dead_code)]
 pub mod memory;
 #[allow(dead_code)]
 mod nvme;
 #[allow(dead_code)]
 mod pci;
+pub mod pmr;
+#[allow(dead_code)]
+mod prp;
 #[allow(dead_code)]
 mod queues;
 #[allow(dead_code)]
+mod registers;
+#[allow(dead_code)]
 pub mod request;
+#[allow(dead_code)]
+mod sgl;
+#[allow(dead_code)]
+mod vfio;
 
+pub use async_event::{AsyncEvent, AsyncEventLog, AsyncEventType};
+#[cfg(feature = "emulator")]
+pub use emulator::EmulatedController;
+pub use log_page::{ErrorLogEntry, FirmwareSlotLog, SmartLog};
 pub use memory::HUGE_PAGE_SIZE;
-pub use nvme::{NvmeDevice, NvmeQueuePair};
-pub use queues::QUEUE_LENGTH;
+pub use nvme::{NvmeDevice, NvmeError, NvmeQueuePair, TimeoutError};
+pub use queues::{QueuePriority, MAX_QUEUE_LENGTH, QUEUE_LENGTH};
+pub use registers::{Bar, Doorbell, RegisterAccess};
+pub use sgl::SglDescriptor;
 use std::error::Error;
 
 pub fn init(_pci_addr: &str) -> Result<(), Box<dyn Error>> {
@@ -30,6 +52,171 @@ pub struct NvmeNamespace {
     pub block_size: u64,
 }
 
+impl NvmeNamespace {
+    /// Number of logical blocks covered by a transfer of `bytes` bytes starting at `lba`.
+    /// Fails if `bytes` is not a multiple of the block size or the blocks exceed the namespace.
+    pub fn transfer_blocks(&self, lba: u64, bytes: usize) -> std::io::Result<u64> {
+        if self.block_size == 0 || bytes == 0 || !(bytes as u64).is_multiple_of(self.block_size) {
+            return Err(nvme::invalid_input(format!(
+                "transfer of {bytes} bytes is not a multiple of the {} byte blocks of namespace {}",
+                self.block_size, self.id
+            )));
+        }
+        let blocks = bytes as u64 / self.block_size;
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.blocks => Ok(blocks),
+            _ => Err(nvme::invalid_input(format!(
+                "blocks {lba}..{} exceed the {} blocks of namespace {}",
+                lba.saturating_add(blocks),
+                self.blocks,
+                self.id
+            ))),
+        }
+    }
+}
+
+/// Identify Controller data structure, NVMe spec 5.15.2.2
+#[derive(Debug, Clone, Default)]
+pub struct ControllerInfo {
+    /// PCI Vendor ID
+    pub vid: u16,
+    /// PCI Subsystem Vendor ID
+    pub ssvid: u16,
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    /// Maximum Data Transfer Size as power of two multiple of the minimum page size, 0 if unlimited
+    pub mdts: u8,
+    /// Controller ID
+    pub cntlid: u16,
+    /// Version as reported in the VS register format (major << 16 | minor << 8 | tertiary)
+    pub version: u32,
+    /// RTD3 Entry Latency in microseconds, expected time to complete a shutdown, 0 if not reported
+    pub rtd3e: u32,
+    /// Optional Asynchronous Events Supported
+    pub oaes: u32,
+    /// Optional Admin Command Support
+    pub oacs: u16,
+    /// Abort Command Limit (0-based)
+    pub acl: u8,
+    /// Asynchronous Event Request Limit (0-based)
+    pub aerl: u8,
+    /// Firmware Updates
+    pub frmw: u8,
+    /// Log Page Attributes
+    pub lpa: u8,
+    /// Error Log Page Entries (0-based)
+    pub elpe: u8,
+    /// Warning Composite Temperature Threshold in Kelvin
+    pub wctemp: u16,
+    /// Critical Composite Temperature Threshold in Kelvin
+    pub cctemp: u16,
+    /// Host Memory Buffer Preferred Size in 4 KiB units
+    pub hmpre: u32,
+    /// Host Memory Buffer Minimum Size in 4 KiB units
+    pub hmmin: u32,
+    /// Total NVM Capacity in bytes
+    pub tnvmcap: u128,
+    /// Submission Queue Entry Size, maximum (upper nibble) and required (lower nibble) as power of two
+    pub sqes: u8,
+    /// Completion Queue Entry Size, maximum (upper nibble) and required (lower nibble) as power of two
+    pub cqes: u8,
+    /// Maximum Outstanding Commands
+    pub maxcmd: u16,
+    /// Number of Namespaces
+    pub nn: u32,
+    /// Optional NVM Command Support
+    pub oncs: u16,
+    /// Fused Operation Support
+    pub fuses: u16,
+    /// Format NVM Attributes
+    pub fna: u8,
+    /// Volatile Write Cache
+    pub vwc: u8,
+    /// Atomic Write Unit Normal (0-based, in logical blocks)
+    pub awun: u16,
+    /// Atomic Write Unit Power Fail (0-based, in logical blocks)
+    pub awupf: u16,
+    /// SGL Support
+    pub sgls: u32,
+    /// Dataset Management Ranges Limit from the NVM command set specific data, 0 if not reported
+    pub dmrl: u8,
+    pub power_states: Vec<PowerState>,
+}
+
+impl ControllerInfo {
+    pub fn supports_dataset_management(&self) -> bool {
+        self.oncs & (1 << 2) != 0
+    }
+
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.oncs & (1 << 3) != 0
+    }
+
+    pub fn has_volatile_write_cache(&self) -> bool {
+        self.vwc & 1 == 1
+    }
+
+    /// SGLs can be used for NVM command set I/O commands
+    pub fn supports_sgl(&self) -> bool {
+        self.sgls & 0x3 != 0
+    }
+
+    /// SGL data block addresses and lengths have to be dword aligned
+    pub fn sgl_requires_dword_alignment(&self) -> bool {
+        self.sgls & 0x3 == 0x2
+    }
+
+    pub fn supports_sgl_bit_bucket(&self) -> bool {
+        self.sgls & (1 << 16) != 0
+    }
+
+    /// Shadow doorbells can be set up with Doorbell Buffer Config
+    pub fn supports_doorbell_buffer_config(&self) -> bool {
+        self.oacs & (1 << 8) != 0
+    }
+
+    /// Maximum transfer size in bytes of a single command, `None` if unlimited
+    pub fn max_transfer_size(&self, min_page_size: usize) -> Option<usize> {
+        match self.mdts {
+            0 => None,
+            mdts => Some(min_page_size << mdts),
+        }
+    }
+}
+
+/// Power State Descriptor, NVMe spec 5.15.2.2
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowerState {
+    /// Maximum Power in units of 0.01 W, or 0.0001 W if `max_power_scale` is set
+    pub max_power: u16,
+    pub max_power_scale: bool,
+    /// Non-Operational State, no I/O commands are processed in this state
+    pub non_operational: bool,
+    /// Entry Latency in microseconds
+    pub entry_latency: u32,
+    /// Exit Latency in microseconds
+    pub exit_latency: u32,
+    pub relative_read_throughput: u8,
+    pub relative_read_latency: u8,
+    pub relative_write_throughput: u8,
+    pub relative_write_latency: u8,
+}
+
+impl PowerState {
+    pub fn max_power_watts(&self) -> f64 {
+        let scale = if self.max_power_scale { 0.0001 } else { 0.01 };
+        self.max_power as f64 * scale
+    }
+}
+
+/// Contiguous range of logical blocks
+#[derive(Debug, Clone, Copy)]
+pub struct LbaRange {
+    pub slba: u64,
+    pub blocks: u32,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct NvmeStats {
     pub completions: u64,
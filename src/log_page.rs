@@ -0,0 +1,274 @@
+use crate::nvme::identify_string;
+use crate::queues::NvmeCompletion;
+use crate::NvmeError;
+
+// log page identifiers, NVMe spec 5.16.1
+pub const LOG_ERROR: u8 = 0x01;
+pub const LOG_SMART: u8 = 0x02;
+pub const LOG_FIRMWARE_SLOT: u8 = 0x03;
+pub const LOG_CHANGED_NAMESPACES: u8 = 0x04;
+
+/// Error Information log entry, NVMe spec 5.16.1.2
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+pub(crate) struct ErrorLogData {
+    error_count: u64,
+    sqid: u16,
+    cid: u16,
+    status: u16,
+    parameter_error_location: u16,
+    lba: u64,
+    nsid: u32,
+    vendor_specific: u8,
+    transport_type: u8,
+    _rsvd1: u16,
+    command_specific: u64,
+    transport_specific: u16,
+    _rsvd2: [u8; 22],
+}
+
+const _: () = assert!(std::mem::size_of::<ErrorLogData>() == 64);
+
+/// Error Information log entry, NVMe spec 5.16.1.2
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorLogEntry {
+    /// Unique and incrementing identifier of the error, 0 marks an unused entry
+    pub error_count: u64,
+    /// Submission Queue ID of the failed command, 0xFFFF if not specific to a command
+    pub sqid: u16,
+    /// Command ID of the failed command, 0xFFFF if not specific to a command
+    pub cid: u16,
+    /// Status field of the completion queue entry without the phase tag
+    pub status: u16,
+    /// Byte (bits 7:0) and bit (bits 10:8) of the command parameter that caused the error
+    pub parameter_error_location: u16,
+    /// First LBA that experienced the error
+    pub lba: u64,
+    pub ns_id: u32,
+    /// Log page with vendor specific information, 0 if there is none
+    pub vendor_specific: u8,
+    pub transport_type: u8,
+    /// Command Specific Information
+    pub command_specific: u64,
+    pub transport_specific: u16,
+}
+
+impl ErrorLogEntry {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        assert!(data.len() >= std::mem::size_of::<ErrorLogData>());
+        let data = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const ErrorLogData) };
+
+        Self {
+            error_count: data.error_count,
+            sqid: data.sqid,
+            cid: data.cid,
+            status: data.status >> 1,
+            parameter_error_location: data.parameter_error_location,
+            lba: data.lba,
+            ns_id: data.nsid,
+            vendor_specific: data.vendor_specific,
+            transport_type: data.transport_type,
+            command_specific: data.command_specific,
+            transport_specific: data.transport_specific,
+        }
+    }
+
+    /// Whether the entry describes command `cid` of submission queue `sqid`
+    pub fn matches(&self, sqid: u16, cid: u16) -> bool {
+        self.sqid == sqid && self.cid == cid
+    }
+
+    /// The error the command completed with
+    pub fn error(&self) -> Option<NvmeError> {
+        let entry = NvmeCompletion {
+            status: self.status << 1,
+            ..Default::default()
+        };
+        NvmeError::check(&entry).err()
+    }
+}
+
+/// Firmware Slot Information, NVMe spec 5.16.1.4
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareSlotLog {
+    /// Slot of the running firmware (1-7)
+    pub active_slot: u8,
+    /// Slot activated at the next controller reset, `None` if it stays the active slot
+    pub next_slot: Option<u8>,
+    /// Firmware revisions of slots 1-7, `None` for empty slots
+    pub revisions: [Option<String>; 7],
+}
+
+impl FirmwareSlotLog {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        assert!(data.len() >= 64);
+        let revision = |slot: usize| {
+            let revision = identify_string(&data[8 * slot..8 * slot + 8]);
+            (!revision.is_empty()).then_some(revision)
+        };
+
+        Self {
+            active_slot: data[0] & 0x7,
+            next_slot: match (data[0] >> 4) & 0x7 {
+                0 => None,
+                slot => Some(slot),
+            },
+            revisions: std::array::from_fn(|i| revision(i + 1)),
+        }
+    }
+
+    /// Firmware revision the controller is running
+    pub fn active_revision(&self) -> Option<&str> {
+        self.revision(self.active_slot)
+    }
+
+    /// Firmware revision in slot `slot` (1-7)
+    pub fn revision(&self, slot: u8) -> Option<&str> {
+        let idx = (slot as usize).checked_sub(1)?;
+        self.revisions.get(idx)?.as_deref()
+    }
+}
+
+/// SMART / Health Information log page, NVMe spec 5.16.1.3
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+pub(crate) struct SmartLogData {
+    critical_warning: u8,
+    composite_temperature: u16,
+    available_spare: u8,
+    available_spare_threshold: u8,
+    percentage_used: u8,
+    endurance_group_critical_warning: u8,
+    _rsvd1: [u8; 25],
+    data_units_read: u128,
+    data_units_written: u128,
+    host_read_commands: u128,
+    host_write_commands: u128,
+    controller_busy_time: u128,
+    power_cycles: u128,
+    power_on_hours: u128,
+    unsafe_shutdowns: u128,
+    media_errors: u128,
+    error_log_entries: u128,
+    warning_temperature_time: u32,
+    critical_temperature_time: u32,
+    temperature_sensors: [u16; 8],
+    _rsvd2: [u8; 296],
+}
+
+const _: () = assert!(std::mem::size_of::<SmartLogData>() == 512);
+
+/// SMART / Health Information, NVMe spec 5.16.1.3
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmartLog {
+    /// Critical Warning bits, see the `SmartLog::*_warning` functions
+    pub critical_warning: u8,
+    /// Composite Temperature in Kelvin
+    pub temperature: u16,
+    /// Available Spare capacity in percent
+    pub available_spare: u8,
+    /// Available Spare Threshold in percent
+    pub available_spare_threshold: u8,
+    /// Percentage of the life span used, may exceed 100
+    pub percentage_used: u8,
+    /// Data Units Read in thousands of 512 byte units
+    pub data_units_read: u128,
+    /// Data Units Written in thousands of 512 byte units
+    pub data_units_written: u128,
+    pub host_read_commands: u128,
+    pub host_write_commands: u128,
+    /// Controller Busy Time in minutes
+    pub controller_busy_time: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    /// Media and Data Integrity Errors
+    pub media_errors: u128,
+    /// Number of Error Information Log Entries over the life of the controller
+    pub error_log_entries: u128,
+    /// Minutes above the warning composite temperature threshold
+    pub warning_temperature_time: u32,
+    /// Minutes above the critical composite temperature threshold
+    pub critical_temperature_time: u32,
+    /// Temperature Sensors 1-8 in Kelvin, 0 if not implemented
+    pub temperature_sensors: [u16; 8],
+}
+
+impl SmartLog {
+    pub(crate) fn parse(data: &[u8]) -> Self {
+        assert!(data.len() >= std::mem::size_of::<SmartLogData>());
+        let data = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const SmartLogData) };
+
+        Self {
+            critical_warning: data.critical_warning,
+            temperature: data.composite_temperature,
+            available_spare: data.available_spare,
+            available_spare_threshold: data.available_spare_threshold,
+            percentage_used: data.percentage_used,
+            data_units_read: data.data_units_read,
+            data_units_written: data.data_units_written,
+            host_read_commands: data.host_read_commands,
+            host_write_commands: data.host_write_commands,
+            controller_busy_time: data.controller_busy_time,
+            power_cycles: data.power_cycles,
+            power_on_hours: data.power_on_hours,
+            unsafe_shutdowns: data.unsafe_shutdowns,
+            media_errors: data.media_errors,
+            error_log_entries: data.error_log_entries,
+            warning_temperature_time: data.warning_temperature_time,
+            critical_temperature_time: data.critical_temperature_time,
+            temperature_sensors: data.temperature_sensors,
+        }
+    }
+
+    /// Composite Temperature in degrees Celsius
+    pub fn temperature_celsius(&self) -> i32 {
+        self.temperature as i32 - 273
+    }
+
+    /// Bytes read by the host, rounded up to 512,000 bytes
+    pub fn bytes_read(&self) -> u128 {
+        self.data_units_read * 512_000
+    }
+
+    /// Bytes written by the host, rounded up to 512,000 bytes
+    pub fn bytes_written(&self) -> u128 {
+        self.data_units_written * 512_000
+    }
+
+    pub fn has_critical_warning(&self) -> bool {
+        self.critical_warning != 0
+    }
+
+    /// Available spare capacity fell below the threshold
+    pub fn spare_warning(&self) -> bool {
+        self.critical_warning & 1 != 0
+    }
+
+    /// Temperature is above an over or below an under temperature threshold
+    pub fn temperature_warning(&self) -> bool {
+        self.critical_warning & (1 << 1) != 0
+    }
+
+    /// Reliability is degraded due to media or internal errors
+    pub fn reliability_warning(&self) -> bool {
+        self.critical_warning & (1 << 2) != 0
+    }
+
+    /// Media has been placed in read only mode
+    pub fn read_only_warning(&self) -> bool {
+        self.critical_warning & (1 << 3) != 0
+    }
+
+    /// Volatile memory backup device has failed
+    pub fn volatile_memory_backup_warning(&self) -> bool {
+        self.critical_warning & (1 << 4) != 0
+    }
+
+    /// Persistent Memory Region has become read only or unreliable
+    pub fn pmr_read_only_warning(&self) -> bool {
+        self.critical_warning & (1 << 5) != 0
+    }
+}
@@ -2,15 +2,17 @@ use lazy_static::lazy_static;
 use std::slice;
 // use std::rc::Rc;
 // use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 use std::io::{self, Read, Seek};
 use std::ops::{Deref, DerefMut, Index, IndexMut, Range, RangeFull, RangeTo};
 use std::os::fd::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{fs, mem, process, ptr};
 
+use crate::vfio;
+
 // from https://www.kernel.org/doc/Documentation/x86/x86_64/mm.txt
 const X86_VA_WIDTH: u8 = 47;
 
@@ -21,6 +23,14 @@ pub const IOVA_WIDTH: u8 = X86_VA_WIDTH;
 
 static HUGEPAGE_ID: AtomicUsize = AtomicUsize::new(0);
 
+// how device addresses of DMA memory are obtained, fixed by the first device of the process
+static ADDRESS_MODE: AtomicU8 = AtomicU8::new(ADDRESS_MODE_UNSET);
+const ADDRESS_MODE_UNSET: u8 = 0;
+// physical or IOMMU addresses, set once a real device is opened
+const ADDRESS_MODE_PHYSICAL: u8 = 1;
+// virtual addresses, set once an emulated controller is created
+const ADDRESS_MODE_IDENTITY: u8 = 2;
+
 pub(crate) static mut VFIO_CONTAINER_FILE_DESCRIPTOR: Option<RawFd> = None;
 
 lazy_static! {
@@ -28,12 +38,42 @@ lazy_static! {
         Mutex::new(HashMap::new());
 }
 
+#[derive(Debug)]
+enum Backing {
+    /// View into memory owned by another `Dma`
+    Borrowed,
+    /// File on hugetlbfs
+    HugePage(String),
+    /// Anonymous memory, only used with identity mapping
+    Anonymous,
+    /// Anonymous huge page mapped into the IOMMU at `phys`
+    Iommu,
+    /// Block of a huge page owned by a `DmaPool`, returned to it on drop
+    Pooled {
+        pool: Arc<Mutex<PoolState>>,
+        page: usize,
+        offset: usize,
+        order: usize,
+    },
+}
+
+/// Device addresses of the huge pages of a hugetlbfs allocation, which are not contiguous in
+/// physical memory. Shared with slices of the allocation.
+#[derive(Debug)]
+struct HugePages {
+    virt: usize,
+    phys: Vec<usize>,
+}
+
 #[derive(Debug)]
 pub struct Dma<T> {
     pub virt: *mut T,
+    /// Device address of the first byte, see [`Dma::phys_at`] for the others
     pub phys: usize,
     pub size: usize,
-    path: Option<String>,
+    backing: Backing,
+    // set if the memory spans several huge pages on hugetlbfs
+    huge_pages: Option<Arc<HugePages>>,
 }
 
 unsafe impl<T> Send for Dma<T> {}
@@ -41,15 +81,36 @@ unsafe impl<T> Sync for Dma<T> {}
 
 impl<T> Drop for Dma<T> {
     fn drop(&mut self) {
-        if self.path.is_some() {
-            unsafe {
-                let result = libc::munmap(self.virt as *mut libc::c_void, self.size);
-                if result == -1 {
-                    eprintln!("Error: munmap failed");
-                }
+        if let Backing::Borrowed = self.backing {
+            return;
+        }
+
+        if let Backing::Pooled {
+            pool,
+            page,
+            offset,
+            order,
+        } = &self.backing
+        {
+            pool.lock().unwrap().release(*page, *offset, *order);
+            return;
+        }
+
+        if let Backing::Iommu = self.backing {
+            if let Err(e) = vfio::unmap_dma(self.phys, self.size) {
+                eprintln!("Error: {}", e);
+            }
+        }
+
+        unsafe {
+            let result = libc::munmap(self.virt as *mut libc::c_void, self.size);
+            if result == -1 {
+                eprintln!("Error: munmap failed");
             }
+        }
 
-            match fs::remove_file(self.path.as_mut().unwrap()) {
+        if let Backing::HugePage(path) = &self.backing {
+            match fs::remove_file(path) {
                 Ok(_) => (),
                 Err(e) => eprintln!("Error: {}", e),
             }
@@ -76,7 +137,7 @@ impl<T> DerefMut for Dma<T> {
 pub trait DmaSlice {
     type Item;
 
-    fn chunks(&self, bytes: usize) -> DmaChunks<u8>;
+    fn chunks(&self, bytes: usize) -> DmaChunks<'_, u8>;
     fn slice(&self, range: Range<usize>) -> Self::Item;
 }
 
@@ -94,7 +155,7 @@ impl<'a, T> Iterator for DmaChunks<'a, T> {
         if self.current_offset >= self.dma.size {
             None
         } else {
-            let chunk_phys_addr = self.dma.phys + self.current_offset * std::mem::size_of::<T>();
+            let offset = self.current_offset * std::mem::size_of::<T>();
             let offset_ptr = unsafe { self.dma.virt.add(self.current_offset) };
             let len = std::cmp::min(
                 self.chunk_size,
@@ -104,8 +165,10 @@ impl<'a, T> Iterator for DmaChunks<'a, T> {
             self.current_offset += len;
 
             Some(DmaChunk {
-                phys_addr: chunk_phys_addr,
+                phys_addr: self.dma.device_addr(offset),
                 slice: unsafe { std::slice::from_raw_parts_mut(offset_ptr, len) },
+                dma: self.dma,
+                offset,
             })
         }
     }
@@ -115,11 +178,44 @@ impl<'a, T> Iterator for DmaChunks<'a, T> {
 pub struct DmaChunk<'a, T> {
     pub phys_addr: usize,
     pub slice: &'a mut [T],
+    dma: &'a Dma<T>,
+    // in bytes from the start of `dma`
+    offset: usize,
+}
+
+impl<T> DmaChunk<'_, T> {
+    /// Device address of the byte at `offset` into the chunk. Only memory within a huge page is
+    /// guaranteed to be contiguous, larger chunks have to be translated page by page.
+    pub fn phys_at(&self, offset: usize) -> usize {
+        assert!(
+            offset < std::mem::size_of_val(self.slice),
+            "offset out of bounds"
+        );
+        self.dma.device_addr(self.offset + offset)
+    }
+
+    /// The chunk split into parts that are contiguous in device memory, as device address and
+    /// length in bytes
+    pub fn contiguous_parts(&self) -> Vec<(usize, usize)> {
+        let len = std::mem::size_of_val(self.slice);
+        let mut parts: Vec<(usize, usize)> = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let addr = self.phys_at(offset);
+            let bytes = (HUGE_PAGE_SIZE - addr % HUGE_PAGE_SIZE).min(len - offset);
+            match parts.last_mut() {
+                Some((start, part)) if *start + *part == addr => *part += bytes,
+                _ => parts.push((addr, bytes)),
+            }
+            offset += bytes;
+        }
+        parts
+    }
 }
 
 impl DmaSlice for Dma<u8> {
     type Item = Dma<u8>;
-    fn chunks(&self, bytes: usize) -> DmaChunks<u8> {
+    fn chunks(&self, bytes: usize) -> DmaChunks<'_, u8> {
         DmaChunks {
             current_offset: 0,
             chunk_size: bytes,
@@ -133,9 +229,10 @@ impl DmaSlice for Dma<u8> {
         unsafe {
             Dma {
                 virt: self.virt.add(index.start),
-                phys: self.phys + index.start,
+                phys: self.device_addr(index.start),
                 size: (index.end - index.start),
-                path: None,
+                backing: Backing::Borrowed,
+                huge_pages: self.huge_pages.clone(),
             }
         }
     }
@@ -188,15 +285,24 @@ impl IndexMut<RangeFull> for Dma<u8> {
 }
 
 impl<T> Dma<T> {
-    /// Allocates DMA Memory on a huge page
-    // TODO: vfio support?
+    /// Allocates DMA Memory on a huge page.
+    /// Once a device is accessed through VFIO, the memory is mapped into the IOMMU and `phys`
+    /// holds the IO virtual address instead of the physical one.
     pub fn allocate(size: usize) -> Result<Dma<T>, Box<dyn Error>> {
-        let size = if size % HUGE_PAGE_SIZE != 0 {
+        let size = if !size.is_multiple_of(HUGE_PAGE_SIZE) {
             ((size >> HUGE_PAGE_BITS) + 1) << HUGE_PAGE_BITS
         } else {
             size
         };
 
+        if ADDRESS_MODE.load(Ordering::Relaxed) == ADDRESS_MODE_IDENTITY {
+            return Self::allocate_anonymous(size);
+        }
+
+        if vfio_enabled() {
+            return Self::allocate_iommu(size);
+        }
+
         let id = HUGEPAGE_ID.fetch_add(1, Ordering::SeqCst);
         let path = format!("/mnt/huge/nvme-{}-{}", process::id(), id);
 
@@ -204,6 +310,7 @@ impl<T> Dma<T> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path.clone())
         {
             Ok(f) => {
@@ -221,12 +328,23 @@ impl<T> Dma<T> {
                 if ptr == libc::MAP_FAILED {
                     Err("failed to mmap huge page - are huge pages enabled and free?".into())
                 } else if unsafe { libc::mlock(ptr, size) } == 0 {
+                    // huge pages are only contiguous in themselves
+                    let phys = (0..size)
+                        .step_by(HUGE_PAGE_SIZE)
+                        .map(|offset| virt_to_phys(ptr as usize + offset))
+                        .collect::<Result<Vec<_>, _>>()?;
                     let memory = Dma {
                         // virt: NonNull::new(ptr as *mut T).expect("oops"),
                         virt: ptr as *mut T,
-                        phys: virt_to_phys(ptr as usize)?,
+                        phys: phys[0],
                         size,
-                        path: Some(path),
+                        backing: Backing::HugePage(path),
+                        huge_pages: (phys.len() > 1).then(|| {
+                            Arc::new(HugePages {
+                                virt: ptr as usize,
+                                phys,
+                            })
+                        }),
                     };
                     Ok(memory)
                 } else {
@@ -243,6 +361,224 @@ impl<T> Dma<T> {
             Err(e) => Err(Box::new(e)),
         }
     }
+
+    /// Device address of the byte at `offset`. Memory spanning multiple huge pages is only
+    /// contiguous in device memory with VFIO or identity mapping, huge pages on hugetlbfs are
+    /// translated one by one.
+    pub fn phys_at(&self, offset: usize) -> usize {
+        assert!(offset < self.size, "offset out of bounds");
+        self.device_addr(offset)
+    }
+
+    // `phys_at` without the bounds check, offsets up to the end of the memory are valid
+    fn device_addr(&self, offset: usize) -> usize {
+        match &self.huge_pages {
+            Some(pages) => {
+                let addr = self.virt as usize + offset - pages.virt;
+                // the end of the memory belongs to its last page
+                let page = (addr >> HUGE_PAGE_BITS).min(pages.phys.len() - 1);
+                pages.phys[page] + addr - (page << HUGE_PAGE_BITS)
+            }
+            None => self.phys + offset,
+        }
+    }
+
+    /// View of `size` bytes at `virt` owned by someone else, e.g. a mapped BAR, whose device
+    /// address is `phys`. The memory has to outlive the view.
+    pub(crate) fn borrowed(virt: *mut T, phys: usize, size: usize) -> Dma<T> {
+        Dma {
+            virt,
+            phys,
+            size,
+            backing: Backing::Borrowed,
+            huge_pages: None,
+        }
+    }
+
+    /// Whether `phys` describes all of the memory, i.e. it is contiguous in device memory
+    pub fn is_contiguous(&self) -> bool {
+        (0..self.size)
+            .step_by(HUGE_PAGE_SIZE)
+            .all(|offset| self.phys_at(offset) == self.phys + offset)
+    }
+
+    /// Allocates anonymous memory whose "physical" address is its virtual address
+    fn allocate_anonymous(size: usize) -> Result<Dma<T>, Box<dyn Error>> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err("failed to mmap anonymous memory".into())
+        } else {
+            Ok(Dma {
+                virt: ptr as *mut T,
+                phys: ptr as usize,
+                size,
+                backing: Backing::Anonymous,
+                huge_pages: None,
+            })
+        }
+    }
+
+    /// Allocates anonymous huge pages and maps them into the IOMMU, needs no root privileges
+    fn allocate_iommu(size: usize) -> Result<Dma<T>, Box<dyn Error>> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err("failed to mmap huge page - are huge pages enabled and free?".into());
+        }
+        if (ptr as usize) >> IOVA_WIDTH != 0 {
+            unsafe { libc::munmap(ptr, size) };
+            return Err("huge page address exceeds the IOVA width".into());
+        }
+
+        match vfio::map_dma(ptr as usize, size) {
+            Ok(iova) => Ok(Dma {
+                virt: ptr as *mut T,
+                phys: iova,
+                size,
+                backing: Backing::Iommu,
+                huge_pages: None,
+            }),
+            Err(e) => {
+                unsafe { libc::munmap(ptr, size) };
+                Err(e)
+            }
+        }
+    }
+}
+
+// smallest pool block, one memory page so that PRPs never cross block boundaries
+const POOL_BLOCK_BITS: u32 = 12;
+const POOL_ORDERS: usize = (HUGE_PAGE_BITS - POOL_BLOCK_BITS) as usize + 1;
+
+/// Buddy allocator over huge pages, block sizes range from 4 KiB (order 0) to a huge page
+#[derive(Debug)]
+struct PoolState {
+    pages: Vec<Dma<u8>>,
+    // free blocks of each order as (page, offset), lowest address first
+    free: Vec<BTreeSet<(usize, usize)>>,
+}
+
+impl PoolState {
+    fn grow(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pages.push(Dma::allocate(HUGE_PAGE_SIZE)?);
+        self.free[POOL_ORDERS - 1].insert((self.pages.len() - 1, 0));
+        Ok(())
+    }
+
+    fn take(&mut self, order: usize) -> Option<(usize, usize)> {
+        let from = (order..POOL_ORDERS).find(|&o| !self.free[o].is_empty())?;
+        let (page, offset) = self.free[from].pop_first()?;
+        // split off upper halves until the block has the requested size
+        for o in (order..from).rev() {
+            self.free[o].insert((page, offset + (1 << (o as u32 + POOL_BLOCK_BITS))));
+        }
+        Some((page, offset))
+    }
+
+    fn release(&mut self, page: usize, mut offset: usize, mut order: usize) {
+        // merge with free buddies
+        while order < POOL_ORDERS - 1 {
+            let buddy = offset ^ (1 << (order as u32 + POOL_BLOCK_BITS));
+            if !self.free[order].remove(&(page, buddy)) {
+                break;
+            }
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        self.free[order].insert((page, offset));
+    }
+}
+
+/// Pool of DMA memory handing out buffers carved out of huge pages.
+///
+/// Buffers are regular [`Dma<u8>`]s that return to the pool when dropped. Block sizes are powers
+/// of two between 4 KiB and a huge page, the pool grows by a huge page when no block fits.
+#[derive(Debug, Clone)]
+pub struct DmaPool {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl DmaPool {
+    /// Creates a pool with `huge_pages` huge pages mapped up front
+    pub fn new(huge_pages: usize) -> Result<Self, Box<dyn Error>> {
+        let mut state = PoolState {
+            pages: Vec::with_capacity(huge_pages),
+            free: vec![BTreeSet::new(); POOL_ORDERS],
+        };
+        for _ in 0..huge_pages {
+            state.grow()?;
+        }
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Allocates a physically contiguous buffer of `size` bytes, at most a huge page
+    pub fn allocate(&self, size: usize) -> Result<Dma<u8>, Box<dyn Error>> {
+        if size == 0 || size > HUGE_PAGE_SIZE {
+            return Err(format!("pool buffers must be 1 to {} bytes", HUGE_PAGE_SIZE).into());
+        }
+        let order = size
+            .next_power_of_two()
+            .trailing_zeros()
+            .saturating_sub(POOL_BLOCK_BITS) as usize;
+
+        let mut state = self.state.lock().unwrap();
+        let (page, offset) = match state.take(order) {
+            Some(block) => block,
+            None => {
+                state.grow()?;
+                state.take(order).ok_or("pool exhausted")?
+            }
+        };
+        let memory = &state.pages[page];
+
+        Ok(Dma {
+            virt: unsafe { memory.virt.add(offset) },
+            phys: memory.phys + offset,
+            size,
+            backing: Backing::Pooled {
+                pool: Arc::clone(&self.state),
+                page,
+                offset,
+                order,
+            },
+            huge_pages: None,
+        })
+    }
+
+    /// Number of huge pages mapped by the pool
+    pub fn huge_pages(&self) -> usize {
+        self.state.lock().unwrap().pages.len()
+    }
+
+    /// Bytes currently available without mapping more huge pages
+    pub fn available(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .free
+            .iter()
+            .enumerate()
+            .map(|(order, blocks)| blocks.len() << (order as u32 + POOL_BLOCK_BITS))
+            .sum()
+    }
 }
 
 /// Translates a virtual address to its physical counterpart
@@ -260,7 +596,7 @@ pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
     let mut buffer = [0; mem::size_of::<usize>()];
     file.read_exact(&mut buffer)?;
 
-    let phys = unsafe { mem::transmute::<[u8; mem::size_of::<usize>()], usize>(buffer) };
+    let phys = usize::from_ne_bytes(buffer);
     Ok((phys & 0x007F_FFFF_FFFF_FFFF) * pagesize + addr % pagesize)
 }
 
@@ -268,3 +604,87 @@ pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
 pub fn vfio_enabled() -> bool {
     unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR.is_some() }
 }
+
+/// Backs all further DMA allocations with regular memory and uses virtual addresses as device
+/// addresses. Only sound when every device is emulated in this process, so it fails once a real
+/// device was opened and real devices can't be opened afterwards.
+#[cfg(feature = "emulator")]
+pub(crate) fn enable_identity_mapping() -> Result<(), Box<dyn Error>> {
+    set_address_mode(ADDRESS_MODE_IDENTITY)
+        .map_err(|_| "a real device was opened, emulated controllers are not available".into())
+}
+
+/// Fails if DMA memory is identity mapped for emulated controllers, a real device would access
+/// arbitrary physical memory with its addresses.
+pub(crate) fn claim_physical_mapping() -> Result<(), Box<dyn Error>> {
+    set_address_mode(ADDRESS_MODE_PHYSICAL).map_err(|_| {
+        "an emulated controller was created, real devices can't be opened in this process".into()
+    })
+}
+
+fn set_address_mode(mode: u8) -> Result<(), u8> {
+    match ADDRESS_MODE.compare_exchange(
+        ADDRESS_MODE_UNSET,
+        mode,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    ) {
+        Err(current) if current != mode => Err(current),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two huge pages whose device addresses are swapped
+    fn swapped_pages(memory: &mut [u8]) -> Dma<u8> {
+        let virt = memory.as_mut_ptr();
+        let mut dma = Dma::borrowed(virt, 0, 2 * HUGE_PAGE_SIZE);
+        dma.phys = 0x4020_0000;
+        dma.huge_pages = Some(Arc::new(HugePages {
+            virt: virt as usize,
+            phys: vec![0x4020_0000, 0x4000_0000],
+        }));
+        dma
+    }
+
+    #[test]
+    fn huge_pages_are_translated_one_by_one() {
+        let mut memory = vec![0u8; 2 * HUGE_PAGE_SIZE];
+        let dma = swapped_pages(&mut memory);
+        assert!(!dma.is_contiguous());
+        assert_eq!(
+            dma.phys_at(HUGE_PAGE_SIZE - 1),
+            0x4020_0000 + HUGE_PAGE_SIZE - 1
+        );
+        assert_eq!(dma.phys_at(HUGE_PAGE_SIZE + 8), 0x4000_0008);
+
+        let slice = dma.slice(HUGE_PAGE_SIZE + 4096..2 * HUGE_PAGE_SIZE);
+        assert_eq!(slice.phys, 0x4000_1000);
+        assert_eq!(slice.phys_at(4096), 0x4000_2000);
+
+        // a chunk crossing the boundary between the pages
+        let chunk = dma
+            .slice(HUGE_PAGE_SIZE - 4096..HUGE_PAGE_SIZE + 8192)
+            .chunks(usize::MAX)
+            .next()
+            .map(|chunk| {
+                (
+                    chunk.phys_addr,
+                    chunk.phys_at(4096),
+                    chunk.contiguous_parts(),
+                )
+            })
+            .unwrap();
+        assert_eq!(
+            chunk,
+            (
+                0x4040_0000 - 4096,
+                0x4000_0000,
+                vec![(0x4040_0000 - 4096, 4096), (0x4000_0000, 8192)]
+            )
+        );
+    }
+}
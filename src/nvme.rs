@@ -1,17 +1,39 @@
 use tokio::sync::oneshot::Sender;
 use tokio::sync::Mutex;
 
-use crate::cmd::NvmeCommand;
-use crate::memory::{Dma, DmaSlice};
-use crate::pci::pci_map_resource;
+use crate::async_event::{AsyncEvent, AsyncEventLog};
+use crate::cmb::{CmbUsage, ControllerMemoryBuffer};
+use crate::cmd::{DsmRange, NvmeCommand};
+#[cfg(feature = "emulator")]
+use crate::emulator::EmulatedController;
+use crate::features::{
+    Arbitration, AsyncEventConfig, Feature, FeatureCapabilities, FeatureSelect, HostMemoryBuffer,
+    NumberOfQueues, TemperatureThreshold, Timestamp, HOST_MEMORY_BUFFER, TIMESTAMP,
+};
+use crate::log_page::{
+    ErrorLogEntry, FirmwareSlotLog, SmartLogData, LOG_CHANGED_NAMESPACES, LOG_ERROR,
+    LOG_FIRMWARE_SLOT, LOG_SMART,
+};
+use crate::memory::{self, Dma, DmaChunk, DmaSlice};
+use crate::pci::{pci_map_resource, PciBinding};
+use crate::pmr::PersistentMemoryRegion;
+use crate::prp::{PrpLists, MAX_PRP_TRANSFER, PAGE_SIZE};
 use crate::queues::*;
-use crate::{NvmeNamespace, NvmeStats, HUGE_PAGE_SIZE};
+use crate::registers::{Doorbell, DoorbellBuffers, MmioRegisters, RegisterAccess};
+use crate::request::Orphans;
+use crate::sgl::{SglDescriptor, SglSegments, SGL_SEGMENT_ENTRIES};
+use crate::vfio;
+use crate::{
+    ControllerInfo, LbaRange, NvmeNamespace, NvmeStats, PowerState, SmartLog, HUGE_PAGE_SIZE,
+};
 use core::fmt;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Debug;
 use std::hint::spin_loop;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // clippy doesnt like this
 #[allow(unused, clippy::upper_case_acronyms)]
@@ -47,6 +69,16 @@ pub enum NvmeRegs64 {
     PMRMSC = 0xE14, // Persistent Memory Buffer Space Control
 }
 
+/// Maximum number of ranges of a single Dataset Management command
+pub const DSM_MAX_RANGES: usize = 256;
+
+/// Asynchronous Event Requests kept outstanding at most, regardless of AERL
+const MAX_ASYNC_EVENT_REQUESTS: usize = 4;
+/// First command id of Asynchronous Event Requests, other admin commands use their queue slot
+const AER_CID: u16 = 0xF000;
+/// How long a failed controller gets to complete a shutdown
+const FAILED_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum NvmeArrayRegs {
@@ -93,212 +125,488 @@ struct IdentifyNamespaceData {
     endgid: u16,
     nguid: [u8; 16],
     eui64: u64,
-    pub lba_format_support: [u32; 16],
-    _rsvd3: [u8; 192],
+    pub lba_format_support: [u32; 64],
     vendor_specific: [u8; 3712],
 }
 
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+struct IdentifyControllerData {
+    vid: u16,
+    ssvid: u16,
+    sn: [u8; 20],
+    mn: [u8; 40],
+    fr: [u8; 8],
+    rab: u8,
+    ieee: [u8; 3],
+    cmic: u8,
+    mdts: u8,
+    cntlid: u16,
+    ver: u32,
+    rtd3r: u32,
+    rtd3e: u32,
+    oaes: u32,
+    ctratt: u32,
+    _rsvd1: [u8; 156],
+    oacs: u16,
+    acl: u8,
+    aerl: u8,
+    frmw: u8,
+    lpa: u8,
+    elpe: u8,
+    npss: u8,
+    avscc: u8,
+    apsta: u8,
+    wctemp: u16,
+    cctemp: u16,
+    mtfa: u16,
+    hmpre: u32,
+    hmmin: u32,
+    tnvmcap: u128,
+    unvmcap: u128,
+    _rsvd2: [u8; 200],
+    sqes: u8,
+    cqes: u8,
+    maxcmd: u16,
+    nn: u32,
+    oncs: u16,
+    fuses: u16,
+    fna: u8,
+    vwc: u8,
+    awun: u16,
+    awupf: u16,
+    nvscc: u8,
+    nwpc: u8,
+    acwu: u16,
+    _rsvd3: u16,
+    sgls: u32,
+    _rsvd4: [u8; 228],
+    subnqn: [u8; 256],
+    _rsvd5: [u8; 1024],
+    psd: [PowerStateDescriptor; 32],
+    vendor_specific: [u8; 1024],
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+struct PowerStateDescriptor {
+    mp: u16,
+    _rsvd1: u8,
+    flags: u8,
+    enlat: u32,
+    exlat: u32,
+    rrt: u8,
+    rrl: u8,
+    rwt: u8,
+    rwl: u8,
+    _rsvd2: [u8; 16],
+}
+
+const _: () = assert!(std::mem::size_of::<IdentifyControllerData>() == 4096);
+const _: () = assert!(std::mem::size_of::<IdentifyNamespaceData>() == 4096);
+
+/// Converts a space padded ASCII field of an identify data structure
+pub(crate) fn identify_string(field: &[u8]) -> String {
+    field
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// I/O error for requests rejected before submission
+pub(crate) fn invalid_input(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message.into())
+}
+
+/// Sender completing the request of a command and the command's submission time
+type PendingCommand = (Sender<std::io::Result<()>>, Instant);
+
+// Checks that `data` covers whole blocks of `ns` starting at `lba` and, if `dword_aligned`,
+// starts at a dword aligned address
+fn check_buffer(
+    ns: &NvmeNamespace,
+    data: &impl DmaSlice,
+    lba: u64,
+    dword_aligned: bool,
+) -> std::io::Result<()> {
+    let (addr, bytes) = data
+        .chunks(usize::MAX)
+        .next()
+        .map_or((0, 0), |chunk| (chunk.phys_addr, chunk.slice.len()));
+    ns.transfer_blocks(lba, bytes)?;
+    if dword_aligned && addr % 4 != 0 {
+        return Err(invalid_input(format!(
+            "buffer at {addr:#x} is not dword aligned"
+        )));
+    }
+    Ok(())
+}
+
+// Largest multiple of the block size of `ns` that fits into `max_transfer` bytes
+fn chunk_size(ns: &NvmeNamespace, max_transfer: usize) -> std::io::Result<usize> {
+    match max_transfer as u64 / ns.block_size * ns.block_size {
+        0 => Err(invalid_input(format!(
+            "block size {} exceeds the maximum transfer size",
+            ns.block_size
+        ))),
+        chunk_size => Ok(chunk_size as usize),
+    }
+}
+
+// Read or Write command for `blocks` blocks of `ns` at `lba` with data pointer `dptr`
+fn rw_command(
+    c_id: u16,
+    ns: &NvmeNamespace,
+    lba: u64,
+    blocks: u64,
+    (ptr0, ptr1): (u64, u64),
+    write: bool,
+    fua: bool,
+) -> NvmeCommand {
+    if write {
+        NvmeCommand::io_write(c_id, ns.id, lba, blocks as u16 - 1, ptr0, ptr1, fua)
+    } else {
+        NvmeCommand::io_read(c_id, ns.id, lba, blocks as u16 - 1, ptr0, ptr1)
+    }
+}
+
+/// Command ids of an I/O queue pair, which also select the PRP list and SGL segment page of a
+/// command. A submission queue slot is free again once the controller fetched the command, so
+/// ids are only reused once the command completed.
+#[derive(Debug)]
+struct CommandIds {
+    free: Vec<u16>,
+    in_use: Vec<bool>,
+}
+
+impl CommandIds {
+    // at most `len - 1` commands are outstanding, as many as the completion queue holds
+    fn new(len: usize) -> Self {
+        Self {
+            free: (0..(len - 1) as u16).rev().collect(),
+            in_use: vec![false; len - 1],
+        }
+    }
+
+    fn take(&mut self) -> Option<u16> {
+        let id = self.free.pop()?;
+        self.in_use[id as usize] = true;
+        Some(id)
+    }
+
+    fn release(&mut self, id: u16) {
+        // completions with unknown ids are ignored rather than handing out an id twice
+        if let Some(in_use) = self.in_use.get_mut(id as usize) {
+            if std::mem::take(in_use) {
+                self.free.push(id);
+            }
+        }
+    }
+
+    // all commands are gone after the queue was recreated
+    fn reset(&mut self) {
+        *self = Self::new(self.in_use.len() + 1);
+    }
+}
+
 #[derive(Debug)]
 pub struct NvmeQueuePair<T: DmaSlice + Debug> {
     pub id: u16,
+    priority: QueuePriority,
     pub sub_queue: NvmeSubQueue,
     comp_queue: NvmeCompQueue,
-    pub pending: Mutex<HashMap<u16, Sender<std::io::Result<()>>>>,
+    /// Commands waiting for completion with their submission time
+    pub pending: Mutex<HashMap<u16, PendingCommand>>,
+    /// Commands whose request was dropped before completion
+    pub orphans: Arc<Orphans>,
+    ids: CommandIds,
+    prp_lists: PrpLists,
+    // segment pages, allocated once SGLs are enabled
+    sgl_segments: Option<SglSegments>,
+    sgl_support: bool,
+    sgl_dword_alignment: bool,
+    sgl_bit_bucket: bool,
+    // largest transfer of a single command in bytes
+    max_transfer: usize,
     _type: PhantomData<T>,
 }
 
 impl<T: DmaSlice + Debug> NvmeQueuePair<T> {
+    /// Priority class of the submission queue under weighted round robin
+    pub fn priority(&self) -> QueuePriority {
+        self.priority
+    }
+
     /// returns amount of requests pushed into submission queue
-    pub fn submit_io(&mut self, data: &impl DmaSlice, mut lba: u64, write: bool) -> usize {
+    pub fn submit_io(
+        &mut self,
+        ns: &NvmeNamespace,
+        data: &impl DmaSlice,
+        mut lba: u64,
+        write: bool,
+    ) -> std::io::Result<usize> {
+        let chunk_size = self.check_transfer(ns, data, lba)?;
         let mut reqs = 0;
-        // TODO: contruct PRP list?
-        for chunk in data.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64).div_ceil(512);
-
-            let addr = chunk.phys_addr as u64;
-            let bytes = blocks * 512;
-            let ptr1 = if bytes <= 4096 {
-                0
-            } else {
-                addr + 4096 // self.page_size
-            };
-
-            let entry = if write {
-                NvmeCommand::io_write(
-                    self.id << 11 | self.sub_queue.tail as u16,
-                    1,
-                    lba,
-                    blocks as u16 - 1,
-                    addr,
-                    ptr1,
-                )
-            } else {
-                NvmeCommand::io_read(
-                    self.id << 11 | self.sub_queue.tail as u16,
-                    1,
-                    lba,
-                    blocks as u16 - 1,
-                    addr,
-                    ptr1,
-                )
-            };
-
-            if let Some(tail) = self.sub_queue.submit_checked(entry) {
-                unsafe {
-                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
-                }
-            } else {
+        for chunk in data.chunks(chunk_size) {
+            let blocks = chunk.slice.len() as u64 / ns.block_size;
+            let Some(c_id) = self.next_c_id() else {
                 eprintln!("queue full");
-                return reqs;
-            }
+                return Ok(reqs);
+            };
+            let entry = self.io_command(ns, c_id, lba, &chunk, write, false);
+            let tail = self.push(c_id, entry);
+            self.sub_queue.doorbell.ring(tail as u32);
 
             lba += blocks;
             reqs += 1;
         }
-        reqs
+        Ok(reqs)
     }
 
-    // TODO: maybe return result
-    pub fn complete_io(&mut self, n: usize) -> Option<u16> {
+    /// Waits for `n` completions, returns the submission queue head or the first error
+    pub fn complete_io(&mut self, n: usize) -> Result<u16, NvmeError> {
         assert!(n > 0);
-        let (tail, c_entry, _) = self.comp_queue.complete_n(n);
-        unsafe {
-            std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-        }
-        self.sub_queue.head = c_entry.sq_head as usize;
-        let status = c_entry.status >> 1;
-        if status != 0 {
-            eprintln!(
-                "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                status,
-                status & 0xFF,
-                (status >> 8) & 0x7
-            );
-            eprintln!("{:?}", c_entry);
-            return None;
+        let mut result = Ok(());
+        let mut tail = 0;
+        let mut sq_head = 0;
+        for _ in 0..n {
+            let (head, c_entry, _) = self.comp_queue.complete_spin();
+            self.ids.release(c_entry.c_id);
+            result = result.and(NvmeError::check(&c_entry));
+            (tail, sq_head) = (head, c_entry.sq_head);
         }
-        Some(c_entry.sq_head)
+        self.comp_queue.doorbell.ring(tail as u32);
+        self.sub_queue.head = sq_head as usize;
+        result.map(|_| sq_head)
     }
 
     pub fn quick_poll(&mut self) -> Option<()> {
         if let Some((tail, c_entry, _)) = self.comp_queue.complete() {
-            unsafe {
-                std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-            }
+            self.comp_queue.doorbell.ring(tail as u32);
             self.sub_queue.head = c_entry.sq_head as usize;
-            let status = c_entry.status >> 1;
-            if status != 0 {
-                eprintln!(
-                    "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                    status,
-                    status & 0xFF,
-                    (status >> 8) & 0x7
-                );
-                eprintln!("{:?}", c_entry);
+            self.ids.release(c_entry.c_id);
+            if let Err(e) = NvmeError::check(&c_entry) {
+                eprintln!("{e}");
             }
             return Some(());
         }
         None
     }
 
+    /// Pushes commands for `data` into the submission queue without ringing the doorbell.
+    /// `fua` forces writes to non-volatile media before they complete.
     pub fn submit_async(
         &mut self,
+        ns: &NvmeNamespace,
         data: &T,
         mut lba: u64,
         write: bool,
-    ) -> (Option<usize>, Vec<u16>) {
+        fua: bool,
+    ) -> std::io::Result<(Option<usize>, Vec<u16>)> {
+        let chunk_size = self.check_transfer(ns, data, lba)?;
         let mut ids: Vec<u16> = Vec::new();
 
         let mut last_tail = None;
 
-        let chunks = data.chunks(2 * 4096);
+        let chunks = data.chunks(chunk_size);
 
         for chunk in chunks {
-            let blocks = (chunk.slice.len() as u64).div_ceil(512);
-
-            let addr = chunk.phys_addr as u64;
-            let bytes = blocks * 512;
-            let ptr1 = if bytes <= 4096 {
-                0
-            } else {
-                addr + 4096 // self.page_size
-            };
-            let c_id = self.id << 11 | self.sub_queue.tail as u16;
-            let entry = if write {
-                NvmeCommand::io_write(c_id, 1, lba, blocks as u16 - 1, addr, ptr1)
-            } else {
-                NvmeCommand::io_read(c_id, 1, lba, blocks as u16 - 1, addr, ptr1)
-            };
-
-            if let Some(tail) = self.sub_queue.submit_checked(entry) {
-                last_tail = Some(tail);
-            } else {
+            let blocks = chunk.slice.len() as u64 / ns.block_size;
+            let Some(c_id) = self.next_c_id() else {
                 eprintln!("queue full");
-                return (last_tail, ids);
-            }
+                return Ok((last_tail, ids));
+            };
+            let entry = self.io_command(ns, c_id, lba, &chunk, write, fua);
+            last_tail = Some(self.push(c_id, entry));
 
             lba += blocks;
 
             ids.push(c_id);
         }
 
-        (last_tail, ids)
+        Ok((last_tail, ids))
     }
 
-    pub fn set_tail(&mut self, tail: u32) {
-        unsafe {
-            std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail);
+    /// Checks a transfer of `data` to `lba` against the LBA format of `ns` and the alignment
+    /// required for data pointers, returns the number of bytes to transfer per command
+    pub fn check_transfer(
+        &self,
+        ns: &NvmeNamespace,
+        data: &impl DmaSlice,
+        lba: u64,
+    ) -> std::io::Result<usize> {
+        check_buffer(ns, data, lba, !self.uses_sgl() || self.sgl_dword_alignment)?;
+        chunk_size(ns, self.max_transfer)
+    }
+
+    /// Uses SGLs instead of PRPs to describe data buffers if the controller supports them.
+    /// Returns whether SGLs are used.
+    pub fn set_sgl(&mut self, enable: bool) -> Result<bool, QueueError> {
+        if !enable || !self.sgl_support {
+            self.sgl_segments = None;
+            return Ok(false);
+        }
+        if self.sgl_segments.is_none() {
+            self.sgl_segments = Some(SglSegments::new(self.sub_queue.len)?);
         }
+        Ok(true)
     }
 
-    pub fn poll(&mut self) -> Option<u16> {
+    pub fn uses_sgl(&self) -> bool {
+        self.sgl_segments.is_some()
+    }
+
+    /// Pushes a single command reading or writing the buffers described by `descriptors` into the
+    /// submission queue without ringing the doorbell. Bit bucket descriptors skip read data.
+    /// Returns the new tail and the command id, or `None` if the queue is full.
+    /// Fails if SGLs are not enabled, the descriptors do not describe whole blocks, the transfer
+    /// exceeds the maximum transfer size, or bit buckets are used for writes or are not supported.
+    pub fn submit_vectored(
+        &mut self,
+        ns: &NvmeNamespace,
+        descriptors: &[SglDescriptor],
+        lba: u64,
+        write: bool,
+        fua: bool,
+    ) -> std::io::Result<Option<(usize, u16)>> {
+        let bytes: usize = descriptors.iter().map(|d| d.data_len()).sum();
+        let blocks = ns.transfer_blocks(lba, bytes)?;
+        if bytes > self.max_transfer {
+            return Err(invalid_input(format!(
+                "transfer of {bytes} bytes exceeds the maximum transfer size"
+            )));
+        }
+        if descriptors.iter().any(|d| d.is_bit_bucket()) && (write || !self.sgl_bit_bucket) {
+            return Err(invalid_input(
+                "bit buckets are only supported for reads of controllers advertising them",
+            ));
+        }
+        if descriptors.len() > SGL_SEGMENT_ENTRIES {
+            return Err(invalid_input(format!(
+                "more than {SGL_SEGMENT_ENTRIES} SGL descriptors"
+            )));
+        }
+
+        if self.sgl_segments.is_none() {
+            return Err(invalid_input("SGLs are not enabled"));
+        }
+        let Some(c_id) = self.next_c_id() else {
+            return Ok(None);
+        };
+        let sgl = self
+            .sgl_segments
+            .as_mut()
+            .unwrap()
+            .build(c_id as usize, descriptors);
+        let entry = rw_command(c_id, ns, lba, blocks, (0, 0), write, fua);
+        Ok(Some((self.push(c_id, entry.with_sgl(sgl)), c_id)))
+    }
+
+    // read or write of `chunk` with command id `c_id`
+    fn io_command(
+        &mut self,
+        ns: &NvmeNamespace,
+        c_id: u16,
+        lba: u64,
+        chunk: &DmaChunk<'_, u8>,
+        write: bool,
+        fua: bool,
+    ) -> NvmeCommand {
+        let blocks = chunk.slice.len() as u64 / ns.block_size;
+        match self.sgl_segments.as_mut() {
+            Some(segments) => {
+                // a data block per part of the chunk that is contiguous in device memory
+                let descriptors: Vec<_> = chunk
+                    .contiguous_parts()
+                    .into_iter()
+                    .map(|(addr, len)| SglDescriptor::data_block(addr as u64, len as u32))
+                    .collect();
+                rw_command(c_id, ns, lba, blocks, (0, 0), write, fua)
+                    .with_sgl(segments.build(c_id as usize, &descriptors))
+            }
+            None => {
+                let dptr = self.prp_lists.chunk_prps(c_id as usize, chunk);
+                rw_command(c_id, ns, lba, blocks, dptr, write, fua)
+            }
+        }
+    }
+
+    /// Submits a Flush command for namespace `ns`.
+    /// Returns the new tail and the command id, or `None` if the queue is full.
+    pub fn submit_flush(&mut self, ns: &NvmeNamespace) -> Option<(usize, u16)> {
+        let c_id = self.next_c_id()?;
+        Some((self.push(c_id, NvmeCommand::flush(c_id, ns.id)), c_id))
+    }
+
+    /// Submits a Dataset Management command deallocating the `ranges` range descriptors at `ptr`.
+    /// Returns the new tail and the command id, or `None` if the queue is full.
+    pub fn submit_deallocate(
+        &mut self,
+        ns: &NvmeNamespace,
+        ptr: usize,
+        ranges: usize,
+    ) -> Option<(usize, u16)> {
+        assert!((1..=DSM_MAX_RANGES).contains(&ranges));
+        let c_id = self.next_c_id()?;
+        let entry =
+            NvmeCommand::dataset_management(c_id, ns.id, (ranges - 1) as u8, ptr as u64, 0, true);
+        Some((self.push(c_id, entry), c_id))
+    }
+
+    // free command id for the next command, `None` while the submission queue is full or all
+    // ids are taken by outstanding commands, including orphaned and timed out ones, as the
+    // device may still access their PRP list or SGL segment
+    fn next_c_id(&mut self) -> Option<u16> {
+        if self.sub_queue.is_full() {
+            return None;
+        }
+        self.ids.take()
+    }
+
+    // pushes command `entry` with the id from `next_c_id`, which checked for a free slot
+    fn push(&mut self, c_id: u16, entry: NvmeCommand) -> usize {
+        self.sub_queue
+            .submit_checked(entry)
+            .unwrap_or_else(|| panic!("no free slot for command {c_id}"))
+    }
+
+    pub fn set_tail(&mut self, tail: u32) {
+        self.sub_queue.doorbell.ring(tail);
+    }
+
+    /// Returns the command id and status of the next completion, if any
+    pub fn poll(&mut self) -> Option<(u16, Result<(), NvmeError>)> {
         // take completion at head from completion queue & return completion entry
         if let Some((tail, c_entry, _)) = self.comp_queue.complete() {
-            unsafe {
-                std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-            }
+            self.comp_queue.doorbell.ring(tail as u32);
             self.sub_queue.head = c_entry.sq_head as usize;
-            let status = c_entry.status >> 1;
-            if status != 0 {
-                eprintln!(
-                    "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                    status,
-                    status & 0xFF,
-                    (status >> 8) & 0x7
-                );
-                eprintln!("{:?}", c_entry);
-            }
-            return Some(c_entry.c_id);
+            self.ids.release(c_entry.c_id);
+            return Some((c_entry.c_id, NvmeError::check(&c_entry)));
         }
         None
     }
 
-    pub fn poll_multi(&mut self, max: usize) -> Vec<u16> {
-        let mut ids = Vec::with_capacity(max);
+    /// Returns command id and status of up to `max` completions
+    pub fn poll_multi(&mut self, max: usize) -> Vec<(u16, Result<(), NvmeError>)> {
+        let mut completions = Vec::with_capacity(max);
 
         for _ in 0..max {
             if let Some((tail, c_entry, _)) = self.comp_queue.complete() {
-                unsafe {
-                    std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
-                }
+                self.comp_queue.doorbell.ring(tail as u32);
                 self.sub_queue.head = c_entry.sq_head as usize;
-                let status = c_entry.status >> 1;
-                if status != 0 {
-                    eprintln!(
-                        "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                        status,
-                        status & 0xFF,
-                        (status >> 8) & 0x7
-                    );
-                    eprintln!("{:?}", c_entry);
-                }
-                ids.push(c_entry.c_id);
+                self.ids.release(c_entry.c_id);
+                completions.push((c_entry.c_id, NvmeError::check(&c_entry)));
             } else {
                 break;
             }
         }
 
-        ids
+        completions
     }
 }
 
@@ -323,23 +631,198 @@ impl From<Box<dyn std::error::Error>> for QueueError {
     }
 }
 
+impl From<NvmeError> for QueueError {
+    fn from(value: NvmeError) -> Self {
+        QueueError {
+            message: value.to_string(),
+        }
+    }
+}
+
+/// Failed command, decoded from the status field of its completion queue entry (NVMe spec 4.6.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeError {
+    /// Status Code Type
+    pub sct: u8,
+    /// Status Code
+    pub sc: u8,
+    /// More status information is available in the Error Information log page
+    pub more: bool,
+    /// Do Not Retry, the command is expected to fail again if resubmitted
+    pub dnr: bool,
+    /// Command specific dword 0 of the completion queue entry
+    pub command_specific: u32,
+}
+
+impl NvmeError {
+    /// Returns the error described by `entry`, if its status is not successful
+    pub fn check(entry: &NvmeCompletion) -> Result<(), NvmeError> {
+        let status = entry.status >> 1;
+        if status == 0 {
+            return Ok(());
+        }
+        Err(NvmeError {
+            sct: ((status >> 8) & 0x7) as u8,
+            sc: (status & 0xFF) as u8,
+            more: (status >> 13) & 1 == 1,
+            dnr: (status >> 14) & 1 == 1,
+            command_specific: entry.command_specific,
+        })
+    }
+
+    /// Returns the `NvmeError` wrapped by an error of a [`crate::request::Request`]
+    pub fn from_io_error(error: &std::io::Error) -> Option<&NvmeError> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    pub fn description(&self) -> &'static str {
+        match (self.sct, self.sc) {
+            (0, 0x01) => "Invalid Command Opcode",
+            (0, 0x02) => "Invalid Field in Command",
+            (0, 0x03) => "Command ID Conflict",
+            (0, 0x04) => "Data Transfer Error",
+            (0, 0x05) => "Commands Aborted due to Power Loss Notification",
+            (0, 0x06) => "Internal Error",
+            (0, 0x07) => "Command Abort Requested",
+            (0, 0x08) => "Command Aborted due to SQ Deletion",
+            (0, 0x0B) => "Invalid Namespace or Format",
+            (0, 0x80) => "LBA Out of Range",
+            (0, 0x81) => "Capacity Exceeded",
+            (0, 0x82) => "Namespace Not Ready",
+            (1, 0x00) => "Completion Queue Invalid",
+            (1, 0x01) => "Invalid Queue Identifier",
+            (1, 0x02) => "Invalid Queue Size",
+            (1, 0x03) => "Abort Command Limit Exceeded",
+            (1, 0x05) => "Asynchronous Event Request Limit Exceeded",
+            (1, 0x0C) => "Invalid Queue Deletion",
+            (2, 0x80) => "Write Fault",
+            (2, 0x81) => "Unrecovered Read Error",
+            (2, 0x85) => "Compare Failure",
+            (2, 0x86) => "Access Denied",
+            (2, 0x87) => "Deallocated or Unwritten Logical Block",
+            (7, _) => "Vendor Specific",
+            _ => "Unknown Status",
+        }
+    }
+}
+
+impl Error for NvmeError {}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NVMe command failed: {} (Status Code Type: 0x{:x}, Status Code: 0x{:x}{}{})",
+            self.description(),
+            self.sct,
+            self.sc,
+            if self.more { ", More" } else { "" },
+            if self.dnr { ", Do Not Retry" } else { "" }
+        )
+    }
+}
+
+impl From<NvmeError> for std::io::Error {
+    fn from(value: NvmeError) -> Self {
+        std::io::Error::other(value)
+    }
+}
+
+/// Command that did not complete within its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// Submission queue the command was submitted to, 0 for admin commands
+    pub sqid: u16,
+    /// Command ID
+    pub cid: u16,
+    pub timeout: Duration,
+}
+
+impl TimeoutError {
+    /// Returns the `TimeoutError` wrapped by an error of a [`crate::request::Request`]
+    pub fn from_io_error(error: &std::io::Error) -> Option<&TimeoutError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl Error for TimeoutError {}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NVMe command {} of queue {} timed out after {:?}",
+            self.cid, self.sqid, self.timeout
+        )
+    }
+}
+
+impl From<TimeoutError> for std::io::Error {
+    fn from(value: TimeoutError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, value)
+    }
+}
+
+/// Host Memory Buffer handed to the controller
+#[derive(Debug)]
+struct HostMemory {
+    // Host Memory Buffer Descriptors
+    descriptors: Dma<u8>,
+    buffers: Vec<Dma<u8>>,
+}
+
+impl HostMemory {
+    // size in memory pages
+    fn size(&self) -> u32 {
+        self.buffers
+            .iter()
+            .map(|buffer| buffer.size / PAGE_SIZE)
+            .sum::<usize>() as u32
+    }
+}
+
 #[derive(Debug)]
 #[allow(unused)]
 pub struct NvmeDevice<T: DmaSlice + Debug> {
     pci_addr: String,
-    addr: *mut u8,
-    len: usize,
+    regs: Arc<dyn RegisterAccess>,
     // Doorbell stride
     dstrd: u16,
     admin_sq: NvmeSubQueue,
     admin_cq: NvmeCompQueue,
     io_sq: NvmeSubQueue,
     io_cq: NvmeCompQueue,
-    buffer: Dma<u8>,           // 2MiB of buffer
-    prp_list: Dma<[u64; 512]>, // Address of PRP's, devices doesn't necessarily support 2MiB page sizes; 8 Bytes * 512 = 4096
+    buffer: Dma<u8>, // 2MiB of buffer
+    prp_lists: PrpLists,
     pub namespaces: HashMap<u32, NvmeNamespace>,
     pub stats: NvmeStats,
     q_id: u16,
+    // I/O queues allocated by the controller (NSQA / NCQA)
+    queues: NumberOfQueues,
+    controller: ControllerInfo,
+    // how long to wait for admin commands, CAP.TO by default
+    admin_timeout: Duration,
+    resets: u64,
+    resetting: bool,
+    // timed out admin commands are neither aborted nor escalated to a reset while shutting down
+    shutting_down: bool,
+    shut_down: bool,
+    async_events: bool,
+    // command ids of the outstanding Asynchronous Event Requests
+    aers: Vec<u16>,
+    // their completions, received while waiting for other admin commands
+    aer_completions: Vec<NvmeCompletion>,
+    host_memory: Option<HostMemory>,
+    // shadow doorbells of the i/o queues, see `NvmeDevice::enable_shadow_doorbells`
+    doorbell_buffers: Option<Arc<DoorbellBuffers>>,
+    // controller memory buffer and what new i/o queue pairs place in it
+    cmb: Option<ControllerMemoryBuffer>,
+    cmb_usage: CmbUsage,
+    // weights set with `NvmeDevice::set_arbitration`, restored after resets
+    arbitration: Option<Arbitration>,
+    // kernel driver binding before the device was taken over through sysfs
+    binding: Option<PciBinding>,
+    release_on_drop: bool,
     _type: PhantomData<T>,
 }
 
@@ -349,214 +832,1209 @@ unsafe impl<T: DmaSlice + Debug> Sync for NvmeDevice<T> {}
 
 #[allow(unused)]
 impl<T: DmaSlice + Debug> NvmeDevice<T> {
+    /// Initializes the controller at `pci_addr`. Devices bound to `vfio-pci` are accessed through
+    /// VFIO with all DMA memory mapped into the IOMMU, otherwise the kernel driver is unbound and
+    /// BAR0 is mapped through sysfs, which requires root. See [`NvmeDevice::release`] to give the
+    /// device back to the kernel driver.
     pub fn init(pci_addr: &str) -> Result<Self, Box<dyn Error>> {
-        let (addr, len) = pci_map_resource(pci_addr)?;
+        memory::claim_physical_mapping()?;
+        let (regs, binding) = if vfio::is_bound(pci_addr) {
+            let device_fd = vfio::init(pci_addr)?;
+            vfio::enable_dma(device_fd)?;
+            let (addr, len) = vfio::map_resource(device_fd)?;
+            let regs = MmioRegisters::for_device(addr, len, pci_addr, Some(device_fd));
+            (regs, None)
+        } else {
+            let (addr, len, binding) = pci_map_resource(pci_addr)?;
+            let regs = MmioRegisters::for_device(addr, len, pci_addr, None);
+            (regs, Some(binding))
+        };
+        let mut dev = Self::init_with_registers(pci_addr, Arc::new(regs))?;
+        dev.binding = binding;
+        Ok(dev)
+    }
+
+    /// Initializes a controller emulated in software instead of a pci device.
+    #[cfg(feature = "emulator")]
+    pub fn init_emulated(controller: Arc<EmulatedController>) -> Result<Self, Box<dyn Error>> {
+        Self::init_with_registers("emulated", controller)
+    }
+
+    fn init_with_registers(
+        pci_addr: &str,
+        regs: Arc<dyn RegisterAccess>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let cap = regs.read64(NvmeRegs64::CAP as usize);
+        let dstrd = ((cap >> 32) & 0b1111) as u16;
+        // CAP.MQES limits the i/o queues only
+        let io_len = QUEUE_LENGTH.min((cap & 0xFFFF) as usize + 1);
+        let contiguous = cap & (1 << 16) != 0;
+        // queue 1 is used by the synchronous i/o functions of the device itself
+        let doorbell = |qid: u16, cq: bool| {
+            Doorbell::new(
+                Arc::clone(&regs),
+                0x1000 + ((4 << dstrd) * (2 * qid + cq as u16)) as usize,
+            )
+        };
         let mut dev = Self {
             pci_addr: pci_addr.to_string(),
-            addr,
-            dstrd: {
-                unsafe {
-                    ((std::ptr::read_volatile(
-                        (addr as usize + NvmeRegs64::CAP as usize) as *const u64,
-                    ) >> 32)
-                        & 0b1111) as u16
-                }
-            },
-            len,
-            admin_sq: NvmeSubQueue::new(QUEUE_LENGTH, 0)?,
-            admin_cq: NvmeCompQueue::new(QUEUE_LENGTH, 0)?,
-            io_sq: NvmeSubQueue::new(QUEUE_LENGTH, 0)?,
-            io_cq: NvmeCompQueue::new(QUEUE_LENGTH, 0)?,
+            dstrd,
+            // the admin queues fit on a huge page and are always contiguous
+            admin_sq: NvmeSubQueue::new(QUEUE_LENGTH, true, doorbell(0, false))?,
+            admin_cq: NvmeCompQueue::new(QUEUE_LENGTH, true, doorbell(0, true))?,
+            io_sq: NvmeSubQueue::new(io_len, contiguous, doorbell(1, false))?,
+            io_cq: NvmeCompQueue::new(io_len, contiguous, doorbell(1, true))?,
             buffer: Dma::allocate(crate::memory::HUGE_PAGE_SIZE)?,
-            prp_list: Dma::allocate(8 * 512)?,
+            prp_lists: PrpLists::new(QUEUE_LENGTH)?,
             namespaces: HashMap::new(),
             stats: NvmeStats::default(),
             q_id: 1,
+            queues: NumberOfQueues::default(),
+            controller: ControllerInfo::default(),
+            admin_timeout: Duration::ZERO,
+            resets: 0,
+            resetting: false,
+            shutting_down: false,
+            shut_down: false,
+            async_events: false,
+            aers: Vec::new(),
+            aer_completions: Vec::new(),
+            host_memory: None,
+            doorbell_buffers: None,
+            cmb: None,
+            cmb_usage: CmbUsage::default(),
+            arbitration: None,
+            binding: None,
+            release_on_drop: false,
             _type: PhantomData,
+            regs,
         };
+        dev.admin_timeout = dev.ready_timeout();
+
+        println!("CAP: 0x{:x}", dev.get_reg64(NvmeRegs64::CAP as u64));
+        println!("VS: 0x{:x}", dev.get_reg32(NvmeRegs32::VS as u32));
+        println!("CC: 0x{:x}", dev.get_reg32(NvmeRegs32::CC as u32));
+
+        dev.enable_controller()?;
+        dev.request_queues()?;
+
+        println!("Requesting i/o queue pair");
+        dev.create_io_queues(
+            dev.q_id,
+            (dev.io_cq.get_addr(), dev.io_cq.is_contiguous()),
+            (dev.io_sq.get_addr(), dev.io_sq.is_contiguous()),
+            dev.io_sq.len,
+            QueuePriority::default(),
+        )?;
+        dev.q_id += 1;
+
+        Ok(dev)
+    }
+
+    // disables the controller, programs the admin queues and enables it again
+    fn enable_controller(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("Disabling controller");
+        // Set Enable bit to 0
+        let ctrl_config = self.get_reg32(NvmeRegs32::CC as u32) & 0xFFFF_FFFE;
+        self.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
+
+        // Wait for not ready
+        self.wait_ready(false)?;
+
+        // Configure Admin Queues
+        self.set_reg64(NvmeRegs64::ASQ as u32, self.admin_sq.get_addr() as u64);
+        self.set_reg64(NvmeRegs64::ACQ as u32, self.admin_cq.get_addr() as u64);
+        self.set_reg32(
+            NvmeRegs32::AQA as u32,
+            (QUEUE_LENGTH as u32 - 1) << 16 | (QUEUE_LENGTH as u32 - 1),
+        );
+
+        // Configure other stuff
+        // TODO: check css values
+        let mut cc = self.get_reg32(NvmeRegs32::CC as u32);
+        // mask out reserved stuff
+        cc &= 0xFF00_000F;
+        // Set Completion (2^4 = 16 Bytes) and Submission Entry (2^6 = 64 Bytes) sizes
+        cc |= (4 << 20) | (6 << 16);
+        // Arbitration Mechanism: weighted round robin with urgent priority class if supported
+        if self.get_reg64(NvmeRegs64::CAP as u64) & (1 << 17) != 0 {
+            cc |= 1 << 11;
+        }
+
+        // Set Memory Page Size
+        // let mpsmax = ((self.get_reg64(NvmeRegs64::CAP as u64) >> 52) & 0xF) as u32;
+        // cc |= (mpsmax << 7);
+        println!("MPS {}", (cc >> 7) & 0xF);
+        self.set_reg32(NvmeRegs32::CC as u32, cc);
+
+        // Enable the controller
+        println!("Enabling controller");
+        let ctrl_config = self.get_reg32(NvmeRegs32::CC as u32) | 1;
+        self.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
+
+        // wait for ready
+        self.wait_ready(true)?;
+
+        Ok(())
+    }
+
+    // requests as many i/o queues as possible, before any of them are created
+    fn request_queues(&mut self) -> Result<(), Box<dyn Error>> {
+        let dw0 = self.set_feature(&NumberOfQueues {
+            submission: u16::MAX as u32,
+            completion: u16::MAX as u32,
+        })?;
+        self.queues = NumberOfQueues::decode(dw0);
+        println!(
+            "Allocated {} submission and {} completion queues",
+            self.queues.submission, self.queues.completion
+        );
+        Ok(())
+    }
+
+    /// I/O submission and completion queues allocated by the controller (NSQA / NCQA)
+    pub fn allocated_queues(&self) -> NumberOfQueues {
+        self.queues
+    }
+
+    /// Maximum number of I/O queue pairs, including the one used by the synchronous functions
+    pub fn max_io_queue_pairs(&self) -> usize {
+        self.queues.submission.min(self.queues.completion) as usize
+    }
+
+    /// Whether the controller arbitrates weighted round robin with urgent priority class
+    /// (CC.AMS), so that [`QueuePriority`] and the [`Arbitration`] weights apply
+    pub fn weighted_round_robin(&self) -> bool {
+        (self.get_reg32(NvmeRegs32::CC as u32) >> 11) & 0b111 == 1
+    }
+
+    /// Sets the weights of the high, medium and low priority classes and the arbitration burst,
+    /// kept across resets
+    pub fn set_arbitration(&mut self, arbitration: Arbitration) -> Result<(), Box<dyn Error>> {
+        self.set_feature(&arbitration)?;
+        self.arbitration = Some(arbitration);
+        Ok(())
+    }
+
+    /// Maximum number of entries of an I/O queue (CAP.MQES)
+    pub fn max_queue_length(&self) -> usize {
+        (self.get_reg64(NvmeRegs64::CAP as u64) & 0xFFFF) as usize + 1
+    }
+
+    // waits until CSTS.RDY is `ready`, at most CAP.TO
+    fn wait_ready(&self, ready: bool) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + self.ready_timeout();
+        while (self.get_reg32(NvmeRegs32::CSTS as u32) & 1 == 1) != ready {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "controller did not become {} within {:?}",
+                    if ready { "ready" } else { "not ready" },
+                    self.ready_timeout()
+                )
+                .into());
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Worst case time the controller takes to change CSTS.RDY (CAP.TO)
+    pub fn ready_timeout(&self) -> Duration {
+        let to = (self.get_reg64(NvmeRegs64::CAP as u64) >> 24) & 0xFF;
+        Duration::from_millis(500 * to.max(1))
+    }
+
+    /// Sets how long to wait for admin commands before aborting them, defaults to CAP.TO
+    pub fn set_admin_timeout(&mut self, timeout: Duration) {
+        self.admin_timeout = timeout;
+    }
+
+    pub fn admin_timeout(&self) -> Duration {
+        self.admin_timeout
+    }
+
+    /// Number of controller resets since initialization
+    pub fn reset_count(&self) -> u64 {
+        self.resets
+    }
+
+    /// Whether the controller reported a fatal status (CSTS.CFS) and has to be reset
+    pub fn fatal_status(&self) -> bool {
+        self.get_reg32(NvmeRegs32::CSTS as u32) & (1 << 1) != 0
+    }
+
+    /// Resets the controller and recreates the admin queues and the I/O queue pair of the
+    /// synchronous functions. All other I/O queue pairs are deleted by the controller and have
+    /// to be recreated with [`NvmeDevice::recreate_io_queue_pair`], commands in flight are lost.
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("Resetting controller");
+        self.resets += 1;
+        self.admin_sq.reset();
+        self.admin_cq.reset();
+        self.io_sq.reset();
+        self.io_cq.reset();
+        self.aers.clear();
+        self.aer_completions.clear();
+
+        self.shut_down = false;
+
+        // admin commands timing out during the reset must not trigger another one
+        self.resetting = true;
+        let result = self
+            .enable_controller()
+            .and_then(|_| match &self.cmb {
+                Some(cmb) => self.set_cmb_address(cmb),
+                None => Ok(()),
+            })
+            .and_then(|_| self.request_queues())
+            .and_then(|_| self.set_doorbell_buffers())
+            .and_then(|_| {
+                self.create_io_queues(
+                    1,
+                    (self.io_cq.get_addr(), self.io_cq.is_contiguous()),
+                    (self.io_sq.get_addr(), self.io_sq.is_contiguous()),
+                    self.io_sq.len,
+                    QueuePriority::default(),
+                )
+            });
+        self.resetting = false;
+        result?;
+
+        if self.async_events {
+            if let Err(e) = self.enable_async_events() {
+                eprintln!("re-enabling asynchronous events failed: {e}");
+            }
+        }
+        if let Some(arbitration) = self.arbitration {
+            if let Err(e) = self.set_feature(&arbitration) {
+                eprintln!("restoring the arbitration weights failed: {e}");
+            }
+        }
+        // the reset disabled the host memory buffer, the controller may reuse its contents
+        if let Err(e) = self.set_host_memory_buffer(true, true) {
+            eprintln!("re-enabling the host memory buffer failed: {e}");
+        }
+        Ok(())
+    }
+
+    // creates the completion and then the submission queue `q_id` of `len` entries, `cq` and
+    // `sq` are their addresses and whether they are physically contiguous
+    fn create_io_queues(
+        &mut self,
+        q_id: u16,
+        cq: (usize, bool),
+        sq: (usize, bool),
+        len: usize,
+        priority: QueuePriority,
+    ) -> Result<(), Box<dyn Error>> {
+        let size = (len - 1) as u16;
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::create_io_completion_queue(c_id, q_id, cq.0, size, cq.1)
+        })?;
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::create_io_submission_queue(c_id, q_id, sq.0, size, q_id, sq.1, priority)
+        })?;
+        Ok(())
+    }
+
+    /// Whether the controller requires physically contiguous I/O queues (CAP.CQR)
+    pub fn requires_contiguous_queues(&self) -> bool {
+        self.get_reg64(NvmeRegs64::CAP as u64) & (1 << 16) != 0
+    }
+
+    /// Lets the I/O queues write their doorbells to a shadow doorbell buffer in memory and only
+    /// to the register when the controller asks for it through the EventIdx buffer (Doorbell
+    /// Buffer Config). Saves doorbell writes, which are expensive for emulated controllers.
+    /// Has to be called before any I/O queue pairs are created, stays enabled across resets.
+    pub fn enable_shadow_doorbells(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.controller.supports_doorbell_buffer_config() {
+            return Err("controller does not support Doorbell Buffer Config".into());
+        }
+        if self.q_id > 2 {
+            return Err("shadow doorbells have to be enabled before creating queue pairs".into());
+        }
+        if self.doorbell_buffers.is_some() {
+            return Ok(());
+        }
+        self.doorbell_buffers = Some(Arc::new(DoorbellBuffers::new()?));
+        if let Err(e) = self.set_doorbell_buffers() {
+            self.doorbell_buffers = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn has_shadow_doorbells(&self) -> bool {
+        self.doorbell_buffers.is_some()
+    }
+
+    // hands the (cleared) doorbell buffers to the controller and attaches them to the doorbells
+    // of the i/o queue pair of the synchronous functions
+    fn set_doorbell_buffers(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(buffers) = self.doorbell_buffers.clone() else {
+            return Ok(());
+        };
+        buffers.clear();
+        let (shadow, event_idx) = buffers.addrs();
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::doorbell_buffer_config(c_id, shadow, event_idx)
+        })?;
+        let (tail, head) = (self.io_sq.tail as u32, self.io_cq.head() as u32);
+        self.io_sq
+            .doorbell
+            .set_shadow(Some(Arc::clone(&buffers)), tail);
+        self.io_cq.doorbell.set_shadow(Some(buffers), head);
+        Ok(())
+    }
+
+    /// Maps the Controller Memory Buffer for data buffers allocated from it, I/O queue pairs
+    /// created afterwards also place what `usage` asks for in it. Calling it again only changes
+    /// the usage.
+    pub fn enable_cmb(
+        &mut self,
+        usage: CmbUsage,
+    ) -> Result<ControllerMemoryBuffer, Box<dyn Error>> {
+        let cmb = match &self.cmb {
+            Some(cmb) => cmb.clone(),
+            None => self.map_cmb()?,
+        };
+        if usage.submission_queues && !cmb.supports_submission_queues() {
+            return Err("controller memory buffer does not support submission queues".into());
+        }
+        if usage.prp_lists && !cmb.supports_prp_lists() {
+            return Err("controller memory buffer does not support PRP lists".into());
+        }
+        self.cmb = Some(cmb.clone());
+        self.cmb_usage = usage;
+        Ok(cmb)
+    }
+
+    /// Controller Memory Buffer mapped by [`NvmeDevice::enable_cmb`]
+    pub fn cmb(&self) -> Option<&ControllerMemoryBuffer> {
+        self.cmb.as_ref()
+    }
+
+    // locates the controller memory buffer (CMBLOC / CMBSZ) and maps the BAR it is in
+    fn map_cmb(&mut self) -> Result<ControllerMemoryBuffer, Box<dyn Error>> {
+        // CAP.CMBS: CMBLOC and CMBSZ are only reported once enabled in CMBMSC
+        if self.supports_cmb_address() {
+            self.set_reg64(NvmeRegs64::CMBMSC as u32, 1);
+        }
+        let cmbsz = self.get_reg32(NvmeRegs32::CMBSZ as u32);
+        if cmbsz == 0 {
+            return Err("controller has no controller memory buffer".into());
+        }
+        let cmbloc = self.get_reg32(NvmeRegs32::CMBLOC as u32);
+        // CMBSZ.SZU: 4 KiB, 64 KiB, 1 MiB, ...
+        let unit = 4096usize << (4 * ((cmbsz >> 8) & 0xF));
+        let size = (cmbsz >> 12) as usize * unit;
+        let offset = (cmbloc >> 12) as usize * unit;
+        let bir = (cmbloc & 0b111) as u8;
+
+        let bar = self.regs.map_bar(bir)?;
+        if offset + size > bar.len {
+            return Err(format!(
+                "controller memory buffer of {size} bytes at {offset} exceeds BAR {bir} of {} bytes",
+                bar.len
+            )
+            .into());
+        }
+        let cmb = ControllerMemoryBuffer::new(bar, offset, size, cmbsz);
+        self.set_cmb_address(&cmb)?;
+        println!("Controller memory buffer of {size} bytes in BAR {bir}");
+        Ok(cmb)
+    }
+
+    // controllers with CAP.CMBS only access their memory buffer at the address set in CMBMSC,
+    // older ones at the address of the BAR
+    fn supports_cmb_address(&self) -> bool {
+        self.get_reg64(NvmeRegs64::CAP as u64) & (1 << 57) != 0
+    }
+
+    // sets the bus address of the controller memory buffer (CMBMSC.CBA) and enables it
+    fn set_cmb_address(&self, cmb: &ControllerMemoryBuffer) -> Result<(), Box<dyn Error>> {
+        if !self.supports_cmb_address() {
+            return Ok(());
+        }
+        // CBA | CMSE | CRE
+        self.set_reg64(NvmeRegs64::CMBMSC as u32, cmb.bus_addr() as u64 | 0b11);
+        // CMBSTS.CBAI
+        if self.get_reg32(NvmeRegs32::CMBSTS as u32) & 1 != 0 {
+            return Err(format!(
+                "controller rejected the memory buffer address {:#x}",
+                cmb.bus_addr()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Enables the Persistent Memory Region and maps the BAR it is in. Waits at most PMRTO for
+    /// the region to become ready.
+    pub fn enable_pmr(&mut self) -> Result<PersistentMemoryRegion, Box<dyn Error>> {
+        // CAP.PMRS
+        if self.get_reg64(NvmeRegs64::CAP as u64) & (1 << 56) == 0 {
+            return Err("controller has no persistent memory region".into());
+        }
+        let pmrcap = self.get_reg32(NvmeRegs32::PMRCAP as u32);
+        let bir = ((pmrcap >> 5) & 0b111) as u8;
+        let bar = self.regs.map_bar(bir)?;
+
+        self.set_reg32(NvmeRegs32::PMRCTL as u32, 1);
+        // PMRTO in units of 500 ms or minutes (PMRCAP.PMRTU)
+        let unit = match (pmrcap >> 8) & 0b11 {
+            0 => Duration::from_millis(500),
+            _ => Duration::from_secs(60),
+        };
+        let timeout = unit * ((pmrcap >> 16) & 0xFF).max(1);
+        let deadline = Instant::now() + timeout;
+        // PMRSTS.NRDY
+        while self.get_reg32(NvmeRegs32::PMRSTS as u32) & (1 << 8) != 0 {
+            if Instant::now() >= deadline {
+                self.set_reg32(NvmeRegs32::PMRCTL as u32, 0);
+                return Err(format!(
+                    "persistent memory region did not become ready within {timeout:?}"
+                )
+                .into());
+            }
+            spin_loop();
+        }
+
+        // PMRCAP.CMSS: commands may transfer data from and to the region once its address is
+        // set, PMRMSC is not 8 byte aligned and written in halves
+        if pmrcap & (1 << 24) != 0 {
+            let pmrmsc = bar.bus_addr as u64 | 1 << 1;
+            self.set_reg32(NvmeRegs64::PMRMSC as u32, pmrmsc as u32);
+            self.set_reg32(NvmeRegs64::PMRMSC as u32 + 4, (pmrmsc >> 32) as u32);
+            // PMRSTS.CBAI
+            if self.get_reg32(NvmeRegs32::PMRSTS as u32) & (1 << 12) != 0 {
+                return Err(format!(
+                    "controller rejected the persistent memory region address {:#x}",
+                    bar.bus_addr
+                )
+                .into());
+            }
+        }
+        println!("Persistent memory region of {} bytes in BAR {bir}", bar.len);
+        Ok(PersistentMemoryRegion::new(
+            bar,
+            Arc::clone(&self.regs),
+            pmrcap,
+        ))
+    }
+
+    /// Disables the Persistent Memory Region, its contents are kept
+    pub fn disable_pmr(&mut self) {
+        self.set_reg32(NvmeRegs32::PMRCTL as u32, 0);
+    }
+
+    /// Identifies the controller and caches the result, see [`NvmeDevice::controller_info`]
+    pub fn identify_controller(&mut self) -> Result<ControllerInfo, Box<dyn Error>> {
+        println!("Trying to identify controller");
+        self.submit_and_complete_admin(NvmeCommand::identify_controller)?;
+
+        let data: IdentifyControllerData =
+            unsafe { *(self.buffer.virt as *const IdentifyControllerData) };
+
+        let npss = data.npss as usize;
+        let power_states = data.psd[..=npss]
+            .iter()
+            .map(|psd| PowerState {
+                max_power: psd.mp,
+                max_power_scale: psd.flags & 1 == 1,
+                non_operational: psd.flags & 2 == 2,
+                entry_latency: psd.enlat,
+                exit_latency: psd.exlat,
+                relative_read_throughput: psd.rrt & 0x1F,
+                relative_read_latency: psd.rrl & 0x1F,
+                relative_write_throughput: psd.rwt & 0x1F,
+                relative_write_latency: psd.rwl & 0x1F,
+            })
+            .collect();
+
+        let mut info = ControllerInfo {
+            vid: data.vid,
+            ssvid: data.ssvid,
+            serial: identify_string(&data.sn),
+            model: identify_string(&data.mn),
+            firmware: identify_string(&data.fr),
+            mdts: data.mdts,
+            cntlid: data.cntlid,
+            // NVMe 1.0 controllers don't report the version here
+            version: match data.ver {
+                0 => self.get_reg32(NvmeRegs32::VS as u32),
+                ver => ver,
+            },
+            rtd3e: data.rtd3e,
+            oaes: data.oaes,
+            oacs: data.oacs,
+            acl: data.acl,
+            aerl: data.aerl,
+            frmw: data.frmw,
+            lpa: data.lpa,
+            elpe: data.elpe,
+            wctemp: data.wctemp,
+            cctemp: data.cctemp,
+            hmpre: data.hmpre,
+            hmmin: data.hmmin,
+            tnvmcap: data.tnvmcap,
+            sqes: data.sqes,
+            cqes: data.cqes,
+            maxcmd: data.maxcmd,
+            nn: data.nn,
+            oncs: data.oncs,
+            fuses: data.fuses,
+            fna: data.fna,
+            vwc: data.vwc,
+            awun: data.awun,
+            awupf: data.awupf,
+            sgls: data.sgls,
+            dmrl: 0,
+            power_states,
+        };
+
+        println!(
+            "  - Model: {} Serial: {} Firmware: {}",
+            info.model, info.serial, info.firmware
+        );
+
+        // the NVM command set specific data only exists on NVMe 2.0 controllers
+        if self
+            .submit_and_complete_admin(|c_id, addr| {
+                NvmeCommand::identify_io_command_set_controller(c_id, addr, 0)
+            })
+            .is_ok()
+        {
+            // byte 1 is WZSL, DMRL is byte 3
+            info.dmrl = self.buffer[..][3];
+        }
+
+        self.controller = info.clone();
+        Ok(info)
+    }
+
+    /// Controller data from the last [`NvmeDevice::identify_controller`]
+    pub fn controller_info(&self) -> &ControllerInfo {
+        &self.controller
+    }
+
+    /// Reads the SMART / Health Information log page. `ns_id` 0xFFFF_FFFF returns the data of the
+    /// whole controller, other namespace ids require per namespace data (LPA bit 0).
+    pub fn smart_log(&mut self, ns_id: u32) -> Result<SmartLog, Box<dyn Error>> {
+        let data = self.get_log_page(LOG_SMART, ns_id, 0, std::mem::size_of::<SmartLogData>())?;
+        Ok(SmartLog::parse(&data))
+    }
+
+    /// Reads all valid entries of the Error Information log page, most recent error first.
+    /// The number of entries is taken from [`NvmeDevice::identify_controller`].
+    pub fn error_log(&mut self) -> Result<Vec<ErrorLogEntry>, Box<dyn Error>> {
+        let entries = self.controller.elpe as usize + 1;
+        let data = self.get_log_page(LOG_ERROR, 0xFFFF_FFFF, 0, entries * 64)?;
+
+        let mut log: Vec<_> = data
+            .chunks(64)
+            .map(ErrorLogEntry::parse)
+            .filter(|entry| entry.error_count != 0)
+            .collect();
+        log.sort_by_key(|entry| std::cmp::Reverse(entry.error_count));
+        Ok(log)
+    }
+
+    /// Reads the Firmware Slot Information log page
+    pub fn firmware_slot_log(&mut self) -> Result<FirmwareSlotLog, Box<dyn Error>> {
+        let data = self.get_log_page(LOG_FIRMWARE_SLOT, 0xFFFF_FFFF, 0, 512)?;
+        Ok(FirmwareSlotLog::parse(&data))
+    }
+
+    /// Reads the value of feature `F` selected by `select`
+    pub fn get_feature<F: Feature>(&mut self, select: FeatureSelect) -> Result<F, Box<dyn Error>> {
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, F::ID, select as u8, 0)
+        })?;
+        Ok(F::decode(entry.command_specific))
+    }
+
+    /// Changes feature `F` and returns dword 0 of the completion, which holds the allocated
+    /// queues for [`crate::features::NumberOfQueues`]
+    pub fn set_feature<F: Feature>(&mut self, value: &F) -> Result<u32, Box<dyn Error>> {
+        let value = value.encode();
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::set_features(c_id, addr, F::ID, value)
+        })?;
+        Ok(entry.command_specific)
+    }
+
+    /// Whether feature `fid` can be saved or changed and if it is namespace specific
+    pub fn feature_capabilities(&mut self, fid: u8) -> Result<FeatureCapabilities, Box<dyn Error>> {
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, fid, 3, 0)
+        })?;
+        Ok(FeatureCapabilities::decode(entry.command_specific))
+    }
+
+    /// Reads the over or under temperature threshold of `sensor`, 0 for the composite temperature
+    pub fn temperature_threshold(
+        &mut self,
+        sensor: u8,
+        under: bool,
+        select: FeatureSelect,
+    ) -> Result<TemperatureThreshold, Box<dyn Error>> {
+        let selector = TemperatureThreshold {
+            threshold: 0,
+            sensor,
+            under,
+        }
+        .encode();
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, TemperatureThreshold::ID, select as u8, selector)
+        })?;
+        // the threshold is the only field of the completion
+        Ok(TemperatureThreshold {
+            threshold: entry.command_specific as u16,
+            sensor,
+            under,
+        })
+    }
+
+    /// Reads the timestamp of the controller
+    pub fn timestamp(&mut self, select: FeatureSelect) -> Result<Timestamp, Box<dyn Error>> {
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, TIMESTAMP, select as u8, 0)
+        })?;
+        Ok(Timestamp::parse(&self.buffer[..8]))
+    }
+
+    /// Sets the timestamp of the controller to `millis` milliseconds since the Unix epoch
+    pub fn set_timestamp(&mut self, millis: u64) -> Result<(), Box<dyn Error>> {
+        self.buffer[..8].copy_from_slice(&(millis & 0xFFFF_FFFF_FFFF).to_le_bytes());
+        self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::set_features(c_id, addr, TIMESTAMP, 0)
+        })?;
+        Ok(())
+    }
+
+    /// Reads the Host Memory Buffer attributes
+    pub fn host_memory_buffer(
+        &mut self,
+        select: FeatureSelect,
+    ) -> Result<HostMemoryBuffer, Box<dyn Error>> {
+        let entry = self.submit_and_complete_admin(|c_id, addr| {
+            NvmeCommand::get_features(c_id, addr, HOST_MEMORY_BUFFER, select as u8, 0)
+        })?;
+        let dword =
+            |i: usize| u32::from_le_bytes(self.buffer[i * 4..i * 4 + 4].try_into().unwrap());
+        Ok(HostMemoryBuffer {
+            enabled: entry.command_specific & 1 == 1,
+            size: dword(0),
+            list_addr: (dword(2) as u64) << 32 | dword(1) as u64,
+            list_entries: dword(3),
+        })
+    }
 
-        for i in 1..512 {
-            dev.prp_list[i - 1] = (dev.buffer.phys + i * 4096) as u64;
+    /// Hands at least `bytes` of host memory, in huge pages, to the controller for its own use.
+    /// Controllers without DRAM use it e.g. for their mapping tables, see HMPRE and HMMIN in
+    /// [`ControllerInfo`]. The memory is kept until the device is dropped.
+    pub fn enable_host_memory_buffer(&mut self, bytes: usize) -> Result<(), Box<dyn Error>> {
+        if self.controller.hmpre == 0 {
+            return Err("controller does not support a host memory buffer".into());
+        }
+        if self.host_memory.is_some() {
+            self.disable_host_memory_buffer()?;
         }
 
-        println!("CAP: 0x{:x}", dev.get_reg64(NvmeRegs64::CAP as u64));
-        println!("VS: 0x{:x}", dev.get_reg32(NvmeRegs32::VS as u32));
-        println!("CC: 0x{:x}", dev.get_reg32(NvmeRegs32::CC as u32));
+        // each 16 byte descriptor describes one huge page
+        let pages = bytes.div_ceil(HUGE_PAGE_SIZE);
+        if pages == 0 || pages > PAGE_SIZE / 16 {
+            return Err(format!("unsupported host memory buffer size of {bytes} bytes").into());
+        }
+        let mut descriptors: Dma<u8> = Dma::allocate(PAGE_SIZE)?;
+        let mut buffers = Vec::with_capacity(pages);
+        for i in 0..pages {
+            let buffer: Dma<u8> = Dma::allocate(HUGE_PAGE_SIZE)?;
+            let descriptor = &mut descriptors[i * 16..i * 16 + 16];
+            descriptor[..8].copy_from_slice(&(buffer.phys as u64).to_le_bytes());
+            descriptor[8..12].copy_from_slice(&((buffer.size / PAGE_SIZE) as u32).to_le_bytes());
+            buffers.push(buffer);
+        }
 
-        println!("Disabling controller");
-        // Set Enable bit to 0
-        let ctrl_config = dev.get_reg32(NvmeRegs32::CC as u32) & 0xFFFF_FFFE;
-        dev.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
+        self.host_memory = Some(HostMemory {
+            descriptors,
+            buffers,
+        });
+        if let Err(e) = self.set_host_memory_buffer(true, false) {
+            self.host_memory = None;
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        // Wait for not ready
-        loop {
-            let csts = dev.get_reg32(NvmeRegs32::CSTS as u32);
-            if csts & 1 == 1 {
-                spin_loop();
-            } else {
-                break;
-            }
+    /// Takes the host memory buffer back from the controller
+    pub fn disable_host_memory_buffer(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.host_memory.is_none() {
+            return Ok(());
         }
+        self.set_host_memory_buffer(false, false)?;
+        self.host_memory = None;
+        Ok(())
+    }
 
-        // Configure Admin Queues
-        dev.set_reg64(NvmeRegs64::ASQ as u32, dev.admin_sq.get_addr() as u64);
-        dev.set_reg64(NvmeRegs64::ACQ as u32, dev.admin_cq.get_addr() as u64);
-        dev.set_reg32(
-            NvmeRegs32::AQA as u32,
-            (QUEUE_LENGTH as u32 - 1) << 16 | (QUEUE_LENGTH as u32 - 1),
+    // `memory_return` hands the same buffer back after a reset
+    fn set_host_memory_buffer(
+        &mut self,
+        enable: bool,
+        memory_return: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(memory) = &self.host_memory else {
+            return Ok(());
+        };
+        let (size, list, entries) = (
+            memory.size(),
+            memory.descriptors.phys as u64,
+            memory.buffers.len() as u32,
         );
+        self.submit_and_complete_admin(|c_id, _| {
+            NvmeCommand::set_host_memory_buffer(c_id, enable, memory_return, size, list, entries)
+        })?;
+        Ok(())
+    }
 
-        // Configure other stuff
-        // TODO: check css values
-        let mut cc = dev.get_reg32(NvmeRegs32::CC as u32);
-        // mask out reserved stuff
-        cc &= 0xFF00_000F;
-        // Set Completion (2^4 = 16 Bytes) and Submission Entry (2^6 = 64 Bytes) sizes
-        cc |= (4 << 20) | (6 << 16);
+    /// Enables SMART / health critical warnings and the supported notices as asynchronous events
+    /// and keeps Asynchronous Event Requests outstanding to receive them, see
+    /// [`NvmeDevice::poll_async_events`]. Stays enabled across controller resets.
+    pub fn enable_async_events(&mut self) -> Result<(), Box<dyn Error>> {
+        self.set_feature(&AsyncEventConfig {
+            // spare, temperature, reliability, read only and volatile memory backup
+            critical_warnings: 0x1F,
+            // namespace attribute and firmware activation notices
+            notices: self.controller.oaes & (1 << 8 | 1 << 9),
+        })?;
+        self.async_events = true;
+        self.submit_async_event_requests()
+    }
 
-        // Set Memory Page Size
-        // let mpsmax = ((dev.get_reg64(NvmeRegs64::CAP as u64) >> 52) & 0xF) as u32;
-        // cc |= (mpsmax << 7);
-         println!("MPS {}", (cc >> 7) & 0xF);
-        dev.set_reg32(NvmeRegs32::CC as u32, cc);
+    // keeps up to AERL + 1 requests outstanding, leaving admin queue slots for other commands
+    fn submit_async_event_requests(&mut self) -> Result<(), Box<dyn Error>> {
+        let requests = (self.controller.aerl as usize + 1)
+            .min(MAX_ASYNC_EVENT_REQUESTS)
+            .min(self.admin_sq.len - 1);
+        while self.aers.len() < requests {
+            let cid = (AER_CID..).find(|cid| !self.aers.contains(cid)).unwrap();
+            self.submit_admin(NvmeCommand::async_event_req(cid))?;
+            self.aers.push(cid);
+        }
+        Ok(())
+    }
 
-        // Enable the controller
-        println!("Enabling controller");
-        let ctrl_config = dev.get_reg32(NvmeRegs32::CC as u32) | 1;
-        dev.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
+    /// Returns the asynchronous events reported since the last call without waiting for new
+    /// ones. The log page of every event is read, which clears it so the controller reports
+    /// further events of its type, and the request is resubmitted.
+    pub fn poll_async_events(&mut self) -> Vec<AsyncEvent> {
+        while let Some((head, entry, _)) = self.admin_cq.complete() {
+            self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
+            self.admin_sq.head = entry.sq_head as usize;
+            // anything else is the late completion of a timed out command
+            if self.aers.contains(&{ entry.c_id }) {
+                self.aer_completions.push(entry);
+            }
+        }
 
-        // wait for ready
-        loop {
-            let csts = dev.get_reg32(NvmeRegs32::CSTS as u32);
-            if csts & 1 == 0 {
-                spin_loop();
-            } else {
-                break;
+        let mut events = Vec::new();
+        for entry in std::mem::take(&mut self.aer_completions) {
+            self.aers.retain(|&cid| cid != entry.c_id);
+            if let Err(e) = NvmeError::check(&entry) {
+                eprintln!("asynchronous event request failed: {e}");
+                continue;
+            }
+
+            let mut event = AsyncEvent::from_completion(entry.command_specific);
+            match self.async_event_log(event.log_page) {
+                Ok(log) => event.log = Some(log),
+                Err(e) => eprintln!("reading log page {} failed: {e}", event.log_page),
+            }
+            events.push(event);
+        }
+        // also replaces requests that did not fit into the admin queue before
+        if self.async_events {
+            if let Err(e) = self.submit_async_event_requests() {
+                eprintln!("resubmitting asynchronous event requests failed: {e}");
             }
         }
+        events
+    }
 
-        let q_id = dev.q_id;
-        let addr = dev.io_cq.get_addr();
-        println!("Requesting i/o completion queue");
-        let comp = dev.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::create_io_completion_queue(c_id, q_id, addr, (QUEUE_LENGTH - 1) as u16)
-        })?;
-        let addr = dev.io_sq.get_addr();
-        println!("Requesting i/o submission queue");
-        let comp = dev.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::create_io_submission_queue(
-                c_id,
-                q_id,
-                addr,
-                (QUEUE_LENGTH - 1) as u16,
-                q_id,
+    fn async_event_log(&mut self, lid: u8) -> Result<AsyncEventLog, Box<dyn Error>> {
+        Ok(match lid {
+            LOG_ERROR => AsyncEventLog::Error(self.error_log()?),
+            LOG_SMART => AsyncEventLog::Smart(self.smart_log(0xFFFF_FFFF)?),
+            LOG_FIRMWARE_SLOT => AsyncEventLog::FirmwareSlot(self.firmware_slot_log()?),
+            LOG_CHANGED_NAMESPACES => {
+                AsyncEventLog::Raw(self.get_log_page(lid, 0xFFFF_FFFF, 0, 4096)?)
+            }
+            _ => AsyncEventLog::Raw(self.get_log_page(lid, 0xFFFF_FFFF, 0, 512)?),
+        })
+    }
+
+    /// Reads `len` bytes of log page `lid` starting at byte `offset`, both have to be multiples of
+    /// 4. Transfers larger than the internal buffer or the maximum transfer size are split into
+    /// multiple commands. Those and non-zero offsets require extended data for Get Log Page (LPA
+    /// bit 2).
+    pub fn get_log_page(
+        &mut self,
+        lid: u8,
+        ns_id: u32,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        if len == 0 || !len.is_multiple_of(4) || !offset.is_multiple_of(4) {
+            return Err(format!(
+                "log page transfers have to be dword aligned, got {len} bytes at offset {offset}"
             )
-        })?;
-        dev.q_id += 1;
+            .into());
+        }
 
-        let offset_sq = 0x1000 + ((4 << dev.dstrd) * (2 * dev.q_id) as usize);
-        let offset_cq = 0x1000 + ((4 << dev.dstrd) * (2 * dev.q_id + 1) as usize);
+        // without extended data, NUMD is limited to 12 bits and offsets are not supported
+        let extended = self.controller.lpa & (1 << 2) != 0;
+        let chunk_size = if extended {
+            self.max_transfer_size().min(self.buffer.size)
+        } else {
+            self.max_transfer_size().min(4096 * 4)
+        };
+        if !extended && (offset != 0 || len > chunk_size) {
+            return Err(format!(
+                "controller does not support log page offsets to read {len} bytes at offset {offset}"
+            )
+            .into());
+        }
 
-        dev.io_sq.doorbell = dev.addr as usize + offset_sq;
-        dev.io_cq.doorbell = dev.addr as usize + offset_cq;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let chunk = (len - data.len()).min(chunk_size);
+            let numd = (chunk / 4 - 1) as u32;
+            let lpo = offset + data.len() as u64;
+            // the list page of the i/o queue tail is free, the synchronous i/o functions
+            // complete all their commands before returning
+            let (ptr0, ptr1) = self
+                .prp_lists
+                .prps(self.io_sq.tail, self.buffer.phys as u64, chunk);
+            self.submit_and_complete_admin(|c_id, _| {
+                NvmeCommand::get_log_page(c_id, ns_id, numd, ptr0, ptr1, lid, 0, lpo)
+            })?;
+            data.extend_from_slice(&self.buffer[..chunk]);
+        }
+        Ok(data)
+    }
 
-        Ok(dev)
+    /// Whether the controller has a volatile write cache that needs to be flushed
+    pub fn has_volatile_write_cache(&self) -> bool {
+        self.controller.has_volatile_write_cache()
     }
 
-    pub fn identify_controller(&mut self) -> Result<(), Box<dyn Error>> {
-        println!("Trying to identify controller");
-        let _entry = self.submit_and_complete_admin(NvmeCommand::identify_controller);
+    /// Commits the volatile write cache of namespace `ns` to non-volatile media.
+    /// Does nothing if the controller has no volatile write cache.
+    pub fn flush(&mut self, ns: &NvmeNamespace) -> Result<(), Box<dyn Error>> {
+        if !self.has_volatile_write_cache() {
+            return Ok(());
+        }
+        let tail = self
+            .io_sq
+            .submit(NvmeCommand::flush(self.io_sq.tail as u16, ns.id));
+        self.stats.submissions += 1;
+        self.io_sq.doorbell.ring(tail as u32);
+        self.io_sq.head = self.complete_io(1)? as usize;
+        Ok(())
+    }
 
-        println!("Dumping identify controller");
-        let mut serial = String::new();
-        let data = &self.buffer;
+    /// Largest transfer of a single I/O command in bytes, larger transfers are split.
+    /// Limited by the controller's MDTS and what a single PRP list can describe.
+    pub fn max_transfer_size(&self) -> usize {
+        let mpsmin = (self.get_reg64(NvmeRegs64::CAP as u64) >> 48) & 0xF;
+        match self.controller.max_transfer_size(4096 << mpsmin) {
+            Some(mdts) => mdts.min(MAX_PRP_TRANSFER),
+            None => MAX_PRP_TRANSFER,
+        }
+    }
 
-        for &b in &data[4..24] {
-            if b == 0 {
-                break;
-            }
-            serial.push(b as char);
+    /// Returns the maximum number of ranges per Dataset Management command
+    pub fn dsm_range_limit(&self) -> Result<usize, Box<dyn Error>> {
+        if !self.controller.supports_dataset_management() {
+            return Err("controller does not support Dataset Management".into());
         }
+        Ok(match self.controller.dmrl {
+            0 => DSM_MAX_RANGES,
+            dmrl => (dmrl as usize).min(DSM_MAX_RANGES),
+        })
+    }
 
-        let mut model = String::new();
-        for &b in &data[24..64] {
-            if b == 0 {
-                break;
-            }
-            model.push(b as char);
+    /// Deallocates (trims) `ranges` of namespace `ns`
+    pub fn deallocate(
+        &mut self,
+        ns: &NvmeNamespace,
+        ranges: &[LbaRange],
+    ) -> Result<(), Box<dyn Error>> {
+        if ranges.iter().any(|range| range.blocks == 0) {
+            return Err(invalid_input("LBA ranges must not be empty").into());
         }
+        let limit = self.dsm_range_limit()?;
 
-        let mut firmware = String::new();
-        for &b in &data[64..72] {
-            if b == 0 {
-                break;
+        for chunk in ranges.chunks(limit) {
+            write_dsm_ranges(&mut self.buffer[0..4096], chunk);
+            let entry = NvmeCommand::dataset_management(
+                self.io_sq.tail as u16,
+                ns.id,
+                (chunk.len() - 1) as u8,
+                self.buffer.phys as u64,
+                0,
+                true,
+            );
+            let tail = self.io_sq.submit(entry);
+            self.stats.submissions += 1;
+            self.io_sq.doorbell.ring(tail as u32);
+            self.io_sq.head = self.complete_io(1)? as usize;
+        }
+        Ok(())
+    }
+
+    /// Requests to abort command `cid` of submission queue `sqid`.
+    /// Returns whether the controller aborted the command, it may complete normally otherwise.
+    /// Fails with a [`TimeoutError`] if the Abort command times out as well, the controller
+    /// should be reset then.
+    pub fn abort(&mut self, sqid: u16, cid: u16) -> Result<bool, Box<dyn Error>> {
+        let c_id = self.admin_sq.tail as u16;
+        self.submit_admin(NvmeCommand::abort(c_id, sqid, cid))?;
+
+        match self.admin_wait(c_id) {
+            Some(entry) => {
+                NvmeError::check(&entry)?;
+                Ok(entry.command_specific & 1 == 0)
+            }
+            None => Err(TimeoutError {
+                sqid: 0,
+                cid: c_id,
+                timeout: self.admin_timeout,
             }
-            firmware.push(b as char);
+            .into()),
         }
+    }
 
-        println!(
-            "  - Model: {} Serial: {} Firmware: {}",
-            model.trim(),
-            serial.trim(),
-            firmware.trim()
+    /// Recreates queue pair `q_pair` with its id after a controller reset.
+    /// Commands in flight are lost, their slots can be reused right away.
+    pub fn recreate_io_queue_pair(
+        &mut self,
+        q_pair: &mut NvmeQueuePair<T>,
+    ) -> Result<(), QueueError> {
+        println!("Recreating i/o queue pair with id {}", q_pair.id);
+        q_pair.sub_queue.reset();
+        q_pair.comp_queue.reset();
+        q_pair.orphans.clear();
+        q_pair.ids.reset();
+        if q_pair.sub_queue.doorbell.has_shadow() {
+            q_pair
+                .sub_queue
+                .doorbell
+                .set_shadow(self.doorbell_buffers.clone(), 0);
+            q_pair
+                .comp_queue
+                .doorbell
+                .set_shadow(self.doorbell_buffers.clone(), 0);
+        }
+        let cq = (
+            q_pair.comp_queue.get_addr(),
+            q_pair.comp_queue.is_contiguous(),
         );
-
+        let sq = (
+            q_pair.sub_queue.get_addr(),
+            q_pair.sub_queue.is_contiguous(),
+        );
+        self.create_io_queues(q_pair.id, cq, sq, q_pair.sub_queue.len, q_pair.priority)?;
         Ok(())
     }
 
-    // 1 to 1 Submission/Completion Queue Mapping
-    pub fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair<T>, QueueError> {
+    /// Creates an I/O queue pair of `len` entries with 1 to 1 submission / completion queue
+    /// mapping, whose submission queue is served as `priority` under weighted round robin.
+    /// Fails if `len` exceeds [`NvmeDevice::max_queue_length`] or the controller allocated no
+    /// further queues, see [`NvmeDevice::max_io_queue_pairs`].
+    pub fn create_io_queue_pair(
+        &mut self,
+        len: usize,
+        priority: QueuePriority,
+    ) -> Result<NvmeQueuePair<T>, QueueError> {
         let q_id = self.q_id;
+        if q_id as usize > self.max_io_queue_pairs() {
+            return Err(QueueError {
+                message: format!(
+                    "controller allocated only {} i/o queue pairs",
+                    self.max_io_queue_pairs()
+                ),
+            });
+        }
+        if !(2..=self.max_queue_length()).contains(&len) {
+            return Err(QueueError {
+                message: format!(
+                    "invalid queue length {len}, supported are 2 to {} entries",
+                    self.max_queue_length()
+                ),
+            });
+        }
         println!("Requesting i/o queue pair with id {q_id}");
 
+        let cmb = self.cmb.as_ref();
+        let prp_lists = match cmb.filter(|_| self.cmb_usage.prp_lists) {
+            Some(cmb) => PrpLists::new_in(cmb, len)?,
+            None => PrpLists::new(len)?,
+        };
         let offset = 0x1000 + ((4 << self.dstrd) * (2 * q_id + 1) as usize);
-        assert!(offset <= self.len - 4, "SQ doorbell offset out of bounds");
-
-        let dbl = self.addr as usize + offset;
+        let dbl = Doorbell::new(Arc::clone(&self.regs), offset);
 
-        let comp_queue = NvmeCompQueue::new(len, dbl)?;
-        let comp = self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::create_io_completion_queue(
-                c_id,
-                q_id,
-                comp_queue.get_addr(),
-                (len - 1) as u16,
-            )
-        })?;
+        let contiguous = self.requires_contiguous_queues();
+        let mut comp_queue = NvmeCompQueue::new(len, contiguous, dbl)?;
 
-        let dbl = self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * q_id) as usize);
-        let sub_queue = NvmeSubQueue::new(len, dbl)?;
-        let comp = self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::create_io_submission_queue(
-                c_id,
-                q_id,
-                sub_queue.get_addr(),
-                (len - 1) as u16,
-                q_id,
-            )
-        })?;
+        let offset = 0x1000 + ((4 << self.dstrd) * (2 * q_id) as usize);
+        let dbl = Doorbell::new(Arc::clone(&self.regs), offset);
+        let mut sub_queue = match cmb.filter(|_| self.cmb_usage.submission_queues) {
+            Some(cmb) => NvmeSubQueue::new_in(cmb, len, dbl)?,
+            None => NvmeSubQueue::new(len, contiguous, dbl)?,
+        };
+        if let Some(buffers) = &self.doorbell_buffers {
+            comp_queue.doorbell.set_shadow(Some(Arc::clone(buffers)), 0);
+            sub_queue.doorbell.set_shadow(Some(Arc::clone(buffers)), 0);
+        }
+        self.create_io_queues(
+            q_id,
+            (comp_queue.get_addr(), comp_queue.is_contiguous()),
+            (sub_queue.get_addr(), sub_queue.is_contiguous()),
+            len,
+            priority,
+        )?;
 
         self.q_id += 1;
         Ok(NvmeQueuePair {
             id: q_id,
+            priority,
             sub_queue,
             comp_queue,
             pending: Mutex::new(HashMap::new()),
+            orphans: Arc::new(Orphans::default()),
+            ids: CommandIds::new(len),
+            prp_lists,
+            sgl_segments: None,
+            sgl_support: self.controller.supports_sgl(),
+            sgl_dword_alignment: self.controller.sgl_requires_dword_alignment(),
+            sgl_bit_bucket: self.controller.supports_sgl_bit_bucket(),
+            max_transfer: self.max_transfer_size(),
             _type: PhantomData,
         })
     }
 
     pub fn delete_io_queue_pair(&mut self, qpair: NvmeQueuePair<T>) -> Result<(), Box<dyn Error>> {
-        println!("Deleting i/o queue pair with id {}", qpair.id);
+        self.delete_io_queues(qpair.id)
+    }
+
+    /// Deletes the submission and then the completion queue `q_id`
+    pub fn delete_io_queues(&mut self, q_id: u16) -> Result<(), Box<dyn Error>> {
+        println!("Deleting i/o queue pair with id {}", q_id);
         self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::delete_io_submission_queue(c_id, qpair.id)
+            NvmeCommand::delete_io_submission_queue(c_id, q_id)
         })?;
         self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::delete_io_completion_queue(c_id, qpair.id)
+            NvmeCommand::delete_io_completion_queue(c_id, q_id)
         })?;
         Ok(())
     }
 
+    /// Whether [`NvmeDevice::shutdown`] was called since initialization or the last reset
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Deletes the I/O queue pair of the synchronous functions and notifies the controller of a
+    /// normal shutdown (CC.SHN), waiting until it completed (CSTS.SHST) so the volatile write
+    /// cache is persisted. Done on drop unless called before. All other I/O queue pairs have
+    /// to be deleted first, the controller processes no commands until it is reset.
+    ///
+    /// A controller that reported a fatal status (CSTS.CFS) or is not ready only gets notified
+    /// and gets a second to complete the shutdown, as its admin commands would all time out.
+    /// Nothing is done if the device is gone.
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.shutdown_deleting(std::iter::empty())
+    }
+
+    /// Like [`NvmeDevice::shutdown`], but deletes the I/O queue pairs `q_ids` first. Queues are
+    /// only deleted until the first deletion fails.
+    pub(crate) fn shutdown_deleting(
+        &mut self,
+        q_ids: impl IntoIterator<Item = u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.shut_down {
+            return Ok(());
+        }
+        let csts = self.get_reg32(NvmeRegs32::CSTS as u32);
+        if csts == u32::MAX {
+            // reads of a removed device return all ones
+            self.shut_down = true;
+            return Err(format!("device {} is gone", self.pci_addr).into());
+        }
+
+        let failed = csts & (1 << 1) != 0 || csts & 1 == 0;
+        if !failed {
+            self.shutting_down = true;
+            let deleted = q_ids.into_iter().chain([1]).try_for_each(|q_id| {
+                self.delete_io_queues(q_id)
+                    .map_err(|e| format!("deleting i/o queue pair with id {q_id} failed: {e}"))
+            });
+            match deleted {
+                Err(e) => eprintln!("{e}"),
+                Ok(()) => {
+                    if let Err(e) = self.disable_host_memory_buffer() {
+                        eprintln!("disabling the host memory buffer failed: {e}");
+                    }
+                }
+            }
+            self.shutting_down = false;
+        }
+
+        println!("Shutting down controller");
+        let cc = self.get_reg32(NvmeRegs32::CC as u32) & !(0b11 << 14);
+        self.set_reg32(NvmeRegs32::CC as u32, cc | 0b01 << 14);
+        self.shut_down = true;
+
+        // RTD3E is the expected shutdown time, CAP.TO is used if it is shorter or not reported
+        let mut timeout =
+            Duration::from_micros(self.controller.rtd3e as u64).max(self.ready_timeout());
+        if failed {
+            timeout = timeout.min(FAILED_SHUTDOWN_TIMEOUT);
+        }
+        let deadline = Instant::now() + timeout;
+        while (self.get_reg32(NvmeRegs32::CSTS as u32) >> 2) & 0b11 != 0b10 {
+            if Instant::now() >= deadline {
+                return Err(
+                    format!("controller did not complete shutdown within {timeout:?}").into(),
+                );
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Shuts the controller down and gives a device taken over through sysfs back to the driver
+    /// it was bound to before [`NvmeDevice::init`], restoring the bus master and interrupt
+    /// disable bits of its command register. Devices bound to `vfio-pci` stay bound. The device
+    /// must not be used afterwards.
+    pub fn release(&mut self) -> Result<(), Box<dyn Error>> {
+        // the kernel driver resets the controller anyway, so give it back regardless
+        let shutdown = self.shutdown();
+        if let Some(binding) = self.binding.take() {
+            println!("Releasing device {}", self.pci_addr);
+            binding.restore()?;
+        }
+        shutdown
+    }
+
+    /// Whether dropping the device calls [`NvmeDevice::release`] instead of only shutting it
+    /// down. Disabled by default.
+    pub fn set_release_on_drop(&mut self, enable: bool) {
+        self.release_on_drop = enable;
+    }
+
     pub fn identify_namespace_list(&mut self, base: u32) -> Vec<u32> {
         self.submit_and_complete_admin(|c_id, addr| {
             NvmeCommand::identify_namespace_list(c_id, addr, base)
@@ -584,8 +2062,12 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
         let size = namespace_data.nsze;
         let blocks = namespace_data.ncap;
 
-        // figure out block size
-        let flba_idx = (namespace_data.flbas & 0xF) as usize;
+        // figure out block size, bits 6:5 of FLBAS extend the index beyond 16 formats
+        let flba_idx = if namespace_data.nlbaf < 16 {
+            (namespace_data.flbas & 0xF) as usize
+        } else {
+            ((namespace_data.flbas & 0xF) | ((namespace_data.flbas >> 5) & 0x3) << 4) as usize
+        };
         let flba_data = (namespace_data.lba_format_support[flba_idx] >> 16) & 0xFF;
         let block_size = if !(9..32).contains(&flba_data) {
             0
@@ -605,56 +2087,78 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
         namespace
     }
 
-    // TODO: currently namespace 1 is hardcoded
-    pub fn write(&mut self, data: &impl DmaSlice, mut lba: u64) -> Result<(), Box<dyn Error>> {
-        for chunk in data.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64).div_ceil(512);
-            self.namespace_io(1, blocks, lba, chunk.phys_addr as u64, true)?;
-            lba += blocks;
+    pub fn write(
+        &mut self,
+        ns: &NvmeNamespace,
+        data: &impl DmaSlice,
+        mut lba: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        check_buffer(ns, data, lba, true)?;
+        for chunk in data.chunks(self.chunk_size(ns)?) {
+            self.namespace_io(ns, &chunk, lba, true)?;
+            lba += chunk.slice.len() as u64 / ns.block_size;
         }
 
         Ok(())
     }
 
-    pub fn read(&mut self, dest: &impl DmaSlice, mut lba: u64) -> Result<(), Box<dyn Error>> {
-        // let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in dest.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64).div_ceil(512);
-            self.namespace_io(1, blocks, lba, chunk.phys_addr as u64, false)?;
-            lba += blocks;
+    pub fn read(
+        &mut self,
+        ns: &NvmeNamespace,
+        dest: &impl DmaSlice,
+        mut lba: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        check_buffer(ns, dest, lba, true)?;
+        for chunk in dest.chunks(self.chunk_size(ns)?) {
+            self.namespace_io(ns, &chunk, lba, false)?;
+            lba += chunk.slice.len() as u64 / ns.block_size;
         }
         Ok(())
     }
 
-    pub fn write_copied(&mut self, data: &[u8], mut lba: u64) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in data.chunks(128 * 4096) {
+    pub fn write_copied(
+        &mut self,
+        ns: &NvmeNamespace,
+        data: &[u8],
+        mut lba: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        ns.transfer_blocks(lba, data.len())?;
+        for chunk in data.chunks(self.chunk_size(ns)?) {
             self.buffer[..chunk.len()].copy_from_slice(chunk);
-            let blocks = (chunk.len() as u64).div_ceil(ns.block_size);
-            self.namespace_io(1, blocks, lba, self.buffer.phys as u64, true)?;
-            lba += blocks;
+            self.write(ns, &self.buffer.slice(0..chunk.len()), lba)?;
+            lba += chunk.len() as u64 / ns.block_size;
         }
 
         Ok(())
     }
 
-    pub fn read_copied(&mut self, dest: &mut [u8], mut lba: u64) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in dest.chunks_mut(128 * 4096) {
-            let blocks = (chunk.len() as u64).div_ceil(ns.block_size);
-            self.namespace_io(1, blocks, lba, self.buffer.phys as u64, false)?;
-            lba += blocks;
+    pub fn read_copied(
+        &mut self,
+        ns: &NvmeNamespace,
+        dest: &mut [u8],
+        mut lba: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        ns.transfer_blocks(lba, dest.len())?;
+        for chunk in dest.chunks_mut(self.chunk_size(ns)?) {
+            self.read(ns, &self.buffer.slice(0..chunk.len()), lba)?;
+            lba += chunk.len() as u64 / ns.block_size;
             chunk.copy_from_slice(&self.buffer[..chunk.len()]);
         }
         Ok(())
     }
 
+    // Largest multiple of the block size of `ns` a single command can transfer, commands through
+    // the copy buffer are limited to its size
+    fn chunk_size(&self, ns: &NvmeNamespace) -> std::io::Result<usize> {
+        chunk_size(ns, self.max_transfer_size().min(HUGE_PAGE_SIZE))
+    }
+
     pub fn complete(&mut self) -> Option<(usize, NvmeCompletion, usize)> {
         self.io_cq.complete()
     }
 
-    pub fn get_c_doorbell(&self) -> usize {
-        self.io_cq.doorbell
+    pub fn get_c_doorbell(&self) -> &Doorbell {
+        &self.io_cq.doorbell
     }
 
     pub fn set_sq_head(&mut self, head: usize) {
@@ -664,104 +2168,60 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
     fn submit_io(
         &mut self,
         ns: &NvmeNamespace,
-        addr: u64,
-        blocks: u64,
+        chunk: &DmaChunk<'_, u8>,
         lba: u64,
         write: bool,
     ) -> Option<usize> {
+        let blocks = chunk.slice.len() as u64 / ns.block_size;
         assert!(blocks > 0);
         assert!(blocks <= 0x1_0000);
-        let q_id = 1;
-
-        let bytes = blocks * ns.block_size;
-        let ptr1 = if bytes <= 4096 {
-            0
-        } else if bytes <= 8192 {
-            addr + 4096 // self.page_size
-        } else {
-            // idk if this works
-            let offset = (addr - self.buffer.phys as u64) / 8;
-            self.prp_list.phys as u64 + offset
-        };
 
-        let entry = if write {
-            NvmeCommand::io_write(
-                self.io_sq.tail as u16,
-                ns.id,
-                lba,
-                blocks as u16 - 1,
-                addr,
-                ptr1,
-            )
-        } else {
-            NvmeCommand::io_read(
-                self.io_sq.tail as u16,
-                ns.id,
-                lba,
-                blocks as u16 - 1,
-                addr,
-                ptr1,
-            )
-        };
-        self.io_sq.submit_checked(entry)
+        let c_id = self.io_sq.tail as u16;
+        let dptr = self.prp_lists.chunk_prps(c_id as usize, chunk);
+        self.io_sq
+            .submit_checked(rw_command(c_id, ns, lba, blocks, dptr, write, false))
     }
 
-    fn complete_io(&mut self, step: u64) -> Option<u16> {
-        let q_id = 1;
-
+    fn complete_io(&mut self, step: u64) -> Result<u16, NvmeError> {
         let (tail, c_entry, _) = self.io_cq.complete_n(step as usize);
-        self.write_reg_idx(NvmeArrayRegs::CQyHDBL, q_id as u16, tail as u32);
-
-        let status = c_entry.status >> 1;
-        if status != 0 {
-            eprintln!(
-                "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                status,
-                status & 0xFF,
-                (status >> 8) & 0x7
-            );
-            eprintln!("{:?}", c_entry);
-            return None;
-        }
+        self.io_cq.doorbell.ring(tail as u32);
+
+        NvmeError::check(&c_entry)?;
         self.stats.completions += 1;
-        Some(c_entry.sq_head)
+        Ok(c_entry.sq_head)
     }
 
     pub fn batched_write(
         &mut self,
-        ns_id: u32,
+        ns: &NvmeNamespace,
         data: &[u8],
         mut lba: u64,
         batch_len: u64,
     ) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&ns_id).unwrap();
-        let block_size = 512;
-        let q_id = 1;
+        ns.transfer_blocks(lba, data.len())?;
+        let max_blocks = self.chunk_size(ns)? as u64 / ns.block_size;
 
-        for chunk in data.chunks(HUGE_PAGE_SIZE) {
+        for chunk in data.chunks(HUGE_PAGE_SIZE / ns.block_size as usize * ns.block_size as usize) {
             self.buffer[..chunk.len()].copy_from_slice(chunk);
             let tail = self.io_sq.tail;
 
-            let batch_len = std::cmp::min(batch_len, chunk.len() as u64 / block_size);
-            let batch_size = chunk.len() as u64 / batch_len;
-            let blocks = batch_size / ns.block_size;
-
-            for i in 0..batch_len {
-                if let Some(tail) = self.submit_io(
-                    &ns,
-                    self.buffer.phys as u64 + i * batch_size,
-                    blocks,
-                    lba,
-                    true,
-                ) {
+            let chunk_blocks = chunk.len() as u64 / ns.block_size;
+            let blocks = chunk_blocks.div_ceil(batch_len.max(1)).min(max_blocks);
+            let batch_len = chunk_blocks.div_ceil(blocks);
+            let batch_size = blocks * ns.block_size;
+
+            let buffer = self.buffer.slice(0..chunk.len());
+            for part in buffer.chunks(batch_size as usize) {
+                let blocks = part.slice.len() as u64 / ns.block_size;
+                if let Some(tail) = self.submit_io(ns, &part, lba, true) {
                     self.stats.submissions += 1;
-                    self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+                    self.io_sq.doorbell.ring(tail as u32);
                 } else {
                     eprintln!("tail: {tail}, batch_len: {batch_len}, batch_size: {batch_size}, blocks: {blocks}");
                 }
                 lba += blocks;
             }
-            self.io_sq.head = self.complete_io(batch_len).unwrap() as usize;
+            self.io_sq.head = self.complete_io(batch_len)? as usize;
         }
 
         Ok(())
@@ -769,38 +2229,36 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
 
     pub fn batched_read(
         &mut self,
-        ns_id: u32,
+        ns: &NvmeNamespace,
         data: &mut [u8],
         mut lba: u64,
         batch_len: u64,
     ) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&ns_id).unwrap();
-        let block_size = 512;
-        let q_id = 1;
+        ns.transfer_blocks(lba, data.len())?;
+        let max_blocks = self.chunk_size(ns)? as u64 / ns.block_size;
 
-        for chunk in data.chunks_mut(HUGE_PAGE_SIZE) {
+        for chunk in
+            data.chunks_mut(HUGE_PAGE_SIZE / ns.block_size as usize * ns.block_size as usize)
+        {
             let tail = self.io_sq.tail;
 
-            let batch_len = std::cmp::min(batch_len, chunk.len() as u64 / block_size);
-            let batch_size = chunk.len() as u64 / batch_len;
-            let blocks = batch_size / ns.block_size;
-
-            for i in 0..batch_len {
-                if let Some(tail) = self.submit_io(
-                    &ns,
-                    self.buffer.phys as u64 + i * batch_size,
-                    blocks,
-                    lba,
-                    false,
-                ) {
+            let chunk_blocks = chunk.len() as u64 / ns.block_size;
+            let blocks = chunk_blocks.div_ceil(batch_len.max(1)).min(max_blocks);
+            let batch_len = chunk_blocks.div_ceil(blocks);
+            let batch_size = blocks * ns.block_size;
+
+            let buffer = self.buffer.slice(0..chunk.len());
+            for part in buffer.chunks(batch_size as usize) {
+                let blocks = part.slice.len() as u64 / ns.block_size;
+                if let Some(tail) = self.submit_io(ns, &part, lba, false) {
                     self.stats.submissions += 1;
-                    self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+                    self.io_sq.doorbell.ring(tail as u32);
                 } else {
                     eprintln!("tail: {tail}, batch_len: {batch_len}, batch_size: {batch_size}, blocks: {blocks}");
                 }
                 lba += blocks;
             }
-            self.io_sq.head = self.complete_io(batch_len).unwrap() as usize;
+            self.io_sq.head = self.complete_io(batch_len)? as usize;
             chunk.copy_from_slice(&self.buffer[..chunk.len()]);
         }
         Ok(())
@@ -809,76 +2267,80 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
     #[inline(always)]
     fn namespace_io(
         &mut self,
-        ns_id: u32,
-        blocks: u64,
+        ns: &NvmeNamespace,
+        chunk: &DmaChunk<'_, u8>,
         lba: u64,
-        addr: u64,
         write: bool,
     ) -> Result<(), Box<dyn Error>> {
-        assert!(blocks > 0);
-        assert!(blocks <= 0x1_0000);
-
-        let q_id = 1;
-
-        let bytes = blocks * 512;
-        let ptr1 = if bytes <= 4096 {
-            0
-        } else if bytes <= 8192 {
-            // self.buffer.phys as u64 + 4096 // self.page_size
-            addr + 4096 // self.page_size
-        } else {
-            self.prp_list.phys as u64
-        };
-
-        let entry = if write {
-            NvmeCommand::io_write(
-                self.io_sq.tail as u16,
-                ns_id,
-                lba,
-                blocks as u16 - 1,
-                addr,
-                ptr1,
-            )
-        } else {
-            NvmeCommand::io_read(
-                self.io_sq.tail as u16,
-                ns_id,
-                lba,
-                blocks as u16 - 1,
-                addr,
-                ptr1,
-            )
-        };
-
-        let tail = self.io_sq.submit(entry);
+        let tail = self
+            .submit_io(ns, chunk, lba, write)
+            .ok_or("I/O submission queue is full")?;
         self.stats.submissions += 1;
 
-        self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
-        self.io_sq.head = self.complete_io(1).unwrap() as usize;
+        self.io_sq.doorbell.ring(tail as u32);
+        self.io_sq.head = self.complete_io(1)? as usize;
         Ok(())
     }
 
+    /// Submits an admin command and waits for its completion. Commands that time out are
+    /// aborted, and the controller is reset if the Abort command times out as well.
     pub fn submit_and_complete_admin<F: FnOnce(u16, usize) -> NvmeCommand>(
         &mut self,
         cmd_init: F,
     ) -> Result<NvmeCompletion, Box<dyn Error>> {
-        let cid = self.admin_sq.tail;
-        let tail = self.admin_sq.submit(cmd_init(cid as u16, self.buffer.phys));
+        let cid = self.admin_sq.tail as u16;
+        self.submit_admin(cmd_init(cid, self.buffer.phys))?;
+
+        match self.admin_wait(cid) {
+            Some(entry) => {
+                NvmeError::check(&entry)?;
+                Ok(entry)
+            }
+            None => {
+                // the controller is not responding if this happens during a reset, give up
+                if !self.resetting && !self.shutting_down {
+                    match self.abort(0, cid) {
+                        Err(e) if e.is::<TimeoutError>() => self.reset()?,
+                        Err(e) => eprintln!("abort of admin command {cid} failed: {e}"),
+                        Ok(_) => {}
+                    }
+                }
+                Err(TimeoutError {
+                    sqid: 0,
+                    cid,
+                    timeout: self.admin_timeout,
+                }
+                .into())
+            }
+        }
+    }
+
+    // places `entry` in the admin submission queue and rings its doorbell
+    fn submit_admin(&mut self, entry: NvmeCommand) -> Result<(), Box<dyn Error>> {
+        let tail = self
+            .admin_sq
+            .submit_checked(entry)
+            .ok_or("admin submission queue is full")?;
         self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 0, tail as u32);
+        Ok(())
+    }
 
-        let (head, entry, _) = self.admin_cq.complete_spin();
-        self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
-        let status = entry.status >> 1;
-        if status != 0 {
-            println!(
-                "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
-                status,
-                status & 0xFF,
-                (status >> 8) & 0x7
-            );
-            return Err("Requesting i/o completion queue failed".into());
+    // waits up to the admin timeout for the completion of admin command `cid`, completions of
+    // earlier commands that timed out are discarded
+    fn admin_wait(&mut self, cid: u16) -> Option<NvmeCompletion> {
+        let deadline = Instant::now() + self.admin_timeout;
+        loop {
+            let (head, entry, _) = self.admin_cq.complete_spin_until(deadline)?;
+            self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
+            self.admin_sq.head = entry.sq_head as usize;
+            if entry.c_id == cid {
+                return Some(entry);
+            }
+            // asynchronous events complete at any time, keep them for `poll_async_events`
+            if self.aers.contains(&{ entry.c_id }) {
+                self.aer_completions.push(entry);
+            }
         }
-        Ok(entry)
     }
 
     pub fn clear_namespace(&mut self, ns_id: Option<u32>) {
@@ -894,68 +2356,95 @@ impl<T: DmaSlice + Debug> NvmeDevice<T> {
     /// Sets Queue `qid` Tail Doorbell to `val`
     fn write_reg_idx(&self, reg: NvmeArrayRegs, qid: u16, val: u32) {
         match reg {
-            NvmeArrayRegs::SQyTDBL => unsafe {
-                std::ptr::write_volatile(
-                    (self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * qid)) as usize)
-                        as *mut u32,
-                    val,
-                );
-            },
-            NvmeArrayRegs::CQyHDBL => unsafe {
-                std::ptr::write_volatile(
-                    (self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * qid + 1)) as usize)
-                        as *mut u32,
-                    val,
-                );
-            },
+            NvmeArrayRegs::SQyTDBL => self
+                .regs
+                .write32(0x1000 + ((4 << self.dstrd) * (2 * qid)) as usize, val),
+            NvmeArrayRegs::CQyHDBL => self
+                .regs
+                .write32(0x1000 + ((4 << self.dstrd) * (2 * qid + 1)) as usize, val),
         }
     }
 
-    /// Sets the register at `self.addr` + `reg` to `value`.
+    /// Sets the register at `reg` to `value`.
     ///
     /// # Panics
     ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Panics if `reg` does not belong to the register space of the device.
     fn set_reg32(&self, reg: u32, value: u32) {
-        assert!(reg as usize <= self.len - 4, "memory access out of bounds");
+        assert!(
+            reg as usize <= self.regs.size() - 4,
+            "memory access out of bounds"
+        );
 
-        unsafe {
-            std::ptr::write_volatile((self.addr as usize + reg as usize) as *mut u32, value);
-        }
+        self.regs.write32(reg as usize, value);
     }
 
-    /// Returns the register at `self.addr` + `reg`.
+    /// Returns the register at `reg`.
     ///
     /// # Panics
     ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Panics if `reg` does not belong to the register space of the device.
     fn get_reg32(&self, reg: u32) -> u32 {
-        assert!(reg as usize <= self.len - 4, "memory access out of bounds");
+        assert!(
+            reg as usize <= self.regs.size() - 4,
+            "memory access out of bounds"
+        );
 
-        unsafe { std::ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }
+        self.regs.read32(reg as usize)
     }
 
-    /// Sets the register at `self.addr` + `reg` to `value`.
+    /// Sets the register at `reg` to `value`.
     ///
     /// # Panics
     ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Panics if `reg` does not belong to the register space of the device.
     fn set_reg64(&self, reg: u32, value: u64) {
-        assert!(reg as usize <= self.len - 8, "memory access out of bounds");
+        assert!(
+            reg as usize <= self.regs.size() - 8,
+            "memory access out of bounds"
+        );
 
-        unsafe {
-            std::ptr::write_volatile((self.addr as usize + reg as usize) as *mut u64, value);
-        }
+        self.regs.write64(reg as usize, value);
     }
 
-    /// Returns the register at `self.addr` + `reg`.
+    /// Returns the register at `reg`.
     ///
     /// # Panics
     ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Panics if `reg` does not belong to the register space of the device.
     fn get_reg64(&self, reg: u64) -> u64 {
-        assert!(reg as usize <= self.len - 8, "memory access out of bounds");
+        assert!(
+            reg as usize <= self.regs.size() - 8,
+            "memory access out of bounds"
+        );
+
+        self.regs.read64(reg as usize)
+    }
+}
+
+impl<T: DmaSlice + Debug> Drop for NvmeDevice<T> {
+    fn drop(&mut self) {
+        let result = if self.release_on_drop {
+            self.release()
+        } else {
+            self.shutdown()
+        };
+        if let Err(e) = result {
+            eprintln!("shutdown of {} failed: {e}", self.pci_addr);
+        }
+    }
+}
 
-        unsafe { std::ptr::read_volatile((self.addr as usize + reg as usize) as *mut u64) }
+/// Writes `ranges` as Dataset Management range descriptors to `dest`
+pub(crate) fn write_dsm_ranges(dest: &mut [u8], ranges: &[LbaRange]) {
+    assert!(ranges.len() * std::mem::size_of::<DsmRange>() <= dest.len());
+    let dest = dest.as_mut_ptr() as *mut DsmRange;
+    for (i, range) in ranges.iter().enumerate() {
+        let desc = DsmRange {
+            attributes: 0,
+            blocks: range.blocks,
+            slba: range.slba,
+        };
+        unsafe { std::ptr::write_unaligned(dest.add(i), desc) };
     }
 }
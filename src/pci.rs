@@ -13,6 +13,70 @@ pub const BUS_MASTER_ENABLE_BIT: u64 = 2;
 // bit 10: "interrupt disable"
 pub const INTERRUPT_DISABLE: u64 = 10;
 
+/// Kernel driver and command register of a device before it was taken over by
+/// [`pci_map_resource`]
+#[derive(Debug, Clone)]
+pub struct PciBinding {
+    pub pci_addr: String,
+    /// Name of the driver the device was bound to, `None` if it was unbound
+    pub driver: Option<String>,
+    /// Command register in the PCIe config space
+    pub command: u16,
+}
+
+impl PciBinding {
+    /// Records the current driver and command register of the device at `pci_addr`.
+    pub fn record(pci_addr: &str) -> Result<Self, Box<dyn Error>> {
+        let path = format!("/sys/bus/pci/devices/{}/config", pci_addr);
+        let mut file = fs::OpenOptions::new().read(true).open(path)?;
+
+        Ok(Self {
+            pci_addr: pci_addr.to_string(),
+            driver: driver_name(pci_addr),
+            command: read_io16(&mut file, COMMAND_REGISTER_OFFSET)?,
+        })
+    }
+
+    /// Restores the command register bits changed by [`enable_dma`] and [`disable_interrupts`]
+    /// and binds the device to its original driver again.
+    pub fn restore(&self) -> Result<(), Box<dyn Error>> {
+        let path = format!("/sys/bus/pci/devices/{}/config", self.pci_addr);
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mask = (1 << BUS_MASTER_ENABLE_BIT) | (1 << INTERRUPT_DISABLE);
+        let command = read_io16(&mut file, COMMAND_REGISTER_OFFSET)?;
+        write_io16(
+            &mut file,
+            (command & !mask) | (self.command & mask),
+            COMMAND_REGISTER_OFFSET,
+        )?;
+
+        match &self.driver {
+            Some(driver) if driver_name(&self.pci_addr).is_none() => {
+                bind_driver(&self.pci_addr, driver)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the name of the driver bound to the device at `pci_addr`.
+pub fn driver_name(pci_addr: &str) -> Option<String> {
+    let driver = fs::read_link(format!("/sys/bus/pci/devices/{}/driver", pci_addr)).ok()?;
+    Some(driver.file_name()?.to_str()?.to_string())
+}
+
+/// Binds the device at `pci_addr` to `driver`, regardless of the ids the driver matches.
+pub fn bind_driver(pci_addr: &str, driver: &str) -> Result<(), Box<dyn Error>> {
+    let override_path = format!("/sys/bus/pci/devices/{}/driver_override", pci_addr);
+    fs::write(&override_path, driver)?;
+
+    let bound = fs::write(format!("/sys/bus/pci/drivers/{}/bind", driver), pci_addr);
+    // an empty override matches drivers by id again
+    fs::write(&override_path, "\n")?;
+    Ok(bound?)
+}
+
 /// Unbinds the driver from the device at `pci_addr`.
 pub fn unbind_driver(pci_addr: &str) -> Result<(), Box<dyn Error>> {
     let path = format!("/sys/bus/pci/devices/{}/driver/unbind", pci_addr);
@@ -51,10 +115,12 @@ pub fn disable_interrupts(pci_addr: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-/// Mmaps a pci resource and returns a pointer to the mapped memory.
-pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize), Box<dyn Error>> {
+/// Mmaps a pci resource and returns a pointer to the mapped memory, along with the binding of the
+/// device before its driver was unbound.
+pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize, PciBinding), Box<dyn Error>> {
     let path = format!("/sys/bus/pci/devices/{}/resource0", pci_addr);
 
+    let binding = PciBinding::record(pci_addr)?;
     unbind_driver(pci_addr)?;
     enable_dma(pci_addr)?;
     disable_interrupts(pci_addr)?;
@@ -76,8 +142,50 @@ pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize), Box<dyn Erro
     if ptr.is_null() || len == 0 {
         Err("pci mapping failed".into())
     } else {
-        Ok((ptr, len))
+        Ok((ptr, len, binding))
+    }
+}
+
+/// Mmaps BAR `bar` of the device at `pci_addr`, which has to be taken over by
+/// [`pci_map_resource`] already.
+pub fn pci_map_bar(pci_addr: &str, bar: u8) -> Result<(*mut u8, usize), Box<dyn Error>> {
+    let path = format!("/sys/bus/pci/devices/{}/resource{}", pci_addr, bar);
+    let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+    let len = fs::metadata(&path)?.len() as usize;
+
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED || len == 0 {
+        Err(format!("mapping bar{} failed", bar).into())
+    } else {
+        Ok((ptr as *mut u8, len))
+    }
+}
+
+/// Returns the address BAR `bar` of the device at `pci_addr` is assigned on the bus.
+pub fn pci_bar_address(pci_addr: &str, bar: u8) -> Result<usize, Box<dyn Error>> {
+    let path = format!("/sys/bus/pci/devices/{}/resource", pci_addr);
+    // one "start end flags" line per resource
+    let resources = fs::read_to_string(path)?;
+    let start = resources
+        .lines()
+        .nth(bar as usize)
+        .and_then(|line| line.split_whitespace().next())
+        .ok_or(format!("bar{} not found", bar))?;
+    let addr = usize::from_str_radix(start.trim_start_matches("0x"), 16)?;
+    if addr == 0 {
+        return Err(format!("bar{} is not assigned", bar).into());
     }
+    Ok(addr)
 }
 
 /// Opens a pci resource file at the given address.
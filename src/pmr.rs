@@ -0,0 +1,113 @@
+use std::error::Error;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use crate::nvme::NvmeRegs32;
+use crate::registers::{Bar, RegisterAccess};
+
+/// Persistent Memory Region enabled with [`NvmeDevice::enable_pmr`], byte addressable memory of
+/// the controller whose contents survive power loss once made persistent with
+/// [`PersistentMemoryRegion::persist`].
+///
+/// [`NvmeDevice::enable_pmr`]: crate::NvmeDevice::enable_pmr
+#[derive(Debug)]
+pub struct PersistentMemoryRegion {
+    bar: Bar,
+    regs: Arc<dyn RegisterAccess>,
+    pmrcap: u32,
+}
+
+impl PersistentMemoryRegion {
+    pub(crate) fn new(bar: Bar, regs: Arc<dyn RegisterAccess>, pmrcap: u32) -> Self {
+        Self { bar, regs, pmrcap }
+    }
+
+    /// Size of the region in bytes
+    pub fn len(&self) -> usize {
+        self.bar.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bar.len == 0
+    }
+
+    /// Start of the mapped region, for writes not going through [`PersistentMemoryRegion::write`]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.bar.addr
+    }
+
+    /// Address of the region on the PCI bus, as used in commands
+    pub fn bus_addr(&self) -> usize {
+        self.bar.bus_addr
+    }
+
+    /// Copies `data` to the region at `offset`. The data is only persistent after the next
+    /// [`PersistentMemoryRegion::persist`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the data exceeds the region.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        assert!(
+            offset + data.len() <= self.len(),
+            "write exceeds the region"
+        );
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.bar.addr.add(offset), data.len())
+        };
+    }
+
+    /// Copies `buf.len()` bytes of the region starting at `offset` into `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the read exceeds the region.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset + buf.len() <= self.len(), "read exceeds the region");
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.bar.addr.add(offset), buf.as_mut_ptr(), buf.len())
+        };
+    }
+
+    /// Barrier making all previous writes to the region persistent. Reads PMRSTS, whose
+    /// completion guarantees that on controllers with PMRWBM bit 1, and fails if the region is
+    /// not ready or reports an error.
+    pub fn persist(&self) -> Result<(), Box<dyn Error>> {
+        // the reads must not pass the posted writes
+        fence(Ordering::SeqCst);
+        // PMRWBM: otherwise only a read of the region itself flushes the writes
+        if (self.pmrcap >> 10) & 0b10 == 0 {
+            unsafe { std::ptr::read_volatile(self.bar.addr) };
+        }
+        let status = self.regs.read32(NvmeRegs32::PMRSTS as usize);
+
+        // NRDY
+        if status & (1 << 8) != 0 {
+            return Err("persistent memory region is not ready".into());
+        }
+        // HSTS: 0 is normal operation
+        let health = (status >> 9) & 0b111;
+        if health != 0 {
+            return Err(format!("persistent memory region health status {health:#x}").into());
+        }
+        // ERR, vendor specific
+        let error = status & 0xFF;
+        if error != 0 {
+            return Err(format!("persistent memory region error {error:#x}").into());
+        }
+        Ok(())
+    }
+
+    /// Bytes the controller can buffer before writes are slowed down to the sustained write
+    /// throughput (PMREBS), `None` if not reported
+    pub fn elasticity_buffer_size(&self) -> Option<u64> {
+        let pmrebs = self.regs.read32(NvmeRegs32::PMREBS as usize);
+        Some(((pmrebs >> 8) as u64) << (10 * (pmrebs & 0xF))).filter(|&size| size != 0)
+    }
+
+    /// Bytes per second that can be written sustainably (PMRSWTP), `None` if not reported
+    pub fn sustained_write_throughput(&self) -> Option<u64> {
+        let pmrswtp = self.regs.read32(NvmeRegs32::PMRSWTP as usize);
+        Some(((pmrswtp >> 8) as u64) << (10 * (pmrswtp & 0xF))).filter(|&bytes| bytes != 0)
+    }
+}
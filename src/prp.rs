@@ -0,0 +1,142 @@
+use std::error::Error;
+
+use crate::cmb::{CmbBuffer, ControllerMemoryBuffer};
+use crate::memory::{Dma, DmaChunk, DmaSlice, HUGE_PAGE_SIZE};
+
+/// Memory page size used by the controller (CC.MPS = 0)
+pub const PAGE_SIZE: usize = 4096;
+/// Number of entries in a PRP list page
+const PRP_LIST_ENTRIES: usize = PAGE_SIZE / 8;
+/// Largest transfer PRP1 and a single PRP list page can describe, regardless of alignment
+pub const MAX_PRP_TRANSFER: usize = PRP_LIST_ENTRIES * PAGE_SIZE;
+
+/// Memory page for every slot of a submission queue, allocated one huge page at a time so that
+/// their device addresses are known without translating every page
+#[derive(Debug)]
+pub(crate) struct SlotPages {
+    huge_pages: Vec<Dma<u8>>,
+    // allocations the "huge pages" are borrowed from if they are in the controller memory buffer
+    cmb: Vec<CmbBuffer>,
+    len: usize,
+}
+
+impl SlotPages {
+    const PER_HUGE_PAGE: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+    pub fn new(len: usize) -> Result<Self, Box<dyn Error>> {
+        let huge_pages = (0..len.div_ceil(Self::PER_HUGE_PAGE))
+            .map(|_| Dma::allocate(HUGE_PAGE_SIZE))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            huge_pages,
+            cmb: Vec::new(),
+            len,
+        })
+    }
+
+    /// Pages in the controller memory buffer `cmb`, in blocks of at most a huge page
+    pub fn new_in(cmb: &ControllerMemoryBuffer, len: usize) -> Result<Self, Box<dyn Error>> {
+        let cmb = (0..len.div_ceil(Self::PER_HUGE_PAGE))
+            .map(|i| {
+                cmb.allocate((len - i * Self::PER_HUGE_PAGE).min(Self::PER_HUGE_PAGE) * PAGE_SIZE)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            huge_pages: cmb
+                .iter()
+                .map(|buffer| buffer.slice(0..buffer.size))
+                .collect(),
+            cmb,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Virtual and device address of the page of slot `slot`
+    pub fn page(&self, slot: usize) -> (*mut u8, u64) {
+        let huge_page = &self.huge_pages[slot / Self::PER_HUGE_PAGE];
+        let offset = slot % Self::PER_HUGE_PAGE * PAGE_SIZE;
+        unsafe { (huge_page.virt.add(offset), (huge_page.phys + offset) as u64) }
+    }
+}
+
+/// PRP list pages for a submission queue, NVMe spec 4.3
+///
+/// There is one list page per queue slot, selected by the command id. Ids are only reused once
+/// their command completed, so a command can use its page until then.
+#[derive(Debug)]
+pub struct PrpLists {
+    pages: SlotPages,
+}
+
+impl PrpLists {
+    pub fn new(len: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            pages: SlotPages::new(len)?,
+        })
+    }
+
+    /// List pages for a queue of `len` slots in the controller memory buffer `cmb`
+    pub fn new_in(cmb: &ControllerMemoryBuffer, len: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            pages: SlotPages::new_in(cmb, len)?,
+        })
+    }
+
+    /// Returns PRP1 and PRP2 describing the `bytes` physically contiguous bytes at `addr`.
+    /// Transfers spanning more than two pages use list page `slot`, the command id.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the transfer is larger than [`MAX_PRP_TRANSFER`].
+    pub fn prps(&mut self, slot: usize, addr: u64, bytes: usize) -> (u64, u64) {
+        self.build(slot, bytes, |offset| addr + offset as u64)
+    }
+
+    /// Returns PRP1 and PRP2 describing `chunk`, whose pages are translated one by one as it may
+    /// span several huge pages. Transfers spanning more than two pages use list page `slot`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chunk is larger than [`MAX_PRP_TRANSFER`].
+    pub fn chunk_prps(&mut self, slot: usize, chunk: &DmaChunk<'_, u8>) -> (u64, u64) {
+        self.build(slot, chunk.slice.len(), |offset| {
+            chunk.phys_at(offset) as u64
+        })
+    }
+
+    // `device_addr` maps offsets into the transfer to device addresses
+    fn build(
+        &mut self,
+        slot: usize,
+        bytes: usize,
+        device_addr: impl Fn(usize) -> u64,
+    ) -> (u64, u64) {
+        let addr = device_addr(0);
+        let page_offset = addr as usize % PAGE_SIZE;
+        let pages = (page_offset + bytes).div_ceil(PAGE_SIZE);
+
+        match pages {
+            0 | 1 => (addr, 0),
+            2 => (addr, device_addr(PAGE_SIZE - page_offset)),
+            _ => {
+                assert!(
+                    pages - 1 <= PRP_LIST_ENTRIES,
+                    "transfer too large for a single PRP list"
+                );
+                assert!(slot < self.pages.len(), "PRP list slot out of bounds");
+
+                let (virt, phys) = self.pages.page(slot);
+                let list =
+                    unsafe { std::slice::from_raw_parts_mut(virt as *mut u64, PRP_LIST_ENTRIES) };
+                for (i, entry) in list.iter_mut().take(pages - 1).enumerate() {
+                    *entry = device_addr((i + 1) * PAGE_SIZE - page_offset);
+                }
+                (addr, phys)
+            }
+        }
+    }
+}
@@ -1,7 +1,12 @@
+use crate::cmb::{CmbBuffer, ControllerMemoryBuffer};
 use crate::cmd::NvmeCommand;
 use crate::memory::*;
+use crate::prp::PAGE_SIZE;
+use crate::registers::Doorbell;
 use std::error::Error;
 use std::hint::spin_loop;
+use std::marker::PhantomData;
+use std::time::Instant;
 
 /// NVMe spec 4.6
 /// Completion queue entry
@@ -23,18 +28,111 @@ pub struct NvmeCompletion {
     pub status: u16,
 }
 
-/// maximum amount of submission entries on a 2MiB huge page
+/// maximum amount of submission entries on a 2MiB huge page, the default queue length
 pub const QUEUE_LENGTH: usize = 1024;
+/// Largest queue the specification allows (CAP.MQES is 0-based)
+pub const MAX_QUEUE_LENGTH: usize = 1 << 16;
+
+/// Priority class of an I/O submission queue under weighted round robin arbitration (QPRIO),
+/// ignored by controllers arbitrating round robin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueuePriority {
+    /// Served before all other classes, strict priority
+    Urgent = 0,
+    High = 1,
+    #[default]
+    Medium = 2,
+    Low = 3,
+}
+
+// queues are at least 2 entries long
+fn check_len(len: usize) -> Result<(), Box<dyn Error>> {
+    if !(2..=MAX_QUEUE_LENGTH).contains(&len) {
+        return Err(format!("queue length {len} is not between 2 and {MAX_QUEUE_LENGTH}").into());
+    }
+    Ok(())
+}
+
+/// Memory of a queue, contiguous in device memory (PC = 1) or described by a PRP list (PC = 0)
+#[derive(Debug)]
+struct QueueMemory<E> {
+    entries: Dma<u8>,
+    // device addresses of the memory pages of non-contiguous queues
+    prp_list: Option<Dma<u64>>,
+    // allocation `entries` is borrowed from if the queue is in the controller memory buffer
+    cmb: Option<CmbBuffer>,
+    _type: PhantomData<E>,
+}
+
+impl<E> QueueMemory<E> {
+    // queues larger than a huge page are only contiguous with VFIO or identity mapping, others
+    // need a PRP list unless the controller requires contiguous queues (CAP.CQR)
+    fn allocate(len: usize, contiguous: bool) -> Result<Self, Box<dyn Error>> {
+        let bytes = len * std::mem::size_of::<E>();
+        let entries: Dma<u8> = Dma::allocate(bytes)?;
+        if entries.is_contiguous() {
+            return Ok(Self {
+                entries,
+                prp_list: None,
+                cmb: None,
+                _type: PhantomData,
+            });
+        }
+        if contiguous {
+            return Err(format!(
+                "queue of {bytes} bytes is not physically contiguous, which the controller requires"
+            )
+            .into());
+        }
+
+        let pages = bytes.div_ceil(PAGE_SIZE);
+        let prp_list: Dma<u64> = Dma::allocate(pages * 8)?;
+        for page in 0..pages {
+            let phys = entries.phys_at(page * PAGE_SIZE);
+            unsafe { *prp_list.virt.add(page) = phys as u64 };
+        }
+        Ok(Self {
+            entries,
+            prp_list: Some(prp_list),
+            cmb: None,
+            _type: PhantomData,
+        })
+    }
+
+    // the controller memory buffer is always contiguous
+    fn allocate_in(cmb: &ControllerMemoryBuffer, len: usize) -> Result<Self, Box<dyn Error>> {
+        let bytes = len * std::mem::size_of::<E>();
+        let buffer = cmb.allocate(bytes)?;
+        Ok(Self {
+            entries: buffer.slice(0..bytes),
+            prp_list: None,
+            cmb: Some(buffer),
+            _type: PhantomData,
+        })
+    }
+
+    #[inline(always)]
+    fn entry(&self, idx: usize) -> *mut E {
+        unsafe { (self.entries.virt as *mut E).add(idx) }
+    }
+
+    // PRP Entry 1 of the Create I/O Queue commands
+    fn addr(&self) -> usize {
+        match &self.prp_list {
+            Some(list) => list.phys,
+            None => self.entries.phys,
+        }
+    }
+}
 
 /// Submission queue
 #[derive(Debug)]
 pub struct NvmeSubQueue {
-    // TODO: switch to mempool for larger queue
-    commands: Dma<[NvmeCommand; QUEUE_LENGTH]>,
+    commands: QueueMemory<NvmeCommand>,
     pub head: usize,
     pub tail: usize,
-    len: usize,
-    pub doorbell: usize,
+    pub len: usize,
+    pub doorbell: Doorbell,
 }
 
 unsafe impl Send for NvmeSubQueue {}
@@ -42,16 +140,40 @@ unsafe impl Sync for NvmeSubQueue {}
 
 impl NvmeSubQueue {
     
-    pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
+    /// Allocates a queue of `len` entries, `contiguous` if the controller requires physically
+    /// contiguous queues
+    pub fn new(len: usize, contiguous: bool, doorbell: Doorbell) -> Result<Self, Box<dyn Error>> {
+        check_len(len)?;
         Ok(Self {
-            commands: Dma::allocate(crate::memory::HUGE_PAGE_SIZE)?,
+            commands: QueueMemory::allocate(len, contiguous)?,
             head: 0,
             tail: 0,
-            len: len.min(QUEUE_LENGTH),
+            len,
             doorbell,
         })
     }
 
+    /// Allocates a queue of `len` entries in the controller memory buffer `cmb`
+    pub fn new_in(
+        cmb: &ControllerMemoryBuffer,
+        len: usize,
+        doorbell: Doorbell,
+    ) -> Result<Self, Box<dyn Error>> {
+        check_len(len)?;
+        Ok(Self {
+            commands: QueueMemory::allocate_in(cmb, len)?,
+            head: 0,
+            tail: 0,
+            len,
+            doorbell,
+        })
+    }
+
+    /// Whether the queue is in the controller memory buffer
+    pub fn in_cmb(&self) -> bool {
+        self.commands.cmb.is_some()
+    }
+
     
     pub fn is_empty(&self) -> bool {
         self.head == self.tail
@@ -74,7 +196,7 @@ impl NvmeSubQueue {
     
     #[inline(always)]
     pub fn submit(&mut self, entry: NvmeCommand) -> usize {
-        self.commands[self.tail] = entry;
+        unsafe { *self.commands.entry(self.tail) = entry };
 
         self.tail = (self.tail + 1) % self.len;
         self.tail
@@ -82,18 +204,30 @@ impl NvmeSubQueue {
 
     
     pub fn get_addr(&self) -> usize {
-        self.commands.phys
+        self.commands.addr()
+    }
+
+    /// Whether the queue is physically contiguous, otherwise [`NvmeSubQueue::get_addr`] is the
+    /// address of a PRP list
+    pub fn is_contiguous(&self) -> bool {
+        self.commands.prp_list.is_none()
+    }
+
+    /// Empties the queue, e.g. after a controller reset
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
     }
 }
 
 /// Completion queue
 #[derive(Debug)]
 pub struct NvmeCompQueue {
-    commands: Dma<[NvmeCompletion; QUEUE_LENGTH]>,
+    commands: QueueMemory<NvmeCompletion>,
     head: usize,
     phase: bool,
     len: usize,
-    pub doorbell: usize,
+    pub doorbell: Doorbell,
 }
 
 unsafe impl Send for NvmeCompQueue {}
@@ -102,12 +236,15 @@ unsafe impl Sync for NvmeCompQueue {}
 // TODO: error handling
 impl NvmeCompQueue {
     
-    pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
+    /// Allocates a queue of `len` entries, `contiguous` if the controller requires physically
+    /// contiguous queues
+    pub fn new(len: usize, contiguous: bool, doorbell: Doorbell) -> Result<Self, Box<dyn Error>> {
+        check_len(len)?;
         Ok(Self {
-            commands: Dma::allocate(crate::memory::HUGE_PAGE_SIZE)?,
+            commands: QueueMemory::allocate(len, contiguous)?,
             head: 0,
             phase: true,
-            len: len.min(QUEUE_LENGTH),
+            len,
             doorbell,
         })
     }
@@ -115,7 +252,7 @@ impl NvmeCompQueue {
     
     #[inline(always)]
     pub fn complete(&mut self) -> Option<(usize, NvmeCompletion, usize)> {
-        let entry = &self.commands[self.head];
+        let entry = unsafe { &*self.commands.entry(self.head) };
 
         if ((entry.status & 1) == 1) == self.phase {
             let prev = self.head;
@@ -154,6 +291,22 @@ impl NvmeCompQueue {
         }
     }
 
+    /// Like [`NvmeCompQueue::complete_spin`], but gives up at `deadline`
+    pub fn complete_spin_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Option<(usize, NvmeCompletion, usize)> {
+        loop {
+            if let Some(val) = self.complete() {
+                return Some(val);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
     
     pub fn new_head(&mut self) -> (usize, usize) {
         let prev = self.head;
@@ -166,6 +319,24 @@ impl NvmeCompQueue {
 
     
     pub fn get_addr(&self) -> usize {
-        self.commands.phys
+        self.commands.addr()
+    }
+
+    /// Index of the next entry to complete, the value of the head doorbell
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Whether the queue is physically contiguous, otherwise [`NvmeCompQueue::get_addr`] is the
+    /// address of a PRP list
+    pub fn is_contiguous(&self) -> bool {
+        self.commands.prp_list.is_none()
+    }
+
+    /// Empties the queue and clears stale entries, e.g. after a controller reset
+    pub fn reset(&mut self) {
+        self.commands.entries[..].fill(0);
+        self.head = 0;
+        self.phase = true;
     }
 }
@@ -0,0 +1,278 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::os::fd::RawFd;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::memory::Dma;
+use crate::prp::PAGE_SIZE;
+use crate::{pci, vfio};
+
+/// Access to the register space (BAR0) of an NVMe controller.
+///
+/// Offsets are relative to the start of BAR0, i.e. `0x0` is CAP and `0x1000` the first doorbell.
+pub trait RegisterAccess: Debug + Send + Sync {
+    /// Size of the register space in bytes
+    fn size(&self) -> usize;
+
+    fn read32(&self, offset: usize) -> u32;
+
+    fn write32(&self, offset: usize, value: u32);
+
+    fn read64(&self, offset: usize) -> u64;
+
+    fn write64(&self, offset: usize, value: u64);
+
+    /// Maps memory BAR `bar` of the controller, e.g. the one holding the Controller Memory
+    /// Buffer. Not supported by register spaces without a device behind them.
+    fn map_bar(&self, bar: u8) -> Result<Bar, Box<dyn Error>> {
+        Err(format!("BAR {bar} of the controller cannot be mapped").into())
+    }
+}
+
+/// Memory BAR of a controller mapped into this process, see [`RegisterAccess::map_bar`]
+#[derive(Debug)]
+pub struct Bar {
+    pub addr: *mut u8,
+    pub len: usize,
+    /// Address of the BAR on the PCI bus, the controller recognizes accesses to its own memory
+    /// by it
+    pub bus_addr: usize,
+    // memory of an emulated controller, mmio mappings are unmapped on drop instead
+    memory: Option<Arc<Dma<u8>>>,
+}
+
+unsafe impl Send for Bar {}
+unsafe impl Sync for Bar {}
+
+impl Bar {
+    pub(crate) fn mmio(addr: *mut u8, len: usize, bus_addr: usize) -> Self {
+        Self {
+            addr,
+            len,
+            bus_addr,
+            memory: None,
+        }
+    }
+
+    /// BAR of an emulated controller backed by `memory`, its bus address is the virtual one
+    #[cfg(feature = "emulator")]
+    pub(crate) fn memory(memory: Arc<Dma<u8>>) -> Self {
+        Self {
+            addr: memory.virt,
+            len: memory.size,
+            bus_addr: memory.phys,
+            memory: Some(memory),
+        }
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        if self.memory.is_none() {
+            unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+/// Memory mapped BAR0 of a physical controller.
+#[derive(Debug)]
+pub struct MmioRegisters {
+    addr: *mut u8,
+    len: usize,
+    // device the further BARs are mapped from, through VFIO if it has a device fd
+    pci_addr: Option<String>,
+    vfio_device: Option<RawFd>,
+}
+
+// the mapping is never moved or unmapped while the device is in use
+unsafe impl Send for MmioRegisters {}
+unsafe impl Sync for MmioRegisters {}
+
+impl MmioRegisters {
+    pub fn new(addr: *mut u8, len: usize) -> Self {
+        Self {
+            addr,
+            len,
+            pci_addr: None,
+            vfio_device: None,
+        }
+    }
+
+    /// BAR0 of the device at `pci_addr`, further BARs are mapped through VFIO if `vfio_device`
+    /// is set and through sysfs otherwise
+    pub fn for_device(
+        addr: *mut u8,
+        len: usize,
+        pci_addr: &str,
+        vfio_device: Option<RawFd>,
+    ) -> Self {
+        Self {
+            pci_addr: Some(pci_addr.to_string()),
+            vfio_device,
+            ..Self::new(addr, len)
+        }
+    }
+}
+
+impl RegisterAccess for MmioRegisters {
+    fn size(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { std::ptr::read_volatile((self.addr as usize + offset) as *const u32) }
+    }
+
+    #[inline(always)]
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { std::ptr::write_volatile((self.addr as usize + offset) as *mut u32, value) }
+    }
+
+    #[inline(always)]
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { std::ptr::read_volatile((self.addr as usize + offset) as *const u64) }
+    }
+
+    #[inline(always)]
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { std::ptr::write_volatile((self.addr as usize + offset) as *mut u64, value) }
+    }
+
+    fn map_bar(&self, bar: u8) -> Result<Bar, Box<dyn Error>> {
+        let pci_addr = self
+            .pci_addr
+            .as_deref()
+            .ok_or("registers are not backed by a pci device")?;
+        let (addr, len) = match self.vfio_device {
+            Some(device_fd) => vfio::map_region(device_fd, bar as u32)?,
+            None => pci::pci_map_bar(pci_addr, bar)?,
+        };
+        let bus_addr = match pci::pci_bar_address(pci_addr, bar) {
+            Ok(bus_addr) => bus_addr,
+            Err(e) => {
+                unsafe { libc::munmap(addr as *mut libc::c_void, len) };
+                return Err(e);
+            }
+        };
+        Ok(Bar::mmio(addr, len, bus_addr))
+    }
+}
+
+/// Shadow doorbell and EventIdx buffers set with Doorbell Buffer Config, NVMe spec 5.8.
+///
+/// Both are a memory page with an entry for every doorbell at the offset of the doorbell
+/// register from 0x1000, doorbells beyond the page keep using their register.
+#[derive(Debug)]
+pub struct DoorbellBuffers {
+    // shadow doorbells in the first page, EventIdx entries in the second
+    memory: Dma<u8>,
+}
+
+impl DoorbellBuffers {
+    const SHADOW: usize = 0;
+    const EVENT_IDX: usize = PAGE_SIZE;
+
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let buffers = Self {
+            memory: Dma::allocate(2 * PAGE_SIZE)?,
+        };
+        buffers.clear();
+        Ok(buffers)
+    }
+
+    /// Device addresses of the shadow doorbell and the EventIdx buffer
+    pub fn addrs(&self) -> (usize, usize) {
+        (
+            self.memory.phys_at(Self::SHADOW),
+            self.memory.phys_at(Self::EVENT_IDX),
+        )
+    }
+
+    /// Zeroes both buffers, e.g. before they are handed to a controller after a reset
+    pub fn clear(&self) {
+        unsafe { std::ptr::write_bytes(self.memory.virt, 0, 2 * PAGE_SIZE) };
+    }
+
+    /// Whether the buffers have an entry for the doorbell at `offset`
+    pub fn covers(&self, offset: usize) -> bool {
+        (0x1000..=0x1000 + PAGE_SIZE - 4).contains(&offset)
+    }
+
+    fn entry(&self, buffer: usize, offset: usize) -> *mut u32 {
+        assert!(self.covers(offset), "doorbell offset out of bounds");
+        unsafe { self.memory.virt.add(buffer + offset - 0x1000) as *mut u32 }
+    }
+}
+
+/// Submission queue tail or completion queue head doorbell
+#[derive(Debug, Clone)]
+pub struct Doorbell {
+    regs: Arc<dyn RegisterAccess>,
+    offset: usize,
+    shadow: Option<Arc<DoorbellBuffers>>,
+    // last value written, to tell whether the EventIdx was crossed
+    value: Arc<AtomicU32>,
+}
+
+impl Doorbell {
+    pub fn new(regs: Arc<dyn RegisterAccess>, offset: usize) -> Self {
+        assert!(offset <= regs.size() - 4, "doorbell offset out of bounds");
+        Self {
+            regs,
+            offset,
+            shadow: None,
+            value: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// Offset of the doorbell register in BAR0
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Writes further values to the shadow doorbell of `buffers` and only to the register when
+    /// the EventIdx asks for it. `value` is the current value of the doorbell.
+    pub fn set_shadow(&mut self, buffers: Option<Arc<DoorbellBuffers>>, value: u32) {
+        let buffers = buffers.filter(|buffers| buffers.covers(self.offset));
+        if let Some(buffers) = &buffers {
+            let entry = buffers.entry(DoorbellBuffers::SHADOW, self.offset);
+            unsafe { std::ptr::write_volatile(entry, value) };
+        }
+        self.shadow = buffers;
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn has_shadow(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    #[inline(always)]
+    pub fn ring(&self, value: u32) {
+        let Some(buffers) = &self.shadow else {
+            self.regs.write32(self.offset, value);
+            return;
+        };
+
+        let old = self.value.swap(value, Ordering::Relaxed);
+        unsafe {
+            std::ptr::write_volatile(buffers.entry(DoorbellBuffers::SHADOW, self.offset), value);
+        }
+        // the controller has to see the new value before we read its EventIdx
+        fence(Ordering::SeqCst);
+        let event_idx = unsafe {
+            std::ptr::read_volatile(buffers.entry(DoorbellBuffers::EVENT_IDX, self.offset))
+        };
+        if need_event(event_idx, value, old) {
+            self.regs.write32(self.offset, value);
+        }
+    }
+}
+
+// whether the doorbell moving from `old` to `new` passed the EventIdx, like virtio's
+// vring_need_event
+#[inline(always)]
+fn need_event(event_idx: u32, new: u32, old: u32) -> bool {
+    (new.wrapping_sub(event_idx).wrapping_sub(1) as u16) < (new.wrapping_sub(old) as u16)
+}
@@ -1,4 +1,7 @@
-use std::fmt::Display;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
 use std::{future::Future, pin::Pin};
 
@@ -23,14 +26,144 @@ impl Display for State {
     }
 }
 
+/// Value kept alive until the command of a request completed, e.g. the DMA buffer it transfers
+pub struct KeepAlive(Box<dyn Any + Send>);
+
+impl Debug for KeepAlive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KeepAlive")
+    }
+}
+
+#[derive(Debug, Default)]
+struct Orphan {
+    keep_alive: Vec<KeepAlive>,
+    // the request already failed with a timeout error
+    expired: bool,
+    abort_requested: bool,
+}
+
+/// Commands of a queue pair whose [`Request`] was dropped before completion or timed out.
+///
+/// Their completions are discarded, and values attached with [`Request::keep_alive`] are only
+/// dropped once the controller completed the command.
+#[derive(Debug, Default)]
+pub struct Orphans {
+    commands: Mutex<HashMap<u16, Orphan>>,
+}
+
+impl Orphans {
+    /// Delivers the completion of command `id` with `send`, which fails if its request is gone.
+    /// The command is no longer tracked as orphaned afterwards.
+    pub fn complete(&self, id: u16, send: impl FnOnce() -> bool) {
+        let mut commands = self.commands.lock().unwrap();
+        if !send() {
+            commands.remove(&id);
+        }
+    }
+
+    /// Fails the request of command `id` with `send` because the command timed out. The command
+    /// is tracked as orphaned until the controller completes it.
+    pub fn expire(&self, id: u16, send: impl FnOnce()) {
+        let mut commands = self.commands.lock().unwrap();
+        send();
+        commands.entry(id).or_default().expired = true;
+    }
+
+    fn insert(
+        &self,
+        id: u16,
+        receiver: &mut oneshot::Receiver<std::io::Result<()>>,
+        completed: bool,
+        keep_alive: Vec<KeepAlive>,
+    ) {
+        let mut commands = self.commands.lock().unwrap();
+        if let Some(orphan) = commands.get_mut(&id) {
+            // timed out, but still outstanding
+            orphan.keep_alive.extend(keep_alive);
+            return;
+        }
+        if completed {
+            return;
+        }
+        // no completion can be delivered after closing, so it is either received or orphaned
+        receiver.close();
+        if receiver.try_recv().is_err() {
+            commands.insert(
+                id,
+                Orphan {
+                    keep_alive,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    /// Forgets all orphaned commands, e.g. after a controller reset discarded them
+    pub fn clear(&self) {
+        self.commands.lock().unwrap().clear();
+    }
+
+    /// Whether command `id` was orphaned and is still outstanding
+    pub fn contains(&self, id: u16) -> bool {
+        self.commands.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the timed out, and if `dropped` is set also the dropped, commands no abort was
+    /// requested for yet and marks them as requested
+    pub fn take_abortable(&self, dropped: bool) -> Vec<u16> {
+        let mut commands = self.commands.lock().unwrap();
+        commands
+            .iter_mut()
+            .filter(|(_, orphan)| !orphan.abort_requested && (orphan.expired || dropped))
+            .map(|(&id, orphan)| {
+                orphan.abort_requested = true;
+                id
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub id: u16,
     pub receiver: oneshot::Receiver<std::io::Result<()>>,
     pub state: State,
+    orphans: Option<Arc<Orphans>>,
+    keep_alive: Vec<KeepAlive>,
 }
 
-impl Request {}
+impl Request {
+    /// Request for the submitted command `id`, dropping it before completion orphans the command
+    pub fn new(
+        id: u16,
+        receiver: oneshot::Receiver<std::io::Result<()>>,
+        orphans: Arc<Orphans>,
+    ) -> Self {
+        Self {
+            id,
+            receiver,
+            state: State::Submitted,
+            orphans: Some(orphans),
+            keep_alive: Vec::new(),
+        }
+    }
+
+    /// Keeps `value` alive until the command completed, even if the request is dropped before.
+    /// Use it to hand over the DMA buffer of requests that may be cancelled.
+    pub fn keep_alive(mut self, value: impl Any + Send) -> Self {
+        self.keep_alive.push(KeepAlive(Box::new(value)));
+        self
+    }
+}
 
 impl Future for Request {
     type Output = std::io::Result<()>;
@@ -60,14 +193,14 @@ impl Future for Request {
 }
 
 impl Drop for Request {
-    
     fn drop(&mut self) {
-        match self.state {
-            State::Completed => {}
-            _ => panic!(
-                "Request dropped before completion with state {}",
-                self.state
-            ),
+        let completed = matches!(self.state, State::Completed | State::Error);
+        if completed && self.keep_alive.is_empty() {
+            return;
+        }
+        if let Some(orphans) = self.orphans.take() {
+            let keep_alive = std::mem::take(&mut self.keep_alive);
+            orphans.insert(self.id, &mut self.receiver, completed, keep_alive);
         }
     }
 }
@@ -0,0 +1,115 @@
+use std::error::Error;
+
+use crate::prp::{SlotPages, PAGE_SIZE};
+
+/// Number of descriptors in an SGL segment page
+pub const SGL_SEGMENT_ENTRIES: usize = PAGE_SIZE / 16;
+
+const DATA_BLOCK: u8 = 0x0;
+const BIT_BUCKET: u8 = 0x1;
+const SEGMENT: u8 = 0x2;
+const LAST_SEGMENT: u8 = 0x3;
+
+/// SGL descriptor, NVMe spec 4.4
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct SglDescriptor {
+    /// Address of the data block or segment, reserved for bit buckets
+    pub addr: u64,
+    /// Length in bytes
+    pub len: u32,
+    _rsvd: [u8; 3],
+    /// Descriptor Type (4 bits) | Descriptor Sub Type (4 bits)
+    pub identifier: u8,
+}
+
+impl SglDescriptor {
+    fn new(addr: u64, len: u32, descriptor_type: u8) -> Self {
+        Self {
+            addr,
+            len,
+            _rsvd: [0; 3],
+            // sub type 0: the address field contains an address
+            identifier: descriptor_type << 4,
+        }
+    }
+
+    /// Physically contiguous buffer of `len` bytes at `addr`
+    pub fn data_block(addr: u64, len: u32) -> Self {
+        Self::new(addr, len, DATA_BLOCK)
+    }
+
+    /// Discards `len` bytes of read data instead of transferring them to the host
+    pub fn bit_bucket(len: u32) -> Self {
+        Self::new(0, len, BIT_BUCKET)
+    }
+
+    /// Segment of `descriptors` descriptors at `addr` ending in another segment descriptor
+    pub fn segment(addr: u64, descriptors: usize) -> Self {
+        Self::new(addr, (descriptors * 16) as u32, SEGMENT)
+    }
+
+    /// Final segment of `descriptors` descriptors at `addr`
+    pub fn last_segment(addr: u64, descriptors: usize) -> Self {
+        Self::new(addr, (descriptors * 16) as u32, LAST_SEGMENT)
+    }
+
+    pub fn descriptor_type(&self) -> u8 {
+        self.identifier >> 4
+    }
+
+    /// Number of bytes transferred by data block and bit bucket descriptors, 0 for segments
+    pub fn data_len(&self) -> usize {
+        match self.descriptor_type() {
+            DATA_BLOCK | BIT_BUCKET => self.len as usize,
+            _ => 0,
+        }
+    }
+
+    pub fn is_bit_bucket(&self) -> bool {
+        self.descriptor_type() == BIT_BUCKET
+    }
+
+    /// The descriptor as SGL Entry 1 in the data pointer of a command
+    pub fn to_dptr(self) -> [u64; 2] {
+        [self.addr, self.len as u64 | (self.identifier as u64) << 56]
+    }
+}
+
+/// SGL segment pages for a submission queue, one per queue slot like [`crate::prp::PrpLists`]
+#[derive(Debug)]
+pub struct SglSegments {
+    pages: SlotPages,
+}
+
+impl SglSegments {
+    pub fn new(len: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            pages: SlotPages::new(len)?,
+        })
+    }
+
+    /// Returns SGL Entry 1 of a command transferring `descriptors`.
+    /// More than one descriptor is placed in segment page `slot`, the command id.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `descriptors` is empty or has more than [`SGL_SEGMENT_ENTRIES`] entries.
+    pub fn build(&mut self, slot: usize, descriptors: &[SglDescriptor]) -> SglDescriptor {
+        assert!(
+            (1..=SGL_SEGMENT_ENTRIES).contains(&descriptors.len()),
+            "invalid number of SGL descriptors"
+        );
+        if let [descriptor] = descriptors {
+            return *descriptor;
+        }
+        assert!(slot < self.pages.len(), "SGL segment slot out of bounds");
+
+        let (virt, phys) = self.pages.page(slot);
+        let segment = unsafe {
+            std::slice::from_raw_parts_mut(virt as *mut SglDescriptor, descriptors.len())
+        };
+        segment.copy_from_slice(descriptors);
+        SglDescriptor::last_segment(phys, descriptors.len())
+    }
+}
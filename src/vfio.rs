@@ -0,0 +1,256 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::fd::{IntoRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+
+use crate::memory::{VFIO_CONTAINER_FILE_DESCRIPTOR, VFIO_GROUP_FILE_DESCRIPTORS};
+use crate::pci::{BUS_MASTER_ENABLE_BIT, COMMAND_REGISTER_OFFSET, INTERRUPT_DISABLE};
+
+// constants and structs from linux/vfio.h
+const VFIO_API_VERSION: libc::c_int = 0;
+const VFIO_TYPE1_IOMMU: libc::c_ulong = 1;
+const VFIO_GROUP_FLAGS_VIABLE: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_READ: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+const VFIO_PCI_BAR0_REGION_INDEX: u32 = 0;
+const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+
+// _IO(VFIO_TYPE, VFIO_BASE + n)
+const VFIO_GET_API_VERSION: libc::Ioctl = 0x3B64;
+const VFIO_CHECK_EXTENSION: libc::Ioctl = 0x3B65;
+const VFIO_SET_IOMMU: libc::Ioctl = 0x3B66;
+const VFIO_GROUP_GET_STATUS: libc::Ioctl = 0x3B67;
+const VFIO_GROUP_SET_CONTAINER: libc::Ioctl = 0x3B68;
+const VFIO_GROUP_GET_DEVICE_FD: libc::Ioctl = 0x3B6A;
+const VFIO_DEVICE_GET_REGION_INFO: libc::Ioctl = 0x3B6C;
+const VFIO_IOMMU_MAP_DMA: libc::Ioctl = 0x3B71;
+const VFIO_IOMMU_UNMAP_DMA: libc::Ioctl = 0x3B72;
+
+#[repr(C)]
+struct VfioGroupStatus {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VfioRegionInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct VfioIommuType1DmaMap {
+    argsz: u32,
+    flags: u32,
+    vaddr: u64,
+    iova: u64,
+    size: u64,
+}
+
+#[repr(C)]
+struct VfioIommuType1DmaUnmap {
+    argsz: u32,
+    flags: u32,
+    iova: u64,
+    size: u64,
+}
+
+/// Whether the device at `pci_addr` is bound to the `vfio-pci` driver
+pub fn is_bound(pci_addr: &str) -> bool {
+    fs::read_link(format!("/sys/bus/pci/devices/{}/driver", pci_addr))
+        .map(|driver| driver.file_name() == Some("vfio-pci".as_ref()))
+        .unwrap_or(false)
+}
+
+/// Adds the IOMMU group of the device at `pci_addr` to the VFIO container, opening the container
+/// on first use, and returns the device file descriptor.
+#[allow(static_mut_refs)]
+pub fn init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
+    let group_link = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr))?;
+    let group: i32 = group_link
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("invalid iommu group")?
+        .parse()?;
+
+    let mut groups = VFIO_GROUP_FILE_DESCRIPTORS.lock().unwrap();
+
+    // one container per process, all groups share its IOMMU mappings
+    let container = match unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR } {
+        Some(fd) => fd,
+        None => {
+            let fd = open("/dev/vfio/vfio")?;
+            if unsafe { libc::ioctl(fd, VFIO_GET_API_VERSION) } != VFIO_API_VERSION {
+                return Err("unknown VFIO API version".into());
+            }
+            if unsafe { libc::ioctl(fd, VFIO_CHECK_EXTENSION, VFIO_TYPE1_IOMMU) } != 1 {
+                return Err("VFIO type 1 IOMMU is not supported".into());
+            }
+            fd
+        }
+    };
+
+    let group_fd = match groups.get(&group) {
+        Some(&fd) => fd,
+        None => {
+            let fd = open(&format!("/dev/vfio/{}", group))?;
+            let mut status = VfioGroupStatus {
+                argsz: std::mem::size_of::<VfioGroupStatus>() as u32,
+                flags: 0,
+            };
+            check(unsafe { libc::ioctl(fd, VFIO_GROUP_GET_STATUS, &mut status) })?;
+            if status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
+                return Err(format!(
+                    "iommu group {} is not viable, are all its devices bound to vfio-pci?",
+                    group
+                )
+                .into());
+            }
+            check(unsafe { libc::ioctl(fd, VFIO_GROUP_SET_CONTAINER, &container) })?;
+            groups.insert(group, fd);
+            fd
+        }
+    };
+
+    // the IOMMU model can only be set once a group is attached to the container
+    if unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.is_none() {
+        check(unsafe { libc::ioctl(container, VFIO_SET_IOMMU, VFIO_TYPE1_IOMMU) })?;
+        unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR = Some(container) };
+    }
+
+    let name = CString::new(pci_addr)?;
+    let device_fd = unsafe { libc::ioctl(group_fd, VFIO_GROUP_GET_DEVICE_FD, name.as_ptr()) };
+    check(device_fd)?;
+    Ok(device_fd)
+}
+
+/// Enables direct memory access and disables INTx interrupts through the config space region
+/// of the device.
+pub fn enable_dma(device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let region = region_info(device_fd, VFIO_PCI_CONFIG_REGION_INDEX)?;
+    let offset = (region.offset + COMMAND_REGISTER_OFFSET) as libc::off_t;
+
+    let mut command: u16 = 0;
+    let len = std::mem::size_of::<u16>();
+    if unsafe { libc::pread(device_fd, &mut command as *mut u16 as *mut _, len, offset) } != 2 {
+        return Err(io::Error::last_os_error().into());
+    }
+    command |= 1 << BUS_MASTER_ENABLE_BIT;
+    command |= 1 << INTERRUPT_DISABLE;
+    if unsafe { libc::pwrite(device_fd, &command as *const u16 as *const _, len, offset) } != 2 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Mmaps BAR0 of the device and returns a pointer to the mapped memory.
+pub fn map_resource(device_fd: RawFd) -> Result<(*mut u8, usize), Box<dyn Error>> {
+    map_region(device_fd, VFIO_PCI_BAR0_REGION_INDEX)
+}
+
+/// Mmaps region `index` of the device, the regions 0 to 5 are the BARs.
+pub fn map_region(device_fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dyn Error>> {
+    let region = region_info(device_fd, index)?;
+    let len = region.size as usize;
+
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            device_fd,
+            region.offset as libc::off_t,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED || len == 0 {
+        Err(format!("vfio bar{index} mapping failed").into())
+    } else {
+        Ok((ptr as *mut u8, len))
+    }
+}
+
+/// Makes `size` bytes at `vaddr` accessible to all devices of the container at the IO virtual
+/// address `vaddr`.
+#[allow(static_mut_refs)]
+pub fn map_dma(vaddr: usize, size: usize) -> Result<usize, Box<dyn Error>> {
+    let container = unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.ok_or("vfio is not enabled")?;
+    let map = VfioIommuType1DmaMap {
+        argsz: std::mem::size_of::<VfioIommuType1DmaMap>() as u32,
+        flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+        vaddr: vaddr as u64,
+        iova: vaddr as u64,
+        size: size as u64,
+    };
+    check(unsafe { libc::ioctl(container, VFIO_IOMMU_MAP_DMA, &map) })?;
+    Ok(vaddr)
+}
+
+/// Removes the IOMMU mapping created by [`map_dma`].
+#[allow(static_mut_refs)]
+pub fn unmap_dma(iova: usize, size: usize) -> Result<(), Box<dyn Error>> {
+    let container = unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.ok_or("vfio is not enabled")?;
+    let mut unmap = VfioIommuType1DmaUnmap {
+        argsz: std::mem::size_of::<VfioIommuType1DmaUnmap>() as u32,
+        flags: 0,
+        iova: iova as u64,
+        size: size as u64,
+    };
+    check(unsafe { libc::ioctl(container, VFIO_IOMMU_UNMAP_DMA, &mut unmap) })?;
+    Ok(())
+}
+
+fn region_info(device_fd: RawFd, index: u32) -> Result<VfioRegionInfo, Box<dyn Error>> {
+    let mut region = VfioRegionInfo {
+        argsz: std::mem::size_of::<VfioRegionInfo>() as u32,
+        index,
+        ..Default::default()
+    };
+    check(unsafe { libc::ioctl(device_fd, VFIO_DEVICE_GET_REGION_INFO, &mut region) })?;
+    Ok(region)
+}
+
+fn open(path: &str) -> Result<RawFd, Box<dyn Error>> {
+    if !Path::new(path).exists() {
+        return Err(format!("{} does not exist - is the vfio-pci module loaded?", path).into());
+    }
+    Ok(fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?
+        .into_raw_fd())
+}
+
+fn check(result: libc::c_int) -> Result<(), io::Error> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // no hardware needed, a device that does not exist is neither bound nor in an IOMMU group
+    #[test]
+    fn missing_device() {
+        let pci_addr = "ffff:ff:1f.7";
+        assert!(!is_bound(pci_addr));
+        let error = init(pci_addr).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::NotFound)
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::time::Duration;
+
+use vroom::driver::Driver;
+use vroom::features::{
+    Arbitration, FeatureSelect, NumberOfQueues, TemperatureThreshold, VolatileWriteCache,
+};
+use vroom::memory::Dma;
+use vroom::{AsyncEventLog, AsyncEventType, NvmeDevice, NvmeError, HUGE_PAGE_SIZE};
+
+use crate::{controller, round_trip, NAMESPACE_SIZE};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controller_info() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller, 1).unwrap();
+    let info = driver.controller_info().await;
+
+    assert_eq!(info.serial, "VROOM0001");
+    assert_eq!(info.model, "vroom emulated controller");
+    assert_eq!(info.firmware, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.version, 0x0001_0400);
+    assert_eq!(info.max_transfer_size(4096), Some(128 * 1024));
+    assert_eq!((info.wctemp, info.cctemp), (343, 353));
+    assert_eq!(info.tnvmcap, NAMESPACE_SIZE as u128);
+    assert_eq!((info.sqes, info.cqes), (0x66, 0x44));
+    assert_eq!(info.nn, 1);
+    assert!(info.supports_dataset_management() && info.supports_write_zeroes());
+    assert!(info.has_volatile_write_cache());
+    assert!(info.supports_sgl() && !info.sgl_requires_dword_alignment());
+    assert!(info.supports_doorbell_buffer_config());
+    assert_eq!(info.power_states[0].max_power, 2500);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_events_are_delivered() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let mut events = driver.subscribe_events();
+
+    // 77 °C exceeds the default threshold of 70 °C
+    controller.set_temperature(350);
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.event_type, AsyncEventType::SmartHealth);
+    match event.log {
+        Some(AsyncEventLog::Smart(log)) => {
+            assert_eq!(log.temperature, 350);
+            assert!(log.critical_warning & (1 << 1) != 0);
+        }
+        log => panic!("unexpected log page {log:?}"),
+    }
+
+    // the request was resubmitted for the next event
+    controller.raise_async_event(1, 0, 0x02);
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.event_type, AsyncEventType::SmartHealth);
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn smart_log_counts_io() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    let log = driver.smart_log(0xFFFF_FFFF).await.unwrap();
+    assert_eq!(log.data_units_written, 0);
+    assert_eq!(log.critical_warning, 0);
+    assert!(log.available_spare > log.available_spare_threshold);
+
+    // a data unit is 1000 blocks of 512 bytes, rounded up
+    round_trip(&driver, &controller, &ns, 1 << 20, 0, 1).await;
+    controller.set_temperature(300);
+    let log = driver.smart_log(1).await.unwrap();
+    assert_eq!(log.data_units_written, 3);
+    assert_eq!(log.data_units_read, 3);
+    assert!(log.host_write_commands > 0);
+    assert_eq!(log.host_read_commands, log.host_write_commands);
+    assert_eq!(log.temperature, 300);
+    assert_eq!(log.critical_warning, 0);
+
+    assert!(driver.smart_log(2).await.is_err());
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn error_and_firmware_slot_logs() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    assert!(driver.error_log().await.unwrap().is_empty());
+
+    // Invalid Namespace or Format
+    for _ in 0..2 {
+        let error = driver.smart_log(2).await.unwrap_err();
+        let error = error.downcast_ref::<NvmeError>().unwrap();
+        assert_eq!((error.sct, error.sc), (0, 0x0B));
+    }
+    let log = driver.error_log().await.unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].error_count, 2);
+    assert_eq!(log[1].error_count, 1);
+    assert_eq!((log[0].sqid, log[0].status, log[0].ns_id), (0, 0x0B, 2));
+
+    let log = driver.firmware_slot_log().await.unwrap();
+    assert_eq!(log.active_slot, 1);
+    assert_eq!(log.next_slot, None);
+    assert_eq!(log.active_revision(), Some(env!("CARGO_PKG_VERSION")));
+    assert_eq!(log.revision(2), None);
+
+    // the revision of slot 1 at an offset into the page
+    let revision = driver.get_log_page(0x03, 0xFFFF_FFFF, 8, 8).await.unwrap();
+    assert_eq!(
+        String::from_utf8(revision).unwrap().trim_end(),
+        env!("CARGO_PKG_VERSION")
+    );
+    assert!(driver.get_log_page(0x03, 0xFFFF_FFFF, 2, 8).await.is_err());
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn typed_features() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 3).unwrap();
+
+    let cache: VolatileWriteCache = driver.get_feature(FeatureSelect::Current).await.unwrap();
+    assert!(cache.enabled);
+    driver
+        .set_feature(&VolatileWriteCache { enabled: false })
+        .await
+        .unwrap();
+    let cache: VolatileWriteCache = driver.get_feature(FeatureSelect::Current).await.unwrap();
+    assert!(!cache.enabled);
+    let cache: VolatileWriteCache = driver.get_feature(FeatureSelect::Default).await.unwrap();
+    assert!(cache.enabled);
+
+    let arbitration = Arbitration {
+        burst: 3,
+        low_weight: 1,
+        medium_weight: 4,
+        high_weight: 9,
+    };
+    driver.set_feature(&arbitration).await.unwrap();
+    assert_eq!(
+        driver
+            .get_feature::<Arbitration>(FeatureSelect::Current)
+            .await
+            .unwrap(),
+        arbitration
+    );
+
+    // allocated during initialization
+    let queues: NumberOfQueues = driver.get_feature(FeatureSelect::Current).await.unwrap();
+    assert!(queues.submission >= 3 && queues.completion >= 3);
+    // only before the I/O queues were created
+    assert!(driver.set_feature(&queues).await.is_err());
+
+    // the emulated controller only has the composite temperature
+    let threshold = TemperatureThreshold {
+        threshold: 330,
+        sensor: 0,
+        under: false,
+    };
+    driver.set_feature(&threshold).await.unwrap();
+    let error = driver
+        .set_feature(&TemperatureThreshold {
+            sensor: 1,
+            ..threshold
+        })
+        .await
+        .unwrap_err();
+    assert!(error.is::<NvmeError>());
+    controller.set_temperature(335);
+    let log = driver.smart_log(0xFFFF_FFFF).await.unwrap();
+    assert!(log.critical_warning & (1 << 1) != 0);
+
+    driver.cleanup().await.unwrap();
+}
+
+#[test]
+fn device_features() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller).unwrap();
+
+    let threshold = TemperatureThreshold {
+        threshold: 250,
+        sensor: 0,
+        under: true,
+    };
+    nvme.set_feature(&threshold).unwrap();
+    assert_eq!(
+        nvme.temperature_threshold(0, true, FeatureSelect::Current)
+            .unwrap(),
+        threshold
+    );
+    assert_eq!(
+        nvme.temperature_threshold(0, true, FeatureSelect::Default)
+            .unwrap()
+            .threshold,
+        0
+    );
+    assert!(nvme.feature_capabilities(0x04).unwrap().changeable);
+
+    nvme.set_timestamp(1_000_000).unwrap();
+    let timestamp = nvme.timestamp(FeatureSelect::Current).unwrap();
+    assert!((1_000_000..1_010_000).contains(&timestamp.millis));
+    assert_eq!(timestamp.origin, 1);
+
+    nvme.shutdown().unwrap();
+}
+
+#[test]
+fn host_memory_buffer() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller).unwrap();
+    assert_eq!(nvme.identify_controller().unwrap().hmpre, 1024);
+    assert!(
+        !nvme
+            .host_memory_buffer(FeatureSelect::Current)
+            .unwrap()
+            .enabled
+    );
+
+    // rounded up to huge pages
+    nvme.enable_host_memory_buffer(HUGE_PAGE_SIZE + 1).unwrap();
+    let buffer = nvme.host_memory_buffer(FeatureSelect::Current).unwrap();
+    assert!(buffer.enabled);
+    assert_eq!(buffer.size as usize, 2 * HUGE_PAGE_SIZE / 4096);
+    assert_eq!(buffer.list_entries, 2);
+    assert_ne!(buffer.list_addr, 0);
+
+    nvme.disable_host_memory_buffer().unwrap();
+    assert!(
+        !nvme
+            .host_memory_buffer(FeatureSelect::Current)
+            .unwrap()
+            .enabled
+    );
+    assert!(nvme.enable_host_memory_buffer(0).is_err());
+
+    nvme.shutdown().unwrap();
+}
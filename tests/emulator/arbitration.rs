@@ -0,0 +1,71 @@
+use vroom::driver::{Driver, DriverOptions};
+use vroom::features::{Arbitration, FeatureSelect};
+use vroom::memory::Dma;
+use vroom::{NvmeDevice, QueuePriority};
+
+use crate::{controller, round_trip};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queue_priorities() {
+    let controller = controller();
+    let arbitration = Arbitration {
+        burst: 3,
+        low_weight: 1,
+        medium_weight: 4,
+        high_weight: 16,
+    };
+    let options = DriverOptions {
+        priorities: vec![QueuePriority::Urgent, QueuePriority::Low],
+        arbitration: Some(arbitration),
+        ..Default::default()
+    };
+    let driver =
+        Driver::<Dma<u8>>::new_emulated_with_options(controller.clone(), 3, options).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    round_trip(&driver, &controller, &ns, 4096, 0, 1).await;
+
+    // queue 1 belongs to the synchronous functions, pairs without an entry are medium
+    let priorities = [
+        QueuePriority::Medium,
+        QueuePriority::Urgent,
+        QueuePriority::Low,
+        QueuePriority::Medium,
+    ];
+    for (q_id, priority) in (1..).zip(priorities) {
+        assert_eq!(controller.submission_queue_priority(q_id), Some(priority));
+    }
+    assert_eq!(
+        driver
+            .get_feature::<Arbitration>(FeatureSelect::Current)
+            .await
+            .unwrap(),
+        arbitration
+    );
+
+    // both survive a reset
+    driver.reset().await.unwrap();
+    for (q_id, priority) in (1..).zip(priorities) {
+        assert_eq!(controller.submission_queue_priority(q_id), Some(priority));
+    }
+    assert_eq!(
+        driver
+            .get_feature::<Arbitration>(FeatureSelect::Current)
+            .await
+            .unwrap(),
+        arbitration
+    );
+    round_trip(&driver, &controller, &ns, 4096, 8, 2).await;
+}
+
+#[test]
+fn device_queue_priorities() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    assert!(nvme.weighted_round_robin());
+    let q_pair = nvme.create_io_queue_pair(64, QueuePriority::High).unwrap();
+    assert_eq!(q_pair.priority(), QueuePriority::High);
+    assert_eq!(
+        controller.submission_queue_priority(2),
+        Some(QueuePriority::High)
+    );
+}
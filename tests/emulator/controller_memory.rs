@@ -0,0 +1,81 @@
+use vroom::cmb::CmbUsage;
+use vroom::driver::{Driver, DriverOptions};
+use vroom::memory::Dma;
+use vroom::RegisterAccess;
+
+use crate::{controller, pattern, read, write};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controller_memory_buffer() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    assert!(driver.cmb().await.is_none());
+    drop(driver);
+
+    let options = DriverOptions {
+        queue_length: 64,
+        cmb: Some(CmbUsage {
+            submission_queues: true,
+            prp_lists: true,
+        }),
+        ..Default::default()
+    };
+    let driver =
+        Driver::<Dma<u8>>::new_emulated_with_options(controller.clone(), 2, options).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    let cmb = driver.cmb().await.unwrap();
+    assert_eq!(cmb.size(), 16 << 20);
+    assert!(cmb.supports_submission_queues() && cmb.supports_prp_lists() && cmb.supports_data());
+    // per queue pair, the 64 entry submission queue and a 4 KiB PRP list page per entry
+    assert_eq!(cmb.size() - cmb.available(), 2 * (64 + 1) * 4096);
+    let available = cmb.available();
+
+    // data in the buffer, 16 pages need a PRP list, which is in the buffer as well
+    let len = 64 * 1024;
+    let expected = pattern(len, 7);
+    let mut data = cmb.allocate(len).unwrap();
+    data[0..len].copy_from_slice(&expected);
+    write(&driver, &ns, &data, 8).await.unwrap();
+    let mut dest = cmb.allocate(len).unwrap();
+    dest[0..len].fill(0);
+    read(&driver, &ns, &dest, 8).await.unwrap();
+    assert!(dest[0..len] == expected[..]);
+    let mut image = vec![0; len];
+    controller
+        .read_image(&mut image, 8 * ns.block_size)
+        .unwrap();
+    assert_eq!(image, expected);
+
+    assert_eq!(cmb.available(), available - 2 * len);
+    assert!(cmb.allocate(available).is_err());
+    drop((data, dest));
+    assert_eq!(cmb.available(), available);
+    assert!(cmb.allocate(available).is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn persistent_memory_region() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let pmr = driver.enable_pmr().await.unwrap();
+    assert_eq!(pmr.len(), 4 << 20);
+    assert_eq!(pmr.elasticity_buffer_size(), Some(64 << 10));
+    assert_eq!(pmr.sustained_write_throughput(), Some(1 << 30));
+
+    let expected = pattern(8192, 3);
+    pmr.write(pmr.len() - 8192, &expected);
+    pmr.persist().unwrap();
+    let mut buf = vec![0; 8192];
+    pmr.read(pmr.len() - 8192, &mut buf);
+    assert_eq!(buf, expected);
+
+    // the region is not part of the controller state a reset discards
+    driver.reset().await.unwrap();
+    buf.fill(0);
+    pmr.read(pmr.len() - 8192, &mut buf);
+    assert_eq!(buf, expected);
+
+    // PMRCTL.EN cleared, the barrier reports the region as not ready
+    controller.write32(0xE04, 0);
+    assert!(pmr.persist().is_err());
+}
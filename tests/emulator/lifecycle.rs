@@ -0,0 +1,65 @@
+use vroom::driver::Driver;
+use vroom::memory::Dma;
+use vroom::{EmulatedController, NvmeDevice, RegisterAccess};
+
+use crate::{controller, eventually, round_trip};
+
+// CSTS.SHST reports shutdown processing complete
+fn is_shut_down(controller: &EmulatedController) -> bool {
+    (controller.read32(0x1C) >> 2) & 0b11 == 0b10
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cleanup_shuts_the_controller_down() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 2).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    round_trip(&driver, &controller, &ns, 4096, 0, 1).await;
+    assert!(controller.submission_queue_priority(3).is_some());
+
+    driver.cleanup().await.unwrap();
+    assert!(is_shut_down(&controller));
+    assert!(controller.submission_queue_priority(3).is_none());
+    // again, and on drop, is a no-op
+    driver.cleanup().await.unwrap();
+    drop(driver);
+
+    // the controller can be initialized again after a shutdown
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    assert!(!is_shut_down(&controller));
+    round_trip(&driver, &controller, &ns, 4096, 8, 2).await;
+    // the last reference may be held by a polling task for a moment
+    drop(driver);
+    eventually(|| async { is_shut_down(&controller) }).await;
+}
+
+#[test]
+fn device_shuts_down_on_drop() {
+    let controller = controller();
+    let nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    assert!(!is_shut_down(&controller));
+    drop(nvme);
+    assert!(is_shut_down(&controller));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn release_shuts_the_controller_down() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 2).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    round_trip(&driver, &controller, &ns, 4096, 0, 1).await;
+    assert!(!is_shut_down(&controller));
+
+    // emulated controllers have no kernel driver to return to
+    driver.release().await.unwrap();
+    assert!(is_shut_down(&controller));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn release_on_drop() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    driver.set_release_on_drop(true).await;
+    drop(driver);
+    eventually(|| async { is_shut_down(&controller) }).await;
+}
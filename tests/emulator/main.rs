@@ -0,0 +1,91 @@
+//! Tests against the emulated controller, run with `cargo test --features emulator`
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use vroom::driver::Driver;
+use vroom::memory::{Dma, DmaSlice};
+use vroom::{EmulatedController, NvmeNamespace};
+
+mod admin;
+mod arbitration;
+mod controller_memory;
+mod lifecycle;
+mod queues;
+mod recovery;
+mod transfer;
+
+const NAMESPACE_SIZE: usize = 64 << 20;
+const BLOCK_SIZE: u64 = 512;
+
+fn controller() -> Arc<EmulatedController> {
+    EmulatedController::ram(NAMESPACE_SIZE, BLOCK_SIZE).unwrap()
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+async fn write(
+    driver: &Driver<Dma<u8>>,
+    ns: &NvmeNamespace,
+    data: &Dma<u8>,
+    lba: u64,
+) -> io::Result<()> {
+    let requests = driver.write(0, ns, data, lba).await?;
+    futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .collect()
+}
+
+async fn read(
+    driver: &Driver<Dma<u8>>,
+    ns: &NvmeNamespace,
+    dest: &Dma<u8>,
+    lba: u64,
+) -> io::Result<()> {
+    let requests = driver.read(0, ns, dest, lba).await?;
+    futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .collect()
+}
+
+// writes a pattern to `lba` and checks that it reads back, through the driver and the image
+async fn round_trip(
+    driver: &Driver<Dma<u8>>,
+    controller: &EmulatedController,
+    ns: &NvmeNamespace,
+    len: usize,
+    lba: u64,
+    seed: u8,
+) {
+    let expected = pattern(len, seed);
+    let mut data: Dma<u8> = Dma::allocate(len).unwrap();
+    data[0..len].copy_from_slice(&expected);
+    write(driver, ns, &data.slice(0..len), lba).await.unwrap();
+
+    let mut dest: Dma<u8> = Dma::allocate(len).unwrap();
+    dest[0..len].fill(0);
+    read(driver, ns, &dest.slice(0..len), lba).await.unwrap();
+    assert!(dest[0..len] == expected[..]);
+
+    let mut image = vec![0; len];
+    controller
+        .read_image(&mut image, lba * ns.block_size)
+        .unwrap();
+    assert_eq!(image, expected);
+}
+
+// waits up to 5 seconds for `condition`
+async fn eventually<F: std::future::Future<Output = bool>>(mut condition: impl FnMut() -> F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition().await {
+        assert!(Instant::now() < deadline, "condition not met within 5s");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
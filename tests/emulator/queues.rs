@@ -0,0 +1,109 @@
+use vroom::driver::{Driver, DriverOptions};
+use vroom::memory::{Dma, DmaSlice};
+use vroom::{NvmeDevice, QueuePriority, MAX_QUEUE_LENGTH};
+
+use crate::{controller, pattern, round_trip, BLOCK_SIZE};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shadow_doorbells_suppress_register_writes() {
+    let mut doorbell_writes = Vec::new();
+    for shadow_doorbells in [false, true] {
+        let controller = controller();
+        let options = DriverOptions {
+            shadow_doorbells,
+            ..Default::default()
+        };
+        let driver =
+            Driver::<Dma<u8>>::new_emulated_with_options(controller.clone(), 1, options).unwrap();
+        let ns = driver.namespace(1).await.unwrap();
+
+        let before = controller.doorbell_writes();
+        for i in 0..64 {
+            round_trip(&driver, &controller, &ns, 4096, i * 8, i as u8).await;
+        }
+        doorbell_writes.push(controller.doorbell_writes() - before);
+        driver.cleanup().await.unwrap();
+    }
+    assert!(
+        doorbell_writes[1] <= doorbell_writes[0] / 2,
+        "{doorbell_writes:?}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queue_pair_count_is_negotiated() {
+    let controller = controller();
+    let nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    let allocated = nvme.allocated_queues();
+    assert_eq!((allocated.submission, allocated.completion), (64, 64));
+    assert_eq!(nvme.max_io_queue_pairs(), 64);
+    drop(nvme);
+
+    // one pair is kept for the synchronous functions of the device
+    for num_q_pairs in [0, 64] {
+        let error = Driver::<Dma<u8>>::new_emulated(controller.clone(), num_q_pairs).unwrap_err();
+        assert!(error.to_string().contains("1 to 63"), "{error}");
+    }
+    let options = DriverOptions {
+        queue_length: 64,
+        ..Default::default()
+    };
+    let driver =
+        Driver::<Dma<u8>>::new_emulated_with_options(controller.clone(), 63, options).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    let len = 4096;
+    let mut data: Dma<u8> = Dma::allocate(len).unwrap();
+    data[0..len].copy_from_slice(&pattern(len, 10));
+    for q_id in [0, 31, 62] {
+        let requests = driver
+            .write(q_id, &ns, &data.slice(0..len), 8 * q_id as u64)
+            .await
+            .unwrap();
+        for result in futures::future::join_all(requests).await {
+            result.unwrap();
+        }
+        let mut image = vec![0; len];
+        controller
+            .read_image(&mut image, 8 * q_id as u64 * ns.block_size)
+            .unwrap();
+        assert_eq!(image, pattern(len, 10));
+    }
+    driver.cleanup().await.unwrap();
+}
+
+#[test]
+fn queues_larger_than_a_huge_page() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    let ns = nvme.identify_namespace(1);
+    assert_eq!(nvme.max_queue_length(), MAX_QUEUE_LENGTH);
+    for len in [1, MAX_QUEUE_LENGTH + 1] {
+        assert!(nvme
+            .create_io_queue_pair(len, QueuePriority::default())
+            .is_err());
+    }
+
+    // 4 MiB of submission queue entries, the commands wrap around past the first huge page
+    let mut q_pair = nvme
+        .create_io_queue_pair(MAX_QUEUE_LENGTH, QueuePriority::default())
+        .unwrap();
+    let blocks = 1024;
+    let len = blocks * BLOCK_SIZE as usize;
+    let mut data: Dma<u8> = Dma::allocate(len).unwrap();
+    data[0..len].copy_from_slice(&pattern(len, 11));
+    for round in 0..48 {
+        for block in 0..blocks {
+            let slice = data.slice(block * BLOCK_SIZE as usize..(block + 1) * BLOCK_SIZE as usize);
+            let lba = (round * blocks + block) as u64;
+            assert_eq!(q_pair.submit_io(&ns, &slice, lba, true).unwrap(), 1);
+        }
+        q_pair.complete_io(blocks).unwrap();
+    }
+
+    let mut image = vec![0; 48 * len];
+    controller.read_image(&mut image, 0).unwrap();
+    for round in image.chunks(len) {
+        assert!(round == pattern(len, 11));
+    }
+    nvme.delete_io_queue_pair(q_pair).unwrap();
+}
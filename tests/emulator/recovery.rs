@@ -0,0 +1,166 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use vroom::driver::Driver;
+use vroom::memory::{Dma, DmaSlice};
+use vroom::TimeoutError;
+
+use crate::{controller, eventually, pattern, round_trip, write};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn timed_out_command_is_aborted() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    driver.set_io_timeout(Duration::from_millis(50));
+
+    controller.stall_io(true);
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+    let error = write(&driver, &ns, &data.slice(0..4096), 0)
+        .await
+        .unwrap_err();
+    assert!(TimeoutError::from_io_error(&error).is_some());
+
+    // the abort completes the held command, which releases its slot
+    eventually(|| async { driver.dropped_outstanding(0).await == 0 }).await;
+    controller.stall_io(false);
+    round_trip(&driver, &controller, &ns, 4096, 0, 3).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn timed_out_abort_resets_controller() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    driver.set_io_timeout(Duration::from_millis(50));
+    driver.set_admin_timeout(Duration::from_millis(100)).await;
+
+    controller.stall_io(true);
+    controller.ignore_aborts(true);
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+    let error = write(&driver, &ns, &data.slice(0..4096), 0)
+        .await
+        .unwrap_err();
+    assert!(TimeoutError::from_io_error(&error).is_some());
+
+    // only the reset discards the held command
+    eventually(|| async { driver.dropped_outstanding(0).await == 0 }).await;
+    controller.stall_io(false);
+    controller.ignore_aborts(false);
+    round_trip(&driver, &controller, &ns, 4096, 0, 4).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reset_fails_commands_in_flight() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    controller.stall_io(true);
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+    let requests = driver.write(0, &ns, &data.slice(0..4096), 0).await.unwrap();
+    driver.reset().await.unwrap();
+    for result in futures::future::join_all(requests).await {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    controller.stall_io(false);
+    round_trip(&driver, &controller, &ns, 4096, 0, 6).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn timed_out_admin_command_recovers_io_queues() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 2).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    driver.set_io_timeout(Duration::from_millis(500));
+    driver.set_admin_timeout(Duration::from_millis(100)).await;
+
+    // the Get Log Page command and then its Abort time out, which resets the controller
+    controller.ignore_aborts(true);
+    controller.ignore_admin_command(0x02);
+    let error = driver.smart_log(0xFFFF_FFFF).await.unwrap_err();
+    assert!(error.is::<TimeoutError>());
+    controller.ignore_aborts(false);
+
+    // the I/O queues deleted by the reset were recreated
+    round_trip(&driver, &controller, &ns, 8192, 0, 7).await;
+    let mut data: Dma<u8> = Dma::allocate(4096).unwrap();
+    data[0..4096].copy_from_slice(&pattern(4096, 8));
+    let requests = driver
+        .write(1, &ns, &data.slice(0..4096), 16)
+        .await
+        .unwrap();
+    for result in futures::future::join_all(requests).await {
+        result.unwrap();
+    }
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fatal_status_resets_controller() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 2).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    round_trip(&driver, &controller, &ns, 4096, 0, 5).await;
+
+    // the controller processes no commands until the polling task resets it, which fails the
+    // commands in flight
+    controller.inject_fatal_status();
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        write(&driver, &ns, &data.slice(0..4096), 8),
+    )
+    .await
+    .unwrap();
+    if let Err(e) = result {
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+    round_trip(&driver, &controller, &ns, 4096, 8, 6).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_requests_are_orphaned() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    // the buffer handed over with the request outlives it until the command completes
+    controller.stall_io(true);
+    let data = Arc::new(Dma::<u8>::allocate(4096).unwrap());
+    let requests = driver.write(0, &ns, &data.slice(0..4096), 0).await.unwrap();
+    for request in requests {
+        drop(request.keep_alive(Arc::clone(&data)));
+    }
+    assert_eq!(driver.dropped_outstanding(0).await, 1);
+    assert_eq!(Arc::strong_count(&data), 2);
+
+    // only a reset discards the held command, the queue pair then forgets its orphans
+    driver.reset().await.unwrap();
+    assert_eq!(driver.dropped_outstanding(0).await, 0);
+    assert_eq!(Arc::strong_count(&data), 1);
+
+    // or dropping aborts the command
+    driver.set_abort_dropped(true);
+    let requests = driver.write(0, &ns, &data.slice(0..4096), 0).await.unwrap();
+    for request in requests {
+        drop(request.keep_alive(Arc::clone(&data)));
+    }
+    eventually(|| async { driver.dropped_outstanding(0).await == 0 }).await;
+    assert_eq!(Arc::strong_count(&data), 1);
+
+    controller.stall_io(false);
+    round_trip(&driver, &controller, &ns, 4096, 0, 6).await;
+    driver.cleanup().await.unwrap();
+}
@@ -0,0 +1,356 @@
+use std::io;
+
+use vroom::driver::Driver;
+use vroom::memory::{Dma, DmaPool, DmaSlice};
+use vroom::{
+    EmulatedController, LbaRange, NvmeDevice, NvmeError, NvmeNamespace, QueuePriority,
+    SglDescriptor, HUGE_PAGE_SIZE,
+};
+
+use crate::{controller, pattern, read, round_trip, write, BLOCK_SIZE, NAMESPACE_SIZE};
+
+#[test]
+fn device_init_and_io_queues() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+
+    let info = nvme.identify_controller().unwrap();
+    assert!(info.supports_dataset_management());
+    assert!(nvme.max_io_queue_pairs() > 1);
+    assert_eq!(nvme.identify_namespace_list(0), vec![1]);
+    let ns = nvme.identify_namespace(1);
+    assert_eq!(ns.block_size, BLOCK_SIZE);
+    assert_eq!(ns.blocks, NAMESPACE_SIZE as u64 / BLOCK_SIZE);
+
+    // synchronous i/o on the device's own queue pair
+    let expected = pattern(8192, 1);
+    nvme.write_copied(&ns, &expected, 16).unwrap();
+    let mut dest = vec![0; expected.len()];
+    nvme.read_copied(&ns, &mut dest, 16).unwrap();
+    assert_eq!(dest, expected);
+
+    // and on an additional one
+    let mut q_pair = nvme
+        .create_io_queue_pair(32, QueuePriority::default())
+        .unwrap();
+    let mut data: Dma<u8> = Dma::allocate(4096).unwrap();
+    data[0..4096].copy_from_slice(&pattern(4096, 2));
+    assert_eq!(
+        q_pair
+            .submit_io(&ns, &data.slice(0..4096), 64, true)
+            .unwrap(),
+        1
+    );
+    q_pair.complete_io(1).unwrap();
+    let mut image = vec![0; 4096];
+    controller.read_image(&mut image, 64 * BLOCK_SIZE).unwrap();
+    assert_eq!(image, pattern(4096, 2));
+    nvme.delete_io_queue_pair(q_pair).unwrap();
+
+    nvme.shutdown().unwrap();
+}
+
+#[test]
+fn command_ids_outlive_their_queue_slot() {
+    let controller = controller();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    let ns = nvme.identify_namespace(1);
+    let mut q_pair = nvme
+        .create_io_queue_pair(4, QueuePriority::default())
+        .unwrap();
+    let data: Dma<u8> = Dma::allocate(8192).unwrap();
+
+    // fetched by the controller, but not completed
+    controller.stall_io(true);
+    let (tail, held) = q_pair
+        .submit_async(&ns, &data.slice(0..8192), 0, true, false)
+        .unwrap();
+    q_pair.set_tail(tail.unwrap() as u32);
+    controller.stall_io(false);
+
+    // later commands wrap around the queue several times without taking the held id
+    for i in 0..16 {
+        let (tail, ids) = q_pair
+            .submit_async(&ns, &data.slice(0..8192), 16 * i, true, false)
+            .unwrap();
+        assert_eq!(ids.len(), 1);
+        assert_ne!(ids, held);
+        q_pair.set_tail(tail.unwrap() as u32);
+        q_pair.complete_io(1).unwrap();
+    }
+
+    nvme.abort(q_pair.id, held[0]).unwrap();
+    assert!(q_pair.complete_io(1).is_err());
+    nvme.delete_io_queue_pair(q_pair).unwrap();
+}
+
+#[test]
+fn device_io_with_4k_blocks() {
+    let controller = EmulatedController::ram(NAMESPACE_SIZE, 4096).unwrap();
+    let mut nvme = NvmeDevice::<Dma<u8>>::init_emulated(controller.clone()).unwrap();
+    let ns = nvme.identify_namespace(1);
+    assert_eq!(ns.block_size, 4096);
+
+    let len = 3 * 4096;
+    let mut data: Dma<u8> = Dma::allocate(len).unwrap();
+    data[0..len].copy_from_slice(&pattern(len, 3));
+    nvme.write(&ns, &data.slice(0..len), 5).unwrap();
+    let mut dest: Dma<u8> = Dma::allocate(len).unwrap();
+    dest[0..len].fill(0);
+    nvme.read(&ns, &dest.slice(0..len), 5).unwrap();
+    assert!(dest[0..len] == pattern(len, 3)[..]);
+
+    let expected = pattern(16 * 4096, 4);
+    nvme.batched_write(&ns, &expected, 32, 4).unwrap();
+    let mut image = vec![0; expected.len()];
+    controller.read_image(&mut image, 32 * 4096).unwrap();
+    assert_eq!(image, expected);
+    let mut batched = vec![0; expected.len()];
+    nvme.batched_read(&ns, &mut batched, 32, 4).unwrap();
+    assert_eq!(batched, expected);
+
+    // partial blocks and transfers past the end of the namespace are rejected
+    assert!(nvme.write(&ns, &data.slice(0..512), 0).is_err());
+    assert!(nvme.write_copied(&ns, &expected, ns.blocks - 1).is_err());
+
+    nvme.shutdown().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn driver_round_trip() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 2).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    round_trip(&driver, &controller, &ns, 4096, 0, 1).await;
+    // larger than MDTS and a page of PRP entries
+    round_trip(&driver, &controller, &ns, 4 << 20, 1024, 2).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sgl_data_pointers() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    // the controller has no alignment requirements for SGL data blocks, PRPs need dwords
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+    let unaligned = data.slice(1..1 + BLOCK_SIZE as usize);
+    let error = write(&driver, &ns, &unaligned, 0).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert!(driver.set_sgl(true).await.unwrap());
+    write(&driver, &ns, &unaligned, 0).await.unwrap();
+
+    round_trip(&driver, &controller, &ns, 4096, 8, 1).await;
+    round_trip(&driver, &controller, &ns, 4 << 20, 1024, 2).await;
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn vectored_io() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    let mut first: Dma<u8> = Dma::allocate(1024).unwrap();
+    let mut second: Dma<u8> = Dma::allocate(3072).unwrap();
+    first[0..1024].copy_from_slice(&pattern(1024, 1));
+    second[0..3072].copy_from_slice(&pattern(3072, 2));
+    let descriptors = [
+        SglDescriptor::data_block(first.phys as u64, 1024),
+        SglDescriptor::data_block(second.phys as u64, 3072),
+    ];
+
+    let error = driver
+        .write_vectored(0, &ns, &descriptors, 100)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert!(driver.set_sgl(true).await.unwrap());
+
+    // both buffers end up in consecutive blocks
+    driver
+        .write_vectored(0, &ns, &descriptors, 100)
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    let mut image = vec![0; 4096];
+    controller.read_image(&mut image, 100 * BLOCK_SIZE).unwrap();
+    assert_eq!(image[..1024], pattern(1024, 1)[..]);
+    assert_eq!(image[1024..], pattern(3072, 2)[..]);
+
+    // bit buckets skip read data, but can't be written
+    let mut dest: Dma<u8> = Dma::allocate(1536).unwrap();
+    dest[0..1536].fill(0);
+    let descriptors = [
+        SglDescriptor::bit_bucket(512),
+        SglDescriptor::data_block(dest.phys as u64, 1536),
+        SglDescriptor::bit_bucket(2048),
+    ];
+    driver
+        .read_vectored(0, &ns, &descriptors, 100)
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    assert_eq!(dest[0..1536], image[512..2048]);
+    let error = driver
+        .write_vectored(0, &ns, &descriptors, 100)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    // partial blocks are rejected
+    let descriptors = [SglDescriptor::data_block(first.phys as u64, 1000)];
+    let error = driver
+        .write_vectored(0, &ns, &descriptors, 100)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pooled_buffers() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    let pool = DmaPool::new(1).unwrap();
+    assert_eq!(pool.available(), HUGE_PAGE_SIZE);
+    assert!(pool.allocate(0).is_err());
+    assert!(pool.allocate(HUGE_PAGE_SIZE + 1).is_err());
+
+    // small buffers share a huge page, sizes are rounded up to powers of two
+    let mut data = pool.allocate(3 * 4096).unwrap();
+    let dest = pool.allocate(4096).unwrap();
+    assert_eq!(pool.huge_pages(), 1);
+    assert_eq!(pool.available(), HUGE_PAGE_SIZE - 5 * 4096);
+    assert!(dest.phys >= data.phys + 4 * 4096 || dest.phys + 4096 <= data.phys);
+
+    data[0..4096].copy_from_slice(&pattern(4096, 9));
+    write(&driver, &ns, &data.slice(0..4096), 24).await.unwrap();
+    read(&driver, &ns, &dest, 24).await.unwrap();
+    assert!(dest[0..4096] == pattern(4096, 9)[..]);
+
+    // the pool grows if nothing fits, freed blocks merge again
+    let page = pool.allocate(HUGE_PAGE_SIZE).unwrap();
+    assert_eq!(pool.huge_pages(), 2);
+    drop((data, dest, page));
+    assert_eq!(pool.available(), 2 * HUGE_PAGE_SIZE);
+    let _pages = (
+        pool.allocate(HUGE_PAGE_SIZE).unwrap(),
+        pool.allocate(HUGE_PAGE_SIZE).unwrap(),
+    );
+    assert_eq!(pool.huge_pages(), 2);
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deallocate_and_flush() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    round_trip(&driver, &controller, &ns, 1 << 20, 0, 7).await;
+
+    driver.deallocate(0, &ns, &[]).await.unwrap();
+    let error = driver
+        .deallocate(0, &ns, &[LbaRange { slba: 0, blocks: 0 }])
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    // more ranges than fit into one command, every other block
+    let ranges: Vec<_> = (0..1024)
+        .map(|i| LbaRange {
+            slba: i * 2,
+            blocks: 1,
+        })
+        .collect();
+    driver.deallocate(0, &ns, &ranges).await.unwrap();
+    driver.flush(&ns).await.unwrap();
+
+    let expected = pattern(1 << 20, 7);
+    let mut image = vec![0; 1 << 20];
+    controller.read_image(&mut image, 0).unwrap();
+    for (i, block) in image.chunks(BLOCK_SIZE as usize).enumerate() {
+        if i % 2 == 0 {
+            assert!(block.iter().all(|&b| b == 0));
+        } else {
+            assert_eq!(
+                block,
+                &expected[i * BLOCK_SIZE as usize..][..BLOCK_SIZE as usize]
+            );
+        }
+    }
+
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn io_errors_are_typed() {
+    let controller = controller();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+    let data: Dma<u8> = Dma::allocate(4096).unwrap();
+
+    // the controller fails the command
+    let missing = NvmeNamespace { id: 2, ..ns };
+    let error = write(&driver, &missing, &data.slice(0..4096), 0)
+        .await
+        .unwrap_err();
+    let status = NvmeError::from_io_error(&error).unwrap();
+    assert_eq!((status.sct, status.sc), (0, 0x0B));
+    assert_eq!(status.description(), "Invalid Namespace or Format");
+
+    // the driver rejects the transfer before submitting it
+    let error = read(&driver, &ns, &data.slice(0..4096), ns.blocks - 1)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert!(NvmeError::from_io_error(&error).is_none());
+
+    round_trip(&driver, &controller, &ns, 4096, 0, 5).await;
+    driver.cleanup().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn durable_writes_reach_the_image_file() {
+    let path = std::env::temp_dir().join(format!("vroom-fua-{}.img", std::process::id()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(NAMESPACE_SIZE as u64)
+        .unwrap();
+    let controller = EmulatedController::file(path.to_str().unwrap(), BLOCK_SIZE).unwrap();
+    let driver = Driver::<Dma<u8>>::new_emulated(controller.clone(), 1).unwrap();
+    let ns = driver.namespace(1).await.unwrap();
+
+    let len = 64 * 1024;
+    let mut data: Dma<u8> = Dma::allocate(len).unwrap();
+    data[0..len].copy_from_slice(&pattern(len, 8));
+    let requests = driver
+        .write_fua(0, &ns, &data.slice(0..len), 16)
+        .await
+        .unwrap();
+    for result in futures::future::join_all(requests).await {
+        result.unwrap();
+    }
+    round_trip(&driver, &controller, &ns, len, 1024, 9).await;
+    driver.flush(&ns).await.unwrap();
+    driver.cleanup().await.unwrap();
+
+    let image = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        image[16 * BLOCK_SIZE as usize..][..len],
+        pattern(len, 8)[..]
+    );
+    assert_eq!(
+        image[1024 * BLOCK_SIZE as usize..][..len],
+        pattern(len, 9)[..]
+    );
+}